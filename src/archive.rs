@@ -0,0 +1,247 @@
+//! Key dirs as `tar.zst` archives, for moving them between hosts
+//!
+//! An archive starts with a [`Header`], followed by the contents of the dir
+//! under `data/`, and ends with a [`Manifest`] of all the files in it, checked
+//! when importing.
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::PermissionsExt as _;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, format_err, Context as _, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+const HEADER_PATH: &str = "fs-dir-cache.header.json";
+const MANIFEST_PATH: &str = "fs-dir-cache.manifest.json";
+const DATA_DIR: &str = "data";
+
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Header {
+    pub format_version: u32,
+    /// Version of fs-dir-cache that created the archive
+    pub fs_dir_cache_version: String,
+    pub key: String,
+    pub exported_at: DateTime<Utc>,
+}
+
+impl Header {
+    pub fn new(key: &str) -> Self {
+        Self {
+            format_version: FORMAT_VERSION,
+            fs_dir_cache_version: env!("CARGO_PKG_VERSION").to_owned(),
+            key: key.to_owned(),
+            exported_at: Utc::now(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub files: Vec<ManifestFile>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ManifestFile {
+    /// Relative to the key dir
+    pub path: String,
+    pub size: u64,
+    pub blake3: String,
+}
+
+/// Write `dir` as an archive to `out`, returning its manifest
+pub fn export(dir: &Path, header: &Header, out: impl Write) -> Result<Manifest> {
+    let mut builder = tar::Builder::new(zstd::Encoder::new(out, 0)?);
+    builder.follow_symlinks(false);
+    append_json(&mut builder, HEADER_PATH, header)?;
+    let mut manifest = Manifest::default();
+    append_dir(&mut builder, dir, Path::new(""), &mut manifest)?;
+    append_json(&mut builder, MANIFEST_PATH, &manifest)?;
+    builder.into_inner()?.finish()?.flush()?;
+    Ok(manifest)
+}
+
+fn append_json<W: Write>(
+    builder: &mut tar::Builder<W>,
+    path: &str,
+    value: &impl Serialize,
+) -> Result<()> {
+    let data = serde_json::to_vec_pretty(value)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(u64::try_from(data.len())?);
+    header.set_mode(0o644);
+    header.set_mtime(u64::try_from(Utc::now().timestamp()).unwrap_or_default());
+    builder.append_data(&mut header, path, data.as_slice())?;
+    Ok(())
+}
+
+fn append_dir<W: Write>(
+    builder: &mut tar::Builder<W>,
+    dir: &Path,
+    rel_path: &Path,
+    manifest: &mut Manifest,
+) -> Result<()> {
+    let mut entries = fs::read_dir(dir.join(rel_path))?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let rel_path = rel_path.join(entry.file_name());
+        let Some(rel_path_str) = rel_path.to_str() else {
+            bail!("Path is not valid UTF-8: {}", rel_path.display());
+        };
+        let archive_path = Path::new(DATA_DIR).join(&rel_path);
+        let metadata = entry.metadata()?;
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata(&metadata);
+        if metadata.is_dir() {
+            builder.append_data(&mut header, &archive_path, io::empty())?;
+            append_dir(builder, dir, &rel_path, manifest)?;
+        } else if metadata.is_symlink() {
+            builder.append_link(&mut header, &archive_path, fs::read_link(entry.path())?)?;
+        } else {
+            let mut reader = HashingReader {
+                inner: fs::File::open(entry.path())?,
+                hasher: blake3::Hasher::new(),
+            };
+            builder.append_data(&mut header, &archive_path, &mut reader)?;
+            manifest.files.push(ManifestFile {
+                path: rel_path_str.to_owned(),
+                size: metadata.len(),
+                blake3: reader.hasher.finalize().to_hex().to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+struct HashingReader<R> {
+    inner: R,
+    hasher: blake3::Hasher,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+}
+
+/// Unpack an archive from `input`, into an empty dir returned by `prepare`
+/// once the header is read
+///
+/// Fails if the unpacked files don't match the manifest.
+pub fn import(
+    input: impl Read,
+    prepare: impl FnOnce(&Header) -> Result<PathBuf>,
+) -> Result<Header> {
+    let mut archive = tar::Archive::new(zstd::Decoder::new(input)?);
+    let mut entries = archive.entries()?;
+
+    let mut entry = entries
+        .next()
+        .ok_or_else(|| format_err!("Archive is empty"))??;
+    if entry.path()?.as_ref() != Path::new(HEADER_PATH) {
+        bail!("Not an fs-dir-cache archive");
+    }
+    let header: Header =
+        serde_json::from_reader(&mut entry).context("Failed to parse archive header")?;
+    if header.format_version != FORMAT_VERSION {
+        bail!(
+            "Unsupported archive format version {} (created by fs-dir-cache {})",
+            header.format_version,
+            header.fs_dir_cache_version
+        );
+    }
+    let dir = prepare(&header)?;
+    let canonical_dir = fs::canonicalize(&dir)?;
+
+    let mut manifest: Option<Manifest> = None;
+    for entry in entries {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        if manifest.is_some() {
+            bail!("Unexpected entry after the manifest: {}", path.display());
+        }
+        if path == Path::new(MANIFEST_PATH) {
+            manifest =
+                Some(serde_json::from_reader(&mut entry).context("Failed to parse manifest")?);
+            continue;
+        }
+        let rel_path = path
+            .strip_prefix(DATA_DIR)
+            .ok()
+            .filter(|rel_path| {
+                rel_path
+                    .components()
+                    .all(|component| matches!(component, Component::Normal(_)))
+            })
+            .ok_or_else(|| format_err!("Unexpected entry: {}", path.display()))?;
+        let entry_type = entry.header().entry_type();
+        if !(entry_type.is_file() || entry_type.is_dir() || entry_type.is_symlink()) {
+            bail!("Unsupported entry type: {}", path.display());
+        }
+        let Some(parent) = rel_path.parent() else {
+            continue;
+        };
+        let target_parent = dir.join(parent);
+        fs::create_dir_all(&target_parent)?;
+        // don't follow symlinks unpacked earlier out of the dir
+        if !fs::canonicalize(&target_parent)?.starts_with(&canonical_dir) {
+            bail!("Entry outside of the archive dir: {}", path.display());
+        }
+        let target = dir.join(rel_path);
+        entry.unpack(&target)?;
+        if entry_type.is_dir() {
+            // still to be populated, even if exported from a read-only dir
+            let mut permissions = fs::metadata(&target)?.permissions();
+            permissions.set_mode(permissions.mode() | 0o200);
+            fs::set_permissions(&target, permissions)?;
+        }
+    }
+
+    verify(
+        &dir,
+        &manifest.ok_or_else(|| format_err!("Archive is truncated, manifest missing"))?,
+    )?;
+    Ok(header)
+}
+
+fn verify(dir: &Path, manifest: &Manifest) -> Result<()> {
+    for file in &manifest.files {
+        let path = dir.join(&file.path);
+        let metadata =
+            fs::symlink_metadata(&path).with_context(|| format!("File missing: {}", file.path))?;
+        if !metadata.is_file() || metadata.len() != file.size {
+            bail!("File does not match the manifest: {}", file.path);
+        }
+        let mut hasher = blake3::Hasher::new();
+        io::copy(&mut fs::File::open(&path)?, &mut hasher)?;
+        if hasher.finalize().to_hex().as_str() != file.blake3 {
+            bail!("File content does not match the manifest: {}", file.path);
+        }
+    }
+    let files = count_files(dir)?;
+    if files != manifest.files.len() {
+        bail!(
+            "Archive has {files} files, but the manifest lists {}",
+            manifest.files.len()
+        );
+    }
+    Ok(())
+}
+
+fn count_files(dir: &Path) -> io::Result<usize> {
+    let mut count = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            count += count_files(&entry.path())?;
+        } else if file_type.is_file() {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
@@ -0,0 +1,198 @@
+//! Cloning dirs as cheaply as the file system allows
+//!
+//! Each file is reflinked (sharing data blocks copy-on-write, on btrfs, XFS,
+//! etc.) if possible, then hardlinked (if allowed), and only then copied.
+//! Copying itself uses `copy_file_range`, which can still avoid moving the
+//! data around, e.g. with server-side copies on NFS. Modification times are
+//! preserved.
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use tracing::debug;
+
+use crate::LOG_TARGET;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Options {
+    /// Hardlink files that can't be reflinked
+    ///
+    /// Files get shared between the dirs, so it's only safe if neither dir
+    /// can be modified, i.e. both are published.
+    pub hardlink: bool,
+}
+
+/// Number of files cloned with each method
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stats {
+    pub reflinked: u64,
+    pub hardlinked: u64,
+    pub copied: u64,
+}
+
+/// Recursively clone the contents of `from` into `to`
+///
+/// Symlinks are cloned as symlinks. Entries disappearing while cloning are
+/// skipped, as `from` can be in use.
+pub fn clone_dir(from: &Path, to: &Path, opts: Options) -> io::Result<Stats> {
+    let mut stats = Stats::default();
+    clone_dir_into(from, to, opts, &mut stats)?;
+    debug!(target: LOG_TARGET, from = %from.display(), to = %to.display(), ?stats, "Cloned dir");
+    Ok(stats)
+}
+
+fn clone_dir_into(from: &Path, to: &Path, opts: Options, stats: &mut Stats) -> io::Result<()> {
+    let modified = fs::metadata(from)?.modified()?;
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let (from, to) = (entry.path(), to.join(entry.file_name()));
+        let res = entry.file_type().and_then(|file_type| {
+            if file_type.is_dir() {
+                clone_dir_into(&from, &to, opts, stats)
+            } else if file_type.is_symlink() {
+                std::os::unix::fs::symlink(fs::read_link(&from)?, &to)
+            } else {
+                clone_file(&from, &to, opts, stats)
+            }
+        });
+        match res {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            res => res?,
+        }
+    }
+    // once all the entries are in
+    fs::File::open(to)?.set_modified(modified)
+}
+
+fn clone_file(from: &Path, to: &Path, opts: Options, stats: &mut Stats) -> io::Result<()> {
+    let modified = fs::metadata(from)?.modified()?;
+    if reflink(from, to)? {
+        stats.reflinked += 1;
+    } else if opts.hardlink && fs::hard_link(from, to).is_ok() {
+        // the very same file, so with the same mtime already
+        stats.hardlinked += 1;
+        return Ok(());
+    } else {
+        fs::copy(from, to)?;
+        stats.copied += 1;
+    }
+    fs::File::open(to)?.set_modified(modified)
+}
+
+/// Reflink `from` as the new file `to`, returning `false` if reflinks are not
+/// supported
+#[cfg(target_os = "linux")]
+pub fn reflink(from: &Path, to: &Path) -> io::Result<bool> {
+    use std::os::fd::AsRawFd as _;
+
+    let src = fs::File::open(from)?;
+    let permissions = src.metadata()?.permissions();
+    let dst = fs::File::options().write(true).create_new(true).open(to)?;
+    // SAFETY: both are valid, open file descriptors
+    if unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) } != 0 {
+        drop(dst);
+        fs::remove_file(to)?;
+        return Ok(false);
+    }
+    dst.set_permissions(permissions)?;
+    Ok(true)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn reflink(_from: &Path, _to: &Path) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// Do the files at `a` and `b` already share all their data blocks, e.g.
+/// after reflinking one from the other
+///
+/// Compares the extents reported by `FS_IOC_FIEMAP`, so it's `false` if the
+/// file system can't tell.
+#[cfg(target_os = "linux")]
+pub fn shares_data(a: &Path, b: &Path) -> io::Result<bool> {
+    let (Some(a), Some(b)) = (fiemap::extents(a)?, fiemap::extents(b)?) else {
+        return Ok(false);
+    };
+    Ok(!a.is_empty() && a == b)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn shares_data(_a: &Path, _b: &Path) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// Not in `libc`, so declared as in `linux/fiemap.h`
+#[cfg(target_os = "linux")]
+mod fiemap {
+    use std::fs;
+    use std::io;
+    use std::os::fd::AsRawFd as _;
+    use std::path::Path;
+
+    const FS_IOC_FIEMAP: libc::c_ulong = 0xc020_660b;
+    const FIEMAP_FLAG_SYNC: u32 = 0x1;
+    const FIEMAP_EXTENT_LAST: u32 = 0x1;
+    /// Location not known (yet), or the data not stored in blocks of its own
+    const FIEMAP_EXTENT_NOT_COMPARABLE: u32 = 0x2 | 0x4 | 0x8 | 0x100 | 0x200;
+    const BATCH: usize = 64;
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    struct Extent {
+        logical: u64,
+        physical: u64,
+        length: u64,
+        reserved64: [u64; 2],
+        flags: u32,
+        reserved: [u32; 3],
+    }
+
+    #[repr(C)]
+    struct Request {
+        start: u64,
+        length: u64,
+        flags: u32,
+        mapped_extents: u32,
+        extent_count: u32,
+        reserved: u32,
+        extents: [Extent; BATCH],
+    }
+
+    /// Logical offset, physical offset and length of each extent of the file,
+    /// or `None` if they can't be compared
+    pub fn extents(path: &Path) -> io::Result<Option<Vec<(u64, u64, u64)>>> {
+        let file = fs::File::open(path)?;
+        let mut extents = vec![];
+        let mut start = 0;
+        loop {
+            let mut request = Request {
+                start,
+                length: u64::MAX,
+                flags: FIEMAP_FLAG_SYNC,
+                mapped_extents: 0,
+                extent_count: BATCH as u32,
+                reserved: 0,
+                extents: [Extent::default(); BATCH],
+            };
+            // SAFETY: `request` is a valid `struct fiemap`, with room for
+            // `extent_count` extents
+            if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP, &mut request) } != 0 {
+                return Ok(None);
+            }
+            let mapped = &request.extents[..request.mapped_extents as usize];
+            for extent in mapped {
+                if extent.flags & FIEMAP_EXTENT_NOT_COMPARABLE != 0 {
+                    return Ok(None);
+                }
+                extents.push((extent.logical, extent.physical, extent.length));
+            }
+            match mapped.last() {
+                Some(last) if last.flags & FIEMAP_EXTENT_LAST == 0 => {
+                    start = last.logical + last.length;
+                }
+                _ => return Ok(Some(extents)),
+            }
+        }
+    }
+}
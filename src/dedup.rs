@@ -0,0 +1,163 @@
+//! Deduplicating identical files across key dirs
+//!
+//! Files of the same size are hashed with blake3, and all the copies of the
+//! same content are replaced with reflinks of one of them (sharing data blocks
+//! copy-on-write), where the file system supports it. Otherwise they are
+//! hardlinked, but only if they are read-only (as in published dirs), so they
+//! can't be modified in place through one of the dirs. Copies already sharing
+//! all their blocks (e.g. reflinked by an earlier run) are left alone.
+//!
+//! The keys are measured again afterwards, so hardlinked files are counted
+//! once in the totals (see `du` and `metrics`), and not at all against quotas.
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{MetadataExt as _, PermissionsExt as _};
+use std::path::{Path, PathBuf};
+
+use rand::distributions::{Alphanumeric, DistString};
+use tracing::debug;
+
+use crate::{clone, util, LOG_TARGET};
+
+/// What `dedup_dirs` did
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stats {
+    pub reflinked: u64,
+    pub hardlinked: u64,
+    /// Apparent size of the copies no longer taking space on their own
+    pub bytes_saved: u64,
+}
+
+/// A file, with all its paths in the dirs
+struct Inode {
+    paths: Vec<PathBuf>,
+    metadata: fs::Metadata,
+}
+
+impl Inode {
+    fn is_read_only(&self) -> bool {
+        self.metadata.permissions().mode() & 0o222 == 0
+    }
+}
+
+/// Replace identical files in all the `dirs` with clones of one of them
+///
+/// The dirs must not be modified meanwhile.
+pub fn dedup_dirs(dirs: &[PathBuf]) -> io::Result<Stats> {
+    let mut inodes = HashMap::new();
+    for dir in dirs {
+        collect_files(dir, &mut inodes)?;
+    }
+
+    let mut by_size: HashMap<u64, Vec<Inode>> = HashMap::new();
+    for inode in inodes.into_values() {
+        by_size.entry(inode.metadata.len()).or_default().push(inode);
+    }
+
+    let mut stats = Stats::default();
+    for (_, same_size) in by_size {
+        if same_size.len() < 2 {
+            continue;
+        }
+        let mut by_hash: HashMap<blake3::Hash, Vec<Inode>> = HashMap::new();
+        for inode in same_size {
+            by_hash
+                .entry(hash_file(&inode.paths[0])?)
+                .or_default()
+                .push(inode);
+        }
+        for (_, mut same) in by_hash {
+            // keep the one linked the most, saving the most renames
+            same.sort_by_key(|inode| std::cmp::Reverse(inode.paths.len()));
+            let (original, copies) = same.split_first().expect("not empty");
+            for copy in copies {
+                dedup_file(original, copy, &mut stats)?;
+            }
+        }
+    }
+    Ok(stats)
+}
+
+fn collect_files(dir: &Path, inodes: &mut HashMap<(u64, u64), Inode>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            collect_files(&entry.path(), inodes)?;
+        } else if file_type.is_file() {
+            let metadata = entry.metadata()?;
+            if metadata.len() == 0 {
+                continue;
+            }
+            inodes
+                .entry((metadata.dev(), metadata.ino()))
+                .or_insert_with(|| Inode {
+                    paths: vec![],
+                    metadata,
+                })
+                .paths
+                .push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+fn hash_file(path: &Path) -> io::Result<blake3::Hash> {
+    let mut hasher = blake3::Hasher::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize())
+}
+
+/// Replace all the paths of `copy` with clones of `original`
+fn dedup_file(original: &Inode, copy: &Inode, stats: &mut Stats) -> io::Result<()> {
+    if original.metadata.dev() != copy.metadata.dev()
+        || clone::shares_data(&original.paths[0], &copy.paths[0])?
+    {
+        return Ok(());
+    }
+    let hardlink = original.is_read_only()
+        && copy.is_read_only()
+        && original.metadata.permissions() == copy.metadata.permissions();
+    for path in &copy.paths {
+        let tmp_path = path.with_file_name(format!(
+            ".dedup-{}",
+            Alphanumeric.sample_string(&mut rand::thread_rng(), 8)
+        ));
+        let dir = path.parent().expect("file in a dir");
+        // published dirs are read-only
+        let res = util::with_writable_dir(dir, || {
+            if clone::reflink(&original.paths[0], &tmp_path)? {
+                // keep everything but the data blocks of the copy
+                let file = fs::File::open(&tmp_path)?;
+                file.set_modified(copy.metadata.modified()?)?;
+                file.set_permissions(copy.metadata.permissions())?;
+                fs::rename(&tmp_path, path)?;
+                stats.reflinked += 1;
+            } else if hardlink {
+                fs::hard_link(&original.paths[0], &tmp_path)?;
+                fs::rename(&tmp_path, path)?;
+                stats.hardlinked += 1;
+            } else {
+                return Ok(false);
+            }
+            Ok(true)
+        });
+        match res {
+            Ok(true) => {
+                debug!(target: LOG_TARGET, path = %path.display(), original = %original.paths[0].display(), "Deduplicated");
+            }
+            // can't be shared safely
+            Ok(false) => return Ok(()),
+            Err(err) => {
+                let _ = util::with_writable_dir(dir, || fs::remove_file(&tmp_path));
+                return Err(err);
+            }
+        }
+    }
+    // linked from outside the dirs too, if not all the links were replaced
+    if u64::try_from(copy.paths.len()).unwrap_or(u64::MAX) == copy.metadata.nlink() {
+        stats.bytes_saved += copy.metadata.len();
+    }
+    Ok(())
+}
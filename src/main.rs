@@ -1,22 +1,44 @@
+mod archive;
+mod clone;
+mod dedup;
+mod manifest;
+mod metrics;
+mod remote;
 mod root;
 mod util;
 
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 use std::{ffi, fs, io, process};
 
 use anyhow::{bail, format_err, Context, Result};
 use chrono::Utc;
-use clap::{Args, Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use rand::distributions::{Alphanumeric, DistString};
-use root::{mk_lock, Root};
-use tracing::{debug, error, warn};
+use root::dto::{
+    is_key, is_key_name, key_name_of, slot_key, GcRun, HolderInfo, LockingMode, MetadataStoreKind,
+    OnFailure, OverQuota, OwnerProcess, UsageStats,
+};
+use root::journal;
+use root::{mk_lock, try_lock, LockRequest, ManifestUpdate, Root};
+use tracing::{debug, error, info, warn};
 use tracing_subscriber::EnvFilter;
 
 const LOG_TARGET: &str = "fs_dir_cache";
 
+/// Timeout of `exec` locks in roots shared between hosts, renewed while the
+/// command runs
+const EXEC_LEASE_SECS: f64 = 60.0;
+
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Opts {
+    /// Prometheus textfile to update after each `lock`, `unlock`, `exec` and
+    /// `gc`
+    #[arg(long, global = true, env = "FS_DIR_CACHE_METRICS_TEXTFILE")]
+    metrics_textfile: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -32,8 +54,8 @@ struct CommonLockOpts {
     /// Name of the cache
     ///
     /// Base part of the unique key identifying cache subdir
-    #[arg(long, env = "FS_DIR_CACHE_KEY_NAME")]
-    key_name: String,
+    #[arg(long, env = "FS_DIR_CACHE_KEY_NAME", required_unless_present = "cache")]
+    key_name: Option<String>,
 
     /// A string to hash into the final cache subdir id
     ///
@@ -47,6 +69,134 @@ struct CommonLockOpts {
     /// Can be passed multiple times (order is significant).
     #[arg(long)]
     key_file: Vec<PathBuf>,
+
+    /// Another cache to lock at the same time, as
+    /// `env=VAR,key-name=NAME[,key-str=STR...][,key-file=PATH...]`
+    ///
+    /// Can be passed multiple times. All the keys get locked at once, only
+    /// when all of them are available. `exec` passes the dir of each in the
+    /// `VAR` env var, `lock` prints `VAR=DIR` lines after the dir of the
+    /// `--key-name` key (if any).
+    #[arg(long)]
+    cache: Vec<CacheSpec>,
+
+    /// Remote cache to fall back to, e.g. `https://cache.example.com/fs-dir-cache`
+    ///
+    /// Dirs missing locally are downloaded from it before being handed out,
+    /// and `exec` uploads them back after the command succeeds. Remote
+    /// failures are only logged.
+    #[arg(long, env = "FS_DIR_CACHE_REMOTE")]
+    remote: Option<String>,
+}
+
+/// Options of commands that can wait for busy keys
+#[derive(Args)]
+struct WaitOpts {
+    /// Priority of this lock request
+    ///
+    /// Waiters for a busy key get the lock in order of arrival, except ones
+    /// with a higher priority go first (e.g. release builds ahead of PR
+    /// builds).
+    #[arg(
+        long,
+        env = "FS_DIR_CACHE_PRIORITY",
+        default_value_t = 0,
+        allow_negative_numbers = true
+    )]
+    priority: i32,
+}
+
+impl CommonLockOpts {
+    /// Keys to lock: the `--key-name` one (if given), then the `--cache` ones
+    fn keys(&self) -> Result<Vec<String>> {
+        let main_key = self
+            .key_name
+            .as_ref()
+            .map(|key_name| get_key(key_name, &self.key_str, &self.key_file));
+        let cache_keys = self
+            .cache
+            .iter()
+            .map(|cache| get_key(&cache.key_name, &cache.key_str, &cache.key_file));
+        main_key.into_iter().chain(cache_keys).collect()
+    }
+}
+
+/// Additional cache to lock, given with `--cache`
+#[derive(Clone)]
+struct CacheSpec {
+    /// Env var to pass the dir in
+    env: String,
+    key_name: String,
+    key_str: Vec<String>,
+    key_file: Vec<PathBuf>,
+}
+
+impl std::str::FromStr for CacheSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mut env, mut key_name) = (None, None);
+        let (mut key_str, mut key_file) = (vec![], vec![]);
+        for part in s.split(',') {
+            let Some((name, value)) = part.split_once('=') else {
+                bail!("Expected `name=value`, got: {part}");
+            };
+            match name {
+                "env" => env = Some(value.to_owned()),
+                "key-name" => key_name = Some(value.to_owned()),
+                "key-str" => key_str.push(value.to_owned()),
+                "key-file" => key_file.push(PathBuf::from(value)),
+                _ => bail!("Unknown cache option: {name}"),
+            }
+        }
+        Ok(Self {
+            env: env.ok_or_else(|| format_err!("Missing `env=` in: {s}"))?,
+            key_name: key_name.ok_or_else(|| format_err!("Missing `key-name=` in: {s}"))?,
+            key_str,
+            key_file,
+        })
+    }
+}
+
+/// Dirs of the keys locked by `lock` and `exec`
+struct LockedDirs {
+    /// Dir of the `--key-name` key
+    main: Option<PathBuf>,
+    /// Dirs of `--cache` keys, with their env var names
+    caches: Vec<(String, PathBuf)>,
+}
+
+impl LockedDirs {
+    /// `dirs` of the keys in order of [`CommonLockOpts::keys`]
+    fn new(common_opts: &CommonLockOpts, mut dirs: Vec<PathBuf>) -> Self {
+        let caches = dirs.split_off(dirs.len() - common_opts.cache.len());
+        Self {
+            main: dirs.pop(),
+            caches: common_opts
+                .cache
+                .iter()
+                .map(|cache| cache.env.clone())
+                .zip(caches)
+                .collect(),
+        }
+    }
+
+    fn try_map(self, mut f: impl FnMut(PathBuf) -> Result<PathBuf>) -> Result<Self> {
+        Ok(Self {
+            main: self.main.map(&mut f).transpose()?,
+            caches: self
+                .caches
+                .into_iter()
+                .map(|(env, dir)| Ok((env, f(dir)?)))
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    fn all(&self) -> impl Iterator<Item = &PathBuf> {
+        self.main
+            .iter()
+            .chain(self.caches.iter().map(|(_env, dir)| dir))
+    }
 }
 
 #[derive(Args)]
@@ -60,6 +210,49 @@ struct LockOpts {
     #[arg(long)]
     #[arg(long, env = "FS_DIR_CACHE_LOCK_TIMEOUT_SECS")]
     timeout_secs: f64,
+
+    /// Keep the lock alive in the background, renewing it for another
+    /// `--timeout-secs` every given amount of seconds, until it's unlocked or
+    /// the calling process exits
+    ///
+    /// Allows using short timeouts, so a crashed job doesn't block the key
+    /// for long.
+    #[arg(long, env = "FS_DIR_CACHE_LOCK_HEARTBEAT_SECS")]
+    heartbeat_secs: Option<f64>,
+
+    /// Process holding the lock; once it's gone the lock is considered
+    /// released, even before the timeout
+    ///
+    /// Defaults to the calling process. Shells run a lone command
+    /// substitution like `dir=$(fs-dir-cache lock ...)` without a subshell,
+    /// so that's the script's shell; otherwise pass the pid explicitly.
+    #[arg(long, env = "FS_DIR_CACHE_LOCK_OWNER_PID")]
+    owner_pid: Option<u32>,
+}
+
+#[derive(Args, Debug)]
+struct QuotaOpts {
+    /// Size limit of each key dir (apparent size), checked when the lock is
+    /// released; overrides the root config
+    ///
+    /// Not checked for `exec --publish`.
+    #[arg(long, env = "FS_DIR_CACHE_MAX_KEY_BYTES")]
+    max_key_bytes: Option<u64>,
+
+    /// What to do with dirs over `--max-key-bytes`; overrides the root config
+    #[arg(long, env = "FS_DIR_CACHE_OVER_QUOTA", value_enum)]
+    over_quota: Option<OverQuota>,
+}
+
+impl QuotaOpts {
+    /// Size limit and what to do over it, if there's any
+    fn resolve(&self, root_dir: &Path) -> Result<Option<(u64, OverQuota)>> {
+        let config = root::load_config(root_dir)?;
+        Ok(self
+            .max_key_bytes
+            .or(config.max_key_bytes)
+            .map(|max_bytes| (max_bytes, self.over_quota.unwrap_or(config.over_quota))))
+    }
 }
 
 #[derive(Args, Debug)]
@@ -72,6 +265,38 @@ struct UnlockOpts {
     /// Lock used during `unlock`
     #[arg(long, env = "FS_DIR_CACHE_LOCK_ID")]
     lock_id: String,
+
+    /// Record a content manifest of the dir, checked by `verify`
+    #[arg(long, env = "FS_DIR_CACHE_MANIFEST")]
+    manifest: bool,
+
+    #[clap(flatten)]
+    quota: QuotaOpts,
+}
+
+#[derive(Args, Debug)]
+/// Extend the timeout of a held lock
+struct RenewOpts {
+    /// Cache key dir
+    #[arg(long)]
+    dir: PathBuf,
+
+    /// Lock id used to acquire the lock
+    #[arg(long, env = "FS_DIR_CACHE_LOCK_ID")]
+    lock_id: String,
+
+    /// Extend the lock to given amount of seconds from now
+    #[arg(long, env = "FS_DIR_CACHE_LOCK_TIMEOUT_SECS")]
+    timeout_secs: f64,
+
+    /// Keep renewing every given amount of seconds, until the lock is no
+    /// longer held
+    #[arg(long)]
+    every_secs: Option<f64>,
+
+    /// Stop renewing when a process with a given pid exits
+    #[arg(long, requires = "every_secs")]
+    owner_pid: Option<u32>,
 }
 
 #[derive(Args)]
@@ -81,19 +306,330 @@ struct GC {
     #[arg(long, env = "FS_DIR_CACHE_ROOT")]
     root: PathBuf,
 
+    /// Remote cache to garbage collect as well, by the same policy
+    ///
+    /// Needs a backend that can list its contents (e.g. S3).
+    #[arg(long, env = "FS_DIR_CACHE_REMOTE")]
+    remote: Option<String>,
+
     #[command(subcommand)]
     mode: GCModeCommand,
 }
 
+#[derive(Args)]
+/// Show the state of a cache key and its current lock holder
+struct StatusOpts {
+    #[clap(flatten)]
+    key: KeyOpts,
+}
+
+/// Identifies a single, existing key
+#[derive(Args)]
+struct KeyOpts {
+    /// Cache key dir (as printed by `lock`)
+    ///
+    /// Alternative to identifying the key with `--root`, `--key-name`, etc.
+    #[arg(long)]
+    dir: Option<PathBuf>,
+
+    /// Root cache dir
+    #[arg(long, env = "FS_DIR_CACHE_ROOT")]
+    root: Option<PathBuf>,
+
+    /// Name of the cache
+    #[arg(long, env = "FS_DIR_CACHE_KEY_NAME")]
+    key_name: Option<String>,
+
+    /// A string to hash into the final cache subdir id
+    #[arg(long)]
+    key_str: Vec<String>,
+
+    /// A path to a file to hash the content of into the final cache
+    /// subdir id
+    #[arg(long)]
+    key_file: Vec<PathBuf>,
+}
+
+impl KeyOpts {
+    /// Root and key of the cache subdir
+    fn root_and_key(self) -> Result<(PathBuf, String)> {
+        if let Some(dir) = self.dir {
+            return split_key_dir_path(&dir);
+        }
+        let (Some(root), Some(key_name)) = (self.root, self.key_name) else {
+            bail!("Either `--dir` or `--root` and `--key-name` must be given");
+        };
+        let key = get_key(&key_name, &self.key_str, &self.key_file)?;
+        Ok((root, key))
+    }
+}
+
+#[derive(Args)]
+/// Show cache usage statistics
+struct StatsOpts {
+    /// Root cache dir
+    #[arg(long, env = "FS_DIR_CACHE_ROOT")]
+    root: PathBuf,
+
+    /// Show statistics of every individual key, not only per key name
+    #[arg(long)]
+    keys: bool,
+
+    /// Reset all statistics after printing them
+    #[arg(long)]
+    reset: bool,
+}
+
+#[derive(Args)]
+/// Export Prometheus metrics
+struct MetricsOpts {
+    /// Root cache dir
+    #[arg(long, env = "FS_DIR_CACHE_ROOT")]
+    root: PathBuf,
+
+    /// Atomically write the metrics to a given file (e.g. for node_exporter
+    /// textfile collector), instead of stdout
+    #[arg(long)]
+    textfile: Option<PathBuf>,
+}
+
+#[derive(Args)]
+/// Show the event journal of the root
+struct LogOpts {
+    /// Root cache dir
+    #[arg(long, env = "FS_DIR_CACHE_ROOT")]
+    root: PathBuf,
+
+    /// Only show events of a given key (full key or just the key name)
+    #[arg(long)]
+    key: Option<String>,
+
+    /// Only show events of a given lock id
+    #[arg(long)]
+    lock_id: Option<String>,
+
+    /// Number of most recent events to show
+    #[arg(long, short = 'n', default_value_t = 50)]
+    lines: usize,
+
+    /// Keep printing new events as they are recorded
+    #[arg(long, short = 'f')]
+    follow: bool,
+}
+
+impl LogOpts {
+    fn matches(&self, entry: &journal::Entry) -> bool {
+        let key_matches = match &self.key {
+            None => true,
+            Some(key) => entry
+                .key
+                .as_deref()
+                .is_some_and(|k| k == key || key_name_of(k) == key),
+        };
+        let lock_id_matches = match &self.lock_id {
+            None => true,
+            Some(lock_id) => entry.lock_id.as_ref() == Some(lock_id),
+        };
+        key_matches && lock_id_matches
+    }
+}
+
+#[derive(Args)]
+/// Show or change the configuration of the root
+struct ConfigOpts {
+    /// Root cache dir
+    #[arg(long, env = "FS_DIR_CACHE_ROOT")]
+    root: PathBuf,
+
+    /// Where to store metadata of the keys; existing metadata gets moved.
+    /// Should only be changed while the root is not in use.
+    #[arg(long, value_enum)]
+    metadata_store: Option<MetadataStoreKind>,
+
+    /// How to lock; `lockfile` is needed when the root is on a network file
+    /// system shared between hosts. Should only be changed while the root is
+    /// not in use.
+    #[arg(long, value_enum)]
+    locking: Option<LockingMode>,
+
+    /// Size limit of each key dir, checked when the lock is released by
+    /// `exec` or `unlock`; `0` removes it
+    #[arg(long)]
+    max_key_bytes: Option<u64>,
+
+    /// What to do with key dirs over `--max-key-bytes`
+    #[arg(long, value_enum)]
+    over_quota: Option<OverQuota>,
+}
+
+#[derive(Args)]
+/// Create a cache key dir as a (copy-on-write, where possible) clone of
+/// another one
+struct CloneOpts {
+    /// Cache key dir to clone (as printed by `lock`)
+    #[arg(long)]
+    from: PathBuf,
+
+    /// Root cache dir, the one of `--from`
+    #[arg(long, env = "FS_DIR_CACHE_ROOT")]
+    root: PathBuf,
+
+    /// Name of the cache to create
+    #[arg(long, env = "FS_DIR_CACHE_KEY_NAME")]
+    key_name: String,
+
+    /// A string to hash into the final cache subdir id
+    #[arg(long)]
+    key_str: Vec<String>,
+
+    /// A path to a file to hash the content of into the final cache
+    /// subdir id
+    #[arg(long)]
+    key_file: Vec<PathBuf>,
+
+    /// Publish the new dir, making it immutable like `exec --publish` does
+    ///
+    /// Files that can't be reflinked are then hardlinked from a published
+    /// `--from` dir, instead of copied.
+    #[arg(long)]
+    publish: bool,
+}
+
+#[derive(Args)]
+/// Write a cache key dir as a `tar.zst` archive
+struct ExportOpts {
+    /// Cache key dir to export (as printed by `lock`)
+    #[arg(long)]
+    dir: PathBuf,
+
+    /// File to write the archive to, instead of stdout
+    #[arg(long, short = 'o')]
+    output: Option<PathBuf>,
+}
+
+#[derive(Args)]
+/// Install a cache key dir from an archive written by `export`
+///
+/// Replaces the existing dir of the key, unless it's locked.
+struct ImportOpts {
+    /// Root cache dir
+    #[arg(long, env = "FS_DIR_CACHE_ROOT")]
+    root: PathBuf,
+
+    /// File to read the archive from, instead of stdin
+    #[arg(long, short = 'i')]
+    input: Option<PathBuf>,
+}
+
+#[derive(Args)]
+/// Check cache key dirs against the content manifests recorded on release
+///
+/// Reports modified, missing and extra files. Keys without a manifest, or
+/// locked by someone else, are skipped. Fails if any key is corrupted (and
+/// not repaired).
+struct VerifyOpts {
+    #[clap(flatten)]
+    key: KeyOpts,
+
+    /// Verify all the keys in `--root`
+    #[arg(long, conflicts_with = "dir")]
+    all: bool,
+
+    /// What to do with corrupted keys
+    #[arg(long, value_enum)]
+    repair: Option<Repair>,
+}
+
+#[derive(Args)]
+/// Replace identical files across cache key dirs with reflinks of one of
+/// them, or hardlinks if they are read-only
+///
+/// Keys locked by someone else are skipped. Prints the number of bytes saved.
+struct DedupOpts {
+    /// Root cache dir
+    #[arg(long, env = "FS_DIR_CACHE_ROOT")]
+    root: PathBuf,
+}
+
+#[derive(Args)]
+/// Show the disk usage of cache key dirs, per key name and per key
+///
+/// Sizes are measured in the background after each release, so can be stale
+/// (or missing).
+struct DuOpts {
+    /// Root cache dir
+    #[arg(long, env = "FS_DIR_CACHE_ROOT")]
+    root: Option<PathBuf>,
+
+    /// Only this cache key dir (as printed by `lock`), instead of all the
+    /// keys in `--root`
+    #[arg(long)]
+    dir: Option<PathBuf>,
+
+    /// Measure the keys with stale sizes first, unless they are locked
+    #[arg(long)]
+    refresh: bool,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Repair {
+    /// Delete the dir, to be populated from scratch
+    Discard,
+}
+
 #[derive(Args)]
 struct ExecOpts {
     #[clap(flatten)]
     opts: CommonLockOpts,
 
+    #[clap(flatten)]
+    wait: WaitOpts,
+
+    /// What to do if the keys are locked by someone else
+    ///
+    /// `wait` for them; `skip` caching, running in an empty temporary dir;
+    /// `clone` the busy dirs into temporary ones; `fresh`: use an additional
+    /// slot of the keys (separate dirs, kept like any other key), waiting for
+    /// the keys themselves once all the slots are busy. Temporary dirs are
+    /// deleted afterwards, or by `gc` if left behind.
+    #[arg(long, env = "FS_DIR_CACHE_ON_BUSY", value_enum, default_value_t = OnBusy::Wait)]
+    on_busy: OnBusy,
+
+    /// Populate the key only once: run the command in a staging dir, moved
+    /// into place only if it succeeds
+    ///
+    /// Once published, the dir is immutable (its files are made read-only),
+    /// and is shared by all the later `exec --publish` commands, without
+    /// locking. Commands must not rely on the path of the dir while
+    /// populating it. `--on-busy` and `--on-failure` don't apply.
+    #[arg(long, conflicts_with = "cache")]
+    publish: bool,
+
+    /// What to do with the dirs if the command fails, possibly leaving them
+    /// half-written
+    #[arg(long, env = "FS_DIR_CACHE_ON_FAILURE", value_enum, default_value_t = OnFailure::Keep)]
+    on_failure: OnFailure,
+
+    /// Record a content manifest of the dirs after the command succeeds,
+    /// checked by `verify`
+    #[arg(long, env = "FS_DIR_CACHE_MANIFEST")]
+    manifest: bool,
+
+    #[clap(flatten)]
+    quota: QuotaOpts,
+
     #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
     exec: Vec<ffi::OsString>,
 }
 
+#[derive(Clone, Copy, ValueEnum)]
+enum OnBusy {
+    Wait,
+    Skip,
+    Clone,
+    Fresh,
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Acquire a lock on cache key subdir in a given cache root
@@ -103,13 +639,28 @@ enum Commands {
         common: CommonLockOpts,
         #[clap(flatten)]
         lock: LockOpts,
+        #[clap(flatten)]
+        wait: WaitOpts,
     },
     /// Unlock the lock manually
     Unlock(UnlockOpts),
+    Renew(RenewOpts),
     /// Run a command with lock acquired, allows for automatic and reliable
     /// unlocking after command finishes.
     Exec(ExecOpts),
     GC(GC),
+    /// Show who holds the lock on a cache key subdir
+    Status(StatusOpts),
+    Stats(StatsOpts),
+    Metrics(MetricsOpts),
+    Log(LogOpts),
+    Config(ConfigOpts),
+    Clone(CloneOpts),
+    Export(ExportOpts),
+    Import(ImportOpts),
+    Verify(VerifyOpts),
+    Dedup(DedupOpts),
+    Du(DuOpts),
 }
 
 #[derive(Subcommand)]
@@ -124,23 +675,107 @@ enum GCModeCommand {
 fn main() -> Result<()> {
     init_logging();
     let opts = Opts::parse();
+    let metrics_textfile = opts.metrics_textfile.as_deref();
 
     match opts.command {
         Commands::Lock {
             common: common_opts,
             lock: lock_opts,
-        } => println!("{}", lock(Some(lock_opts), common_opts, None)?.display()),
+            wait,
+        } => {
+            let root_dir = common_opts.root.clone();
+            let lock_id = lock_opts.lock_id.clone();
+            // `lock` exits right away, the caller is the one actually holding the lock
+            let caller_pid = std::os::unix::process::parent_id();
+            let owner_pid = lock_opts.owner_pid.unwrap_or(caller_pid);
+            let heartbeat = lock_opts
+                .heartbeat_secs
+                .map(|every_secs| -> Result<_> {
+                    if lock_opts.timeout_secs <= every_secs {
+                        bail!("`--heartbeat-secs` must be shorter than `--timeout-secs`");
+                    }
+                    Ok(RenewOpts {
+                        dir: PathBuf::new(),
+                        lock_id: lock_opts.lock_id.clone(),
+                        timeout_secs: lock_opts.timeout_secs,
+                        every_secs: Some(every_secs),
+                        owner_pid: Some(owner_pid),
+                    })
+                })
+                .transpose()?;
+            let req = LockRequest {
+                lock_id: lock_id.clone(),
+                timeout_secs: lock_opts.timeout_secs,
+                socket_path: None,
+                owner: Some(OwnerProcess::new(owner_pid)),
+                holder: lock_holder(owner_pid),
+                priority: wait.priority,
+                publish: false,
+                maintenance: false,
+            };
+            let dirs = lock(&common_opts, req, OnBusy::Wait)?.expect("waited for the lock");
+            if let Some(heartbeat) = heartbeat {
+                for dir in dirs.all() {
+                    spawn_renew(RenewOpts {
+                        dir: dir.clone(),
+                        lock_id: heartbeat.lock_id.clone(),
+                        ..heartbeat
+                    })?;
+                }
+            }
+            download_missing(&common_opts, &dirs, &lock_id)?;
+            if let Some(dir) = dirs.main {
+                println!("{}", dir.display());
+            }
+            for (env, dir) in dirs.caches {
+                println!("{env}={}", dir.display());
+            }
+            metrics::update_textfile(&root_dir, metrics_textfile);
+        }
         Commands::Unlock(unlock_opts) => {
+            let (root_dir, _key) = split_key_dir_path(&unlock_opts.dir)?;
             unlock(unlock_opts)?;
+            metrics::update_textfile(&root_dir, metrics_textfile);
+        }
+        Commands::Renew(renew_opts) => renew(renew_opts)?,
+        Commands::GC(gc_options) => {
+            let root_dir = gc_options.root.clone();
+            gc(gc_options)?;
+            metrics::update_textfile(&root_dir, metrics_textfile);
         }
-        Commands::GC(gc_options) => gc(gc_options)?,
-        Commands::Exec(exec_opts) => run_exec(exec_opts)?,
+        Commands::Exec(exec_opts) => run_exec(exec_opts, metrics_textfile)?,
+        Commands::Status(status_opts) => status(status_opts)?,
+        Commands::Stats(stats_opts) => stats(stats_opts)?,
+        Commands::Metrics(metrics_opts) => match metrics_opts.textfile {
+            Some(textfile) => metrics::write_textfile(&metrics_opts.root, &textfile)?,
+            None => print!("{}", metrics::render(&metrics_opts.root)?),
+        },
+        Commands::Log(log_opts) => log(log_opts)?,
+        Commands::Config(config_opts) => config(config_opts)?,
+        Commands::Clone(clone_opts) => clone_key(clone_opts)?,
+        Commands::Export(export_opts) => export(export_opts)?,
+        Commands::Import(import_opts) => import(import_opts)?,
+        Commands::Verify(verify_opts) => verify(verify_opts)?,
+        Commands::Dedup(dedup_opts) => dedup(dedup_opts)?,
+        Commands::Du(du_opts) => du(du_opts)?,
     }
 
     Ok(())
 }
 
-fn run_exec(ExecOpts { opts, exec }: ExecOpts) -> Result<()> {
+fn run_exec(
+    ExecOpts {
+        mut opts,
+        wait,
+        on_busy,
+        publish,
+        on_failure,
+        manifest,
+        quota,
+        exec,
+    }: ExecOpts,
+    metrics_textfile: Option<&Path>,
+) -> Result<()> {
     if exec.is_empty() {
         bail!("Missing command");
     }
@@ -150,6 +785,8 @@ fn run_exec(ExecOpts { opts, exec }: ExecOpts) -> Result<()> {
         .to_string();
 
     let root = std::fs::canonicalize(&opts.root)?;
+    // cache dirs passed in env vars must work from within the main one
+    opts.root = root.clone();
 
     let sock_path = root.join(PathBuf::from(format!(
         "lock-{}",
@@ -164,27 +801,343 @@ fn run_exec(ExecOpts { opts, exec }: ExecOpts) -> Result<()> {
 
     let _lock = mk_lock(&sock_path)?;
 
-    let exec_dir = lock(None, opts, Some(sock_path.clone()))?;
+    let lock_id = exec_lock_id();
+    // liveness socket can't be checked from other hosts, so use a lease
+    // that's kept renewed while the command runs
+    let use_lease = root::load_config(&root)?.locking == LockingMode::Lockfile;
+    if publish {
+        let status = exec_publish(
+            &opts,
+            wait.priority,
+            &exec,
+            &sock_path,
+            &lock_id,
+            use_lease,
+            manifest,
+        )?;
+        metrics::update_textfile(&root, metrics_textfile);
+        return finish_exec(&sock_path, status, &cmd_str);
+    }
+    let req = LockRequest {
+        lock_id: lock_id.clone(),
+        timeout_secs: if use_lease { EXEC_LEASE_SECS } else { 0.0 },
+        socket_path: Some(sock_path.clone()),
+        owner: None,
+        holder: exec_holder(&exec),
+        priority: wait.priority,
+        publish: false,
+        maintenance: false,
+    };
+    let locked = lock(&opts, req, on_busy)?;
+    let is_locked = locked.is_some();
+    let dirs = match locked {
+        Some(dirs) => dirs,
+        None => {
+            let busy_dirs = LockedDirs::new(
+                &opts,
+                opts.keys()?.iter().map(|key| root.join(key)).collect(),
+            );
+            busy_dirs.try_map(|busy_dir| {
+                let scratch_dir = root::create_scratch_dir(&root)?;
+                match on_busy {
+                    OnBusy::Clone if busy_dir.exists() => {
+                        info!(target: LOG_TARGET, busy_dir = %busy_dir.display(), "Key busy, using a clone");
+                        clone::clone_dir(&busy_dir, &scratch_dir, clone::Options::default())?;
+                    }
+                    _ => {
+                        info!(target: LOG_TARGET, busy_dir = %busy_dir.display(), "Key busy, running uncached");
+                    }
+                }
+                Ok(scratch_dir)
+            })?
+        }
+    };
 
-    fs::create_dir_all(&exec_dir)?;
+    if use_lease && is_locked {
+        for dir in dirs.all() {
+            spawn_renew(RenewOpts {
+                dir: dir.clone(),
+                lock_id: lock_id.clone(),
+                timeout_secs: EXEC_LEASE_SECS,
+                every_secs: Some(EXEC_LEASE_SECS / 3.0),
+                owner_pid: Some(process::id()),
+            })?;
+        }
+    }
+    if is_locked {
+        download_missing(&opts, &dirs, &lock_id)?;
+    }
+    for dir in dirs.all() {
+        fs::create_dir_all(dir)?;
+    }
 
     debug!(
         target: LOG_TARGET,
-        cmd = ?exec, ?exec_dir, "Executing user command"
+        cmd = ?exec, exec_dir = ?dirs.main, caches = ?dirs.caches, "Executing user command"
     );
-    if !process::Command::new(&exec[0])
-        .args(&exec[1..])
-        .current_dir(exec_dir)
-        .status()
-        .context("Executing user command failed")?
-        .success()
-    {
-        error!(cmd = %cmd_str, "User command failed");
-        bail!("User command failed");
+    let status = run_user_command(&exec, &dirs)?;
+
+    // before uploading, so runaway dirs don't get there
+    let mut over_quota = vec![];
+    if let (true, Some((max_bytes, action))) = (is_locked, quota.resolve(&root)?) {
+        for dir in dirs.all() {
+            match enforce_quota(dir, &lock_id, max_bytes, action) {
+                Ok(true) => over_quota.push(dir.clone()),
+                Ok(false) => {}
+                Err(err) => {
+                    error!(%err, dir = %dir.display(), "Failed to check the dir size");
+                }
+            }
+        }
     }
 
-    if let Err(err) = fs::remove_file(&sock_path) {
-        warn!(%err, sock_path=%sock_path.display(), "Error removing liveness socket")
+    if is_locked && status.success() {
+        upload_all(&opts, &dirs, &lock_id, &over_quota)?;
+    }
+
+    if is_locked && !status.success() {
+        for dir in dirs.all() {
+            // unlock even if this fails
+            let res = split_key_dir_path(dir).and_then(|(root_dir, key)| {
+                Root::new(root_dir)?.handle_failure(&key, &lock_id, on_failure)
+            });
+            if let Err(err) = res {
+                error!(%err, dir = %dir.display(), "Failed to handle the dir after failure");
+            }
+        }
+    }
+
+    if is_locked {
+        let mut unlock_res = Ok(());
+        for dir in dirs.all() {
+            // unlock as many as possible
+            let manifest = if manifest && status.success() && !over_quota.contains(dir) {
+                build_manifest(dir)
+            } else {
+                ManifestUpdate::Clear
+            };
+            if let Err(err) = unlock_with(dir, &lock_id, manifest) {
+                error!(%err, dir = %dir.display(), "Failed to unlock");
+                unlock_res = Err(err);
+            }
+        }
+        unlock_res?;
+    } else {
+        for dir in dirs.all() {
+            if let Err(err) = root::remove_scratch_dir(dir) {
+                warn!(%err, dir = %dir.display(), "Error removing temporary dir");
+            }
+        }
+    }
+    // only once done, not to delay the command
+    metrics::update_textfile(&root, metrics_textfile);
+
+    finish_exec(&sock_path, status, &cmd_str)
+}
+
+/// `exec --publish`: run the command in the published dir of the key,
+/// populating and publishing it first if needed
+fn exec_publish(
+    opts: &CommonLockOpts,
+    priority: i32,
+    exec: &[ffi::OsString],
+    sock_path: &Path,
+    lock_id: &str,
+    use_lease: bool,
+    manifest: bool,
+) -> Result<process::ExitStatus> {
+    let root = Root::new(&opts.root)?;
+    let key = opts.keys()?.pop().expect("`--key-name` is required");
+    let remote = opts.remote.as_deref().map(remote::open).transpose()?;
+    loop {
+        if let Some(dir) = root.start_reading(&key, lock_id, sock_path)? {
+            debug!(target: LOG_TARGET, dir = %dir.display(), "Using published dir");
+            let status = run_user_command(
+                exec,
+                &LockedDirs {
+                    main: Some(dir),
+                    caches: vec![],
+                },
+            );
+            root.stop_reading(&key, lock_id)?;
+            return status;
+        }
+
+        let req = LockRequest {
+            lock_id: lock_id.to_owned(),
+            timeout_secs: if use_lease { EXEC_LEASE_SECS } else { 0.0 },
+            socket_path: Some(sock_path.to_owned()),
+            owner: None,
+            holder: exec_holder(exec),
+            priority,
+            publish: true,
+            maintenance: false,
+        };
+        let dir = lock(opts, req, OnBusy::Wait)?
+            .and_then(|dirs| dirs.main)
+            .expect("waited for the lock");
+        if root.is_published(&key)? {
+            debug!(target: LOG_TARGET, key, "Published while waiting for the lock");
+            unlock_with(&dir, lock_id, ManifestUpdate::Keep)?;
+            continue;
+        }
+        if use_lease {
+            spawn_renew(RenewOpts {
+                dir: dir.clone(),
+                lock_id: lock_id.to_owned(),
+                timeout_secs: EXEC_LEASE_SECS,
+                every_secs: Some(EXEC_LEASE_SECS / 3.0),
+                owner_pid: Some(process::id()),
+            })?;
+        }
+
+        let staging_dir = root.create_staging_dir(&key)?;
+        if let Some(remote) = remote.as_deref() {
+            match remote::fetch(remote, &root, &key, &key, lock_id, &staging_dir) {
+                Ok(true) => {
+                    let publish_res = root.publish(&key, lock_id, &staging_dir);
+                    let manifest = if manifest && publish_res.is_ok() {
+                        build_manifest(&dir)
+                    } else {
+                        ManifestUpdate::Clear
+                    };
+                    unlock_with(&dir, lock_id, manifest)?;
+                    publish_res?;
+                    continue;
+                }
+                Ok(false) => {}
+                Err(err) => {
+                    warn!(%err, key, "Failed to download from remote cache");
+                    // start over with an empty one, without what was unpacked
+                    util::remove_dir_all(&staging_dir).context("Failed to remove staging dir")?;
+                    fs::create_dir(&staging_dir)?;
+                }
+            }
+        }
+        debug!(target: LOG_TARGET, staging_dir = %staging_dir.display(), "Populating dir to publish");
+        let status = run_user_command(
+            exec,
+            &LockedDirs {
+                main: Some(staging_dir.clone()),
+                caches: vec![],
+            },
+        );
+        let publish_res = match status {
+            Ok(status) if status.success() => root.publish(&key, lock_id, &staging_dir).map(|()| {
+                if let Some(remote) = remote.as_deref() {
+                    upload(remote, &root, &key, &key, lock_id);
+                }
+            }),
+            _ => fs::remove_dir_all(&staging_dir).context("Failed to remove staging dir"),
+        };
+        let manifest = match &status {
+            Ok(status) if status.success() && manifest && publish_res.is_ok() => {
+                build_manifest(&dir)
+            }
+            Ok(status) if status.success() => ManifestUpdate::Clear,
+            // the staging dir was discarded
+            _ => ManifestUpdate::Keep,
+        };
+        unlock_with(&dir, lock_id, manifest)?;
+        publish_res?;
+        return status;
+    }
+}
+
+/// Populate dirs missing locally from the `--remote` cache, if any
+fn download_missing(common_opts: &CommonLockOpts, dirs: &LockedDirs, lock_id: &str) -> Result<()> {
+    let Some(url) = common_opts.remote.as_deref() else {
+        return Ok(());
+    };
+    let remote = remote::open(url)?;
+    let root = Root::new(&common_opts.root)?;
+    // remote keys are the same in all slots
+    for (remote_key, dir) in common_opts.keys()?.iter().zip(dirs.all()) {
+        if dir.try_exists()? {
+            continue;
+        }
+        let (_root_dir, key) = split_key_dir_path(dir)?;
+        if let Err(err) = remote::download(&*remote, &root, &key, remote_key, lock_id) {
+            warn!(%err, key, "Failed to download from remote cache");
+        }
+    }
+    Ok(())
+}
+
+/// Upload all the dirs (but `skip`ped ones) to the `--remote` cache, if any
+fn upload_all(
+    common_opts: &CommonLockOpts,
+    dirs: &LockedDirs,
+    lock_id: &str,
+    skip: &[PathBuf],
+) -> Result<()> {
+    let Some(url) = common_opts.remote.as_deref() else {
+        return Ok(());
+    };
+    let remote = remote::open(url)?;
+    let root = Root::new(&common_opts.root)?;
+    for (remote_key, dir) in common_opts.keys()?.iter().zip(dirs.all()) {
+        if skip.contains(dir) {
+            continue;
+        }
+        let (_root_dir, key) = split_key_dir_path(dir)?;
+        upload(&*remote, &root, &key, remote_key, lock_id);
+    }
+    Ok(())
+}
+
+/// [`remote::upload`], only logging failures
+fn upload(
+    remote: &dyn remote::RemoteBackend,
+    root: &Root,
+    key: &str,
+    remote_key: &str,
+    lock_id: &str,
+) {
+    if let Err(err) = remote::upload(remote, root, key, remote_key, lock_id) {
+        warn!(%err, key, "Failed to upload to remote cache");
+    }
+}
+
+/// Process `lock` takes the lock for, or `lock` itself if it can't be
+/// described
+fn lock_holder(owner_pid: u32) -> HolderInfo {
+    match util::process_cmdline(owner_pid) {
+        Some(cmdline) => HolderInfo::new(owner_pid, cmdline),
+        None => HolderInfo::new(process::id(), std::env::args().collect()),
+    }
+}
+
+fn exec_holder(exec: &[ffi::OsString]) -> HolderInfo {
+    HolderInfo::new(
+        process::id(),
+        exec.iter()
+            .map(|s| s.to_string_lossy().into_owned())
+            .collect(),
+    )
+}
+
+/// Run the `exec` command in the main dir (if any), with cache dirs in env
+/// vars
+fn run_user_command(exec: &[ffi::OsString], dirs: &LockedDirs) -> Result<process::ExitStatus> {
+    let mut cmd = process::Command::new(&exec[0]);
+    cmd.args(&exec[1..]);
+    // with only `--cache` keys, run in the caller's dir
+    if let Some(exec_dir) = dirs.main.as_ref() {
+        cmd.current_dir(exec_dir);
+    }
+    for (env, dir) in &dirs.caches {
+        cmd.env(env, dir);
+    }
+    cmd.status().context("Executing user command failed")
+}
+
+fn finish_exec(sock_path: &Path, status: process::ExitStatus, cmd_str: &str) -> Result<()> {
+    remove_liveness_socket(sock_path);
+
+    if !status.success() {
+        error!(cmd = %cmd_str, "User command failed");
+        bail!("User command failed");
     }
 
     Ok(())
@@ -195,6 +1148,7 @@ fn gc(gc_options: GC) -> Result<()> {
         GCModeCommand::Unused { seconds } => {
             let mut root = Root::new(&gc_options.root)?;
 
+            let gc_start = std::time::Instant::now();
             let now = Utc::now();
             let deadline = now
                 .checked_sub_signed(chrono::Duration::seconds(
@@ -207,72 +1161,1011 @@ fn gc(gc_options: GC) -> Result<()> {
                 %now, %deadline, "Looking for unused keys"
             );
 
-            root.with_lock(|root| {
+            let trashed = root.with_lock(|root| {
                 let mut data = root.load_data()?;
+                let mut trashed = vec![];
 
-                let to_delete =  data
-                    .keys
-                    .iter()
-                    .filter(|(key, v)| {
+                for key in root.keys()? {
+                    let key_dir = root.key_dir_path(&key);
+                    let evicted = root.with_key_lock(&key, |locked_key| {
+                        let Some(v) = locked_key.load()? else {
+                            return Ok(None);
+                        };
                         debug!(
                             target: LOG_TARGET,
                             key, last_locked = %v.last_lock, locked_until = %v.locked_until, "Checking key"
                         );
-                        !v.is_timelocked(now) && v.is_last_used_before(deadline)
-                    })
-                    .map(|(k, _v)| k.to_owned()).collect::<Vec<_>>();
+                        if (v.is_timelocked(now) && !v.is_owner_gone())
+                            || v.has_live_readers(now)
+                            || !v.is_last_used_before(deadline)
+                        {
+                            return Ok(None);
+                        }
 
-                  for key in to_delete   {
-                    let key_dir = root.key_dir_path(&key);
-                    if key_dir.try_exists()? {
-                        debug!(
-                            target: LOG_TARGET,
-                            key_dir = %key_dir.display(), "Deleting key dir"
-                        );
-                        fs::remove_dir_all(&key_dir).with_context(|| "Failed to delete")?;
-                    } else {
-                        debug!(
-                            target: LOG_TARGET,
-                            key_dir = %key_dir.display(), "Does not exist"
-                        )
+                        // deleted once all the locks are released
+                        match root::trash_dir(&gc_options.root, &key_dir)? {
+                            Some(path) => trashed.push(path),
+                            None => debug!(
+                                target: LOG_TARGET,
+                                key_dir = %key_dir.display(), "Does not exist"
+                            ),
+                        }
+                        locked_key.remove()?;
+                        root.remove_key_files(&key)?;
+                        Ok(Some(v))
+                    })?;
+
+                    if let Some(v) = evicted {
+                        data.stats.record_eviction(&key, &v.stats);
+                        root.store_data(&data)?;
+                        root.record(journal::Entry::new(&key, None, journal::Event::GcEvicted));
+                        println!("{}", key_dir.display());
                     }
-                    data.keys.remove(&key);
-                    root.store_data(&data)?;
-                    println!("{}", key_dir.display());
                 }
 
-                Ok(())
-            })
+                let removed = root.remove_stale_tmp_files()?;
+                if 0 < removed {
+                    info!(target: LOG_TARGET, removed, "Removed stale temporary files");
+                }
+                let removed = root.remove_abandoned_scratch_dirs()?;
+                if 0 < removed {
+                    info!(target: LOG_TARGET, removed, "Removed abandoned scratch dirs");
+                }
+                let removed = root.remove_stale_waiter_sockets()?;
+                if 0 < removed {
+                    info!(target: LOG_TARGET, removed, "Removed stale waiter sockets");
+                }
+                trashed.extend(root.stale_trashed_dirs()?);
+
+                data.stats.last_gc = Some(GcRun {
+                    finished: Utc::now(),
+                    duration_ms: u64::try_from(gc_start.elapsed().as_millis()).unwrap_or(u64::MAX),
+                });
+                root.store_data(&data)?;
+
+                Ok(trashed)
+            })?;
+            for path in trashed {
+                root::remove_trashed_dir(&path)?;
+            }
+
+            if let Some(url) = gc_options.remote.as_deref() {
+                for key in remote::gc_unused(&*remote::open(url)?, deadline)? {
+                    println!("{}/{key}", url.trim_end_matches('/'));
+                }
+            }
+            Ok(())
         }
     }
 }
 
+/// Returns `None` if the keys are busy and `on_busy` says not to wait
 fn lock(
-    lock_opts: Option<LockOpts>,
-    common_opts: CommonLockOpts,
-    socket_path: Option<PathBuf>,
-) -> Result<PathBuf> {
-    let mut root = Root::new(&common_opts.root)?;
-
-    let key = format!("{}-{}", common_opts.key_name, get_cache_key(&common_opts)?);
-    root.with_lock(|root| {
-        root.lock_key(
-            &key,
-            &lock_opts
-                .as_ref()
-                .map(|o| o.lock_id.clone())
-                .unwrap_or_else(|| format!("exec-{}", std::process::id())),
-            lock_opts.map(|o| o.timeout_secs).unwrap_or_default(),
-            socket_path,
+    common_opts: &CommonLockOpts,
+    req: LockRequest,
+    on_busy: OnBusy,
+) -> Result<Option<LockedDirs>> {
+    let root = Root::new(&common_opts.root)?;
+
+    let keys = common_opts.keys()?;
+    let slot_keys = |slot| -> Vec<String> { keys.iter().map(|key| slot_key(key, slot)).collect() };
+    let try_lock_slot = |slot| {
+        let keys = slot_keys(slot);
+        root.try_lock_keys(
+            &keys.iter().map(String::as_str).collect::<Vec<_>>(),
+            req.clone(),
         )
-    })
+    };
+    let dirs = match on_busy {
+        OnBusy::Wait => Some(root.lock_keys(
+            &keys.iter().map(String::as_str).collect::<Vec<_>>(),
+            req.clone(),
+        )?),
+        OnBusy::Skip | OnBusy::Clone => try_lock_slot(0)?,
+        OnBusy::Fresh => {
+            let mut dirs = None;
+            for slot in 0..MAX_SLOTS {
+                dirs = try_lock_slot(slot)?;
+                if dirs.is_some() {
+                    if 0 < slot {
+                        info!(target: LOG_TARGET, slot, "Keys busy, using an additional slot");
+                    }
+                    break;
+                }
+            }
+            match dirs {
+                Some(dirs) => Some(dirs),
+                None => {
+                    info!(target: LOG_TARGET, "All slots busy, waiting");
+                    Some(root.lock_keys(
+                        &keys.iter().map(String::as_str).collect::<Vec<_>>(),
+                        req.clone(),
+                    )?)
+                }
+            }
+        }
+    };
+    Ok(dirs.map(|dirs| LockedDirs::new(common_opts, dirs)))
+}
+
+/// Number of slots of the keys `exec --on-busy fresh` can use, including the
+/// keys themselves
+const MAX_SLOTS: u32 = 8;
+
+fn exec_lock_id() -> String {
+    format!("exec-{}", std::process::id())
+}
+
+fn renew(renew_opts: RenewOpts) -> Result<()> {
+    let (root_dir, key) = split_key_dir_path(&renew_opts.dir)?;
+    let root = Root::new(root_dir)?;
+
+    let Some(every_secs) = renew_opts.every_secs else {
+        return root.renew_key(&key, &renew_opts.lock_id, renew_opts.timeout_secs);
+    };
+    loop {
+        std::thread::sleep(Duration::from_secs_f64(every_secs));
+        if let Some(owner_pid) = renew_opts.owner_pid {
+            if !util::is_process_alive(owner_pid) {
+                info!(
+                    target: LOG_TARGET,
+                    owner_pid, "Lock owner exited, stopping renewal"
+                );
+                return Ok(());
+            }
+        }
+        if let Err(err) = root.renew_key(&key, &renew_opts.lock_id, renew_opts.timeout_secs) {
+            info!(
+                target: LOG_TARGET,
+                %err, "Lock no longer held, stopping renewal"
+            );
+            return Ok(());
+        }
+    }
+}
+
+/// Run `renew` in a detached background process
+fn spawn_renew(renew_opts: RenewOpts) -> Result<()> {
+    let mut cmd = process::Command::new(std::env::current_exe()?);
+    cmd.arg("renew")
+        .arg("--dir")
+        .arg(&renew_opts.dir)
+        .args(["--lock-id", &renew_opts.lock_id])
+        .args(["--timeout-secs", &renew_opts.timeout_secs.to_string()]);
+    if let Some(every_secs) = renew_opts.every_secs {
+        cmd.args(["--every-secs", &every_secs.to_string()]);
+    }
+    if let Some(owner_pid) = renew_opts.owner_pid {
+        cmd.args(["--owner-pid", &owner_pid.to_string()]);
+    }
+    // don't keep the caller's pipes open, e.g. when capturing `lock` output
+    cmd.stdin(process::Stdio::null())
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .spawn()
+        .context("Failed to start lock renewal process")?;
+    Ok(())
 }
 
 fn unlock(unlock_opts: UnlockOpts) -> Result<()> {
-    let (root_dir, key) = split_key_dir_path(&unlock_opts.dir)?;
-    let mut root = Root::new(root_dir)?;
+    let (root_dir, _key) = split_key_dir_path(&unlock_opts.dir)?;
+    let over_quota = match unlock_opts.quota.resolve(&root_dir)? {
+        Some((max_bytes, action)) => {
+            enforce_quota(&unlock_opts.dir, &unlock_opts.lock_id, max_bytes, action)?
+        }
+        None => false,
+    };
+    let manifest = if unlock_opts.manifest && !over_quota {
+        ManifestUpdate::Set(
+            manifest::build(&unlock_opts.dir)
+                .with_context(|| format!("Failed to hash {}", unlock_opts.dir.display()))?,
+        )
+    } else {
+        ManifestUpdate::Clear
+    };
+    unlock_with(&unlock_opts.dir, &unlock_opts.lock_id, manifest)
+}
+
+fn unlock_with(dir: &Path, lock_id: &str, manifest: ManifestUpdate) -> Result<()> {
+    let (root_dir, key) = split_key_dir_path(dir)?;
+    let root = Root::new(root_dir)?;
+
+    root.unlock_key(&key, lock_id.to_owned(), manifest)?;
+    spawn_measure(dir);
+    Ok(())
+}
+
+/// [`Root::enforce_quota`] on the key `dir`
+fn enforce_quota(dir: &Path, lock_id: &str, max_bytes: u64, action: OverQuota) -> Result<bool> {
+    let (root_dir, key) = split_key_dir_path(dir)?;
+    Root::new(root_dir)?.enforce_quota(&key, lock_id, max_bytes, action)
+}
+
+/// Record the size of the key `dir` in the background, not to hold up the
+/// release
+fn spawn_measure(dir: &Path) {
+    let res = std::env::current_exe().and_then(|exe| {
+        process::Command::new(exe)
+            .args(["du", "--refresh", "--dir"])
+            .arg(dir)
+            .stdin(process::Stdio::null())
+            .stdout(process::Stdio::null())
+            .stderr(process::Stdio::null())
+            .spawn()
+    });
+    if let Err(err) = res {
+        warn!(%err, dir = %dir.display(), "Failed to start measuring the dir");
+    }
+}
+
+/// Manifest of `dir` to record on release, if it can be built
+fn build_manifest(dir: &Path) -> ManifestUpdate {
+    match manifest::build(dir) {
+        Ok(manifest) => ManifestUpdate::Set(manifest),
+        Err(err) => {
+            warn!(%err, dir = %dir.display(), "Failed to build content manifest");
+            ManifestUpdate::Clear
+        }
+    }
+}
+
+fn status(status_opts: StatusOpts) -> Result<()> {
+    let (root_dir, key) = status_opts.key.root_and_key()?;
+    let root = Root::new(&root_dir)?;
+
+    // checked first, as locking would leave a lock file behind
+    let key_data = if root.has_key(&key)? {
+        root.with_key_lock(&key, |locked_key| locked_key.load())?
+    } else {
+        None
+    };
+    let Some(key_data) = key_data else {
+        println!("key: {key}");
+        println!("state: never locked");
+        return Ok(());
+    };
+
+    let now = Utc::now();
+    let liveness = match key_data.socket_path.as_ref() {
+        Some(_) if key_data.is_held_remotely() => "unknown (holder on another host)",
+        Some(sock_path) if try_lock(sock_path).is_ok() => "alive",
+        Some(_) => "gone",
+        None => match key_data.owner.as_ref() {
+            Some(owner) if owner.is_gone() => "gone",
+            Some(owner) if !owner.is_local() => "unknown (owner on another host)",
+            Some(_) => "alive",
+            None if key_data.is_timelocked(now) => "unknown (no liveness socket)",
+            None => "n/a",
+        },
+    };
+    let state = if liveness == "alive" || (key_data.is_timelocked(now) && liveness != "gone") {
+        "locked"
+    } else {
+        "unlocked"
+    };
+
+    println!("key: {key}");
+    println!("dir: {}", root_dir.join(&key).display());
+    println!("state: {state}");
+    println!("lock_id: {}", key_data.lock_id);
+    println!("locked_at: {}", key_data.locked_at());
+    println!("last_used: {}", key_data.last_lock);
+    println!("locked_until: {}", key_data.locked_until);
+    println!(
+        "timeout_secs: {}",
+        key_data
+            .locked_until
+            .signed_duration_since(key_data.locked_at())
+            .num_seconds()
+    );
+    println!(
+        "socket: {}",
+        key_data
+            .socket_path
+            .as_ref()
+            .map(|p| p.display().to_string())
+            .unwrap_or_else(|| "none".into())
+    );
+    if let Some(owner) = key_data.owner.as_ref() {
+        println!("owner_pid: {}", owner.pid);
+    }
+    println!("liveness: {liveness}");
+    if let Some(holder) = key_data.holder.as_ref() {
+        println!("holder_pid: {}", holder.pid);
+        println!(
+            "holder_hostname: {}",
+            holder.hostname.as_deref().unwrap_or("unknown")
+        );
+        println!("holder_cmdline: {}", holder.cmdline.join(" "));
+    }
+    println!("waiters: {}", key_data.queue.len());
+    if let Some(published) = key_data.published {
+        println!("published: {published}");
+        println!(
+            "readers: {}",
+            key_data
+                .readers
+                .iter()
+                .filter(|reader| reader.is_alive(now))
+                .count()
+        );
+    }
+    if let Some(size) = key_data.size {
+        println!(
+            "size: {} bytes, {} allocated ({}{})",
+            size.usage.apparent_bytes,
+            size.usage.allocated_bytes,
+            size.measured_at,
+            if key_data.is_size_stale() {
+                ", stale"
+            } else {
+                ""
+            }
+        );
+    }
+    if let Some(recorded_at) = root.manifest_recorded_at(&key)? {
+        println!("manifest: {recorded_at}");
+    }
+    if let Some(failure) = key_data.last_failure {
+        println!(
+            "last_failure: {} at {} (lock_id: {})",
+            failure.action, failure.time, failure.lock_id
+        );
+        if let Some(path) = failure.quarantine_path {
+            println!("quarantined_to: {}", path.display());
+        }
+    }
+
+    Ok(())
+}
+
+fn verify(verify_opts: VerifyOpts) -> Result<()> {
+    let (root_dir, keys) = if verify_opts.all {
+        let root_dir = verify_opts
+            .key
+            .root
+            .ok_or_else(|| format_err!("`--all` needs `--root`"))?;
+        let keys = Root::new(&root_dir)?.with_lock(|root| root.keys())?;
+        (root_dir, keys)
+    } else {
+        let (root_dir, key) = verify_opts.key.root_and_key()?;
+        (root_dir, vec![key])
+    };
+
+    let mut corrupted = 0;
+    for key in &keys {
+        if !verify_key(&root_dir, key, verify_opts.repair)? {
+            corrupted += 1;
+        }
+    }
+    if 0 < corrupted {
+        bail!("{corrupted} corrupted key(s)");
+    }
+    Ok(())
+}
+
+/// Returns `false` if the key is left corrupted
+fn verify_key(root_dir: &Path, key: &str, repair: Option<Repair>) -> Result<bool> {
+    // published dirs are checked too, they are not modified by lock holders
+    let Some(held) = HeldKeys::lock(
+        root_dir,
+        &[key],
+        HoldOpts {
+            command: "verify",
+            publish: true,
+            maintenance: true,
+            wait: false,
+        },
+    )?
+    else {
+        println!("{key}: busy");
+        return Ok(true);
+    };
+    let mut manifest_update = ManifestUpdate::Keep;
+    let res = (|| -> Result<bool> {
+        let Some(manifest) = held.root.load_manifest(key)? else {
+            println!("{key}: no manifest");
+            return Ok(true);
+        };
+        let diff = manifest::diff(&held.dirs[0], &manifest)
+            .with_context(|| format!("Failed to hash {}", held.dirs[0].display()))?;
+        if diff.is_empty() {
+            println!("{key}: ok");
+            return Ok(true);
+        }
+        println!(
+            "{key}: corrupted ({} modified, {} missing, {} extra)",
+            diff.modified.len(),
+            diff.missing.len(),
+            diff.extra.len()
+        );
+        for (kind, paths) in [
+            ("modified", &diff.modified),
+            ("missing", &diff.missing),
+            ("extra", &diff.extra),
+        ] {
+            for path in paths {
+                println!("  {kind}: {path}");
+            }
+        }
+        let discarded = match repair {
+            Some(Repair::Discard) => held.root.discard_dir(key, &held.lock_id)?,
+            None => false,
+        };
+        held.root.record(journal::Entry::new(
+            key,
+            Some(&held.lock_id),
+            journal::Event::Corrupted {
+                modified: diff.modified.len(),
+                missing: diff.missing.len(),
+                extra: diff.extra.len(),
+                discarded,
+            },
+        ));
+        match (repair, discarded) {
+            (_, true) => {
+                println!("{key}: discarded");
+                manifest_update = ManifestUpdate::Clear;
+            }
+            (Some(_), false) => println!("{key}: in use, not discarded"),
+            (None, false) => {}
+        }
+        Ok(discarded)
+    })();
+    held.release(|_| manifest_update.clone())?;
+    res
+}
+
+fn dedup(dedup_opts: DedupOpts) -> Result<()> {
+    let root_dir = &dedup_opts.root;
+    let keys = Root::new(root_dir)?.with_lock(|root| root.keys())?;
+
+    // all held together, so files can be shared between any of them
+    let mut held = vec![];
+    for key in &keys {
+        match HeldKeys::lock(
+            root_dir,
+            &[key],
+            HoldOpts {
+                command: "dedup",
+                publish: true,
+                maintenance: true,
+                wait: false,
+            },
+        )? {
+            Some(held_key) => held.push(held_key),
+            None => debug!(target: LOG_TARGET, key, "Busy, skipping"),
+        }
+    }
+    let dirs: Vec<_> = held
+        .iter()
+        .map(|held_key| held_key.dirs[0].clone())
+        .filter(|dir| dir.is_dir())
+        .collect();
+    let res = dedup::dedup_dirs(&dirs).context("Failed to deduplicate");
+    let deduped: Vec<_> = held
+        .iter()
+        .flat_map(|held_key| held_key.keys.clone())
+        .collect();
+    // only the inodes change, so the manifests stay valid
+    let mut release_res = Ok(());
+    for held_key in held {
+        // unlock as many as possible
+        if let Err(err) = held_key.release(|_| ManifestUpdate::Keep) {
+            release_res = Err(err);
+        }
+    }
+    let stats = res?;
+    release_res?;
+    // now sharing files, for the totals
+    let root = Root::new(root_dir)?;
+    for key in &deduped {
+        if !root.measure_dir_fully(key)? {
+            debug!(target: LOG_TARGET, key, "Locked, not measured");
+        }
+    }
+    info!(
+        target: LOG_TARGET,
+        keys = dirs.len(),
+        reflinked = stats.reflinked,
+        hardlinked = stats.hardlinked,
+        "Deduplicated"
+    );
+
+    println!("{}", stats.bytes_saved);
+    Ok(())
+}
+
+/// Keys locked for as long as this process runs, by commands working on the
+/// key dirs themselves
+struct HeldKeys {
+    root: Root,
+    keys: Vec<String>,
+    dirs: Vec<PathBuf>,
+    lock_id: String,
+    sock_path: PathBuf,
+    _liveness: root::LivenessLock,
+}
 
-    root.with_lock(|root| root.unlock_key(&key, unlock_opts.lock_id))
+/// How [`HeldKeys::lock`] locks the keys
+struct HoldOpts<'a> {
+    /// Name of the command, part of the lock id
+    command: &'a str,
+    /// Published keys can be locked too, see [`LockRequest::publish`]
+    publish: bool,
+    /// The lock doesn't count as a use of the keys, see
+    /// [`LockRequest::maintenance`]
+    maintenance: bool,
+    /// Wait for busy keys instead of returning `None`
+    wait: bool,
+}
+
+impl HeldKeys {
+    /// Returns `None` if any of the keys is busy, and not [`HoldOpts::wait`]ing
+    fn lock(root_dir: &Path, keys: &[&str], opts: HoldOpts) -> Result<Option<Self>> {
+        let HoldOpts {
+            command,
+            publish,
+            maintenance,
+            wait,
+        } = opts;
+        let root = Root::new(root_dir)?;
+        let sock_path = root_dir.join(format!(
+            "lock-{}",
+            Alphanumeric.sample_string(&mut rand::thread_rng(), 16)
+        ));
+        let liveness = mk_lock(&sock_path)?;
+        let lock_id = format!("{command}-{}", process::id());
+        // see `run_exec`
+        let use_lease = root::load_config(root_dir)?.locking == LockingMode::Lockfile;
+        let req = LockRequest {
+            lock_id: lock_id.clone(),
+            timeout_secs: if use_lease { EXEC_LEASE_SECS } else { 0.0 },
+            socket_path: Some(sock_path.clone()),
+            owner: None,
+            holder: HolderInfo::new(process::id(), std::env::args().collect()),
+            priority: 0,
+            publish,
+            maintenance,
+        };
+        let dirs = if wait {
+            Some(root.lock_keys(keys, req)?)
+        } else {
+            root.try_lock_keys(keys, req)?
+        };
+        let Some(dirs) = dirs else {
+            remove_liveness_socket(&sock_path);
+            return Ok(None);
+        };
+        if use_lease {
+            for dir in &dirs {
+                spawn_renew(RenewOpts {
+                    dir: dir.clone(),
+                    lock_id: lock_id.clone(),
+                    timeout_secs: EXEC_LEASE_SECS,
+                    every_secs: Some(EXEC_LEASE_SECS / 3.0),
+                    owner_pid: Some(process::id()),
+                })?;
+            }
+        }
+        Ok(Some(Self {
+            root,
+            keys: keys.iter().map(|key| (*key).to_owned()).collect(),
+            dirs,
+            lock_id,
+            sock_path,
+            _liveness: liveness,
+        }))
+    }
+
+    /// Unlock all the keys, updating their manifests as `manifest` says
+    fn release(self, manifest: impl Fn(&str) -> ManifestUpdate) -> Result<()> {
+        let mut res = Ok(());
+        for (key, dir) in self.keys.iter().zip(&self.dirs) {
+            let manifest = manifest(key);
+            // kept manifests mean the dirs were not modified
+            let modified = !matches!(manifest, ManifestUpdate::Keep);
+            // unlock as many as possible
+            match self.root.unlock_key(key, self.lock_id.clone(), manifest) {
+                Ok(()) if modified => spawn_measure(dir),
+                Ok(()) => {}
+                Err(err) => {
+                    error!(%err, key, "Failed to unlock");
+                    res = Err(err);
+                }
+            }
+        }
+        remove_liveness_socket(&self.sock_path);
+        res
+    }
+}
+
+fn remove_liveness_socket(sock_path: &Path) {
+    if let Err(err) = fs::remove_file(sock_path) {
+        warn!(%err, sock_path=%sock_path.display(), "Error removing liveness socket")
+    }
+}
+
+fn clone_key(clone_opts: CloneOpts) -> Result<()> {
+    let root_dir = fs::canonicalize(&clone_opts.root)?;
+    let (from_root, from_key) = split_key_dir_path(&clone_opts.from)?;
+    if fs::canonicalize(&from_root)? != root_dir {
+        bail!("`--from` must be a key dir in `--root`");
+    }
+    let to_key = get_key(
+        &clone_opts.key_name,
+        &clone_opts.key_str,
+        &clone_opts.key_file,
+    )?;
+
+    // only the source can be published, checked below
+    let held = HeldKeys::lock(
+        &root_dir,
+        &[&from_key, &to_key],
+        HoldOpts {
+            command: "clone",
+            publish: true,
+            maintenance: true,
+            wait: true,
+        },
+    )?
+    .expect("waited for the lock");
+    let (root, from_dir, to_dir) = (&held.root, &held.dirs[0], &held.dirs[1]);
+
+    let res = (|| -> Result<()> {
+        if root.is_published(&to_key)? {
+            bail!("Key {to_key} was published and is immutable");
+        }
+        if !from_dir.is_dir() {
+            bail!("Key dir {} does not exist", from_dir.display());
+        }
+        if to_dir.exists() && fs::read_dir(to_dir)?.next().is_some() {
+            bail!("Key dir {} already exists", to_dir.display());
+        }
+        let staging_dir = root.create_staging_dir(&to_key)?;
+        // shared files must not be modifiable through either dir
+        let opts = clone::Options {
+            hardlink: clone_opts.publish && root.is_published(&from_key)?,
+        };
+        let stats = clone::clone_dir(from_dir, &staging_dir, opts)
+            .with_context(|| format!("Failed to clone {}", from_dir.display()))?;
+        info!(
+            target: LOG_TARGET,
+            reflinked = stats.reflinked,
+            hardlinked = stats.hardlinked,
+            copied = stats.copied,
+            "Cloned"
+        );
+        if clone_opts.publish {
+            root.publish(&to_key, &held.lock_id, &staging_dir)
+        } else {
+            root.install_staging_dir(&to_key, &staging_dir)
+        }
+    })();
+    let to_dir = to_dir.clone();
+    held.release(|key| {
+        if key == to_key {
+            ManifestUpdate::Clear
+        } else {
+            ManifestUpdate::Keep
+        }
+    })?;
+    res?;
+
+    println!("{}", to_dir.display());
+    Ok(())
+}
+
+fn export(export_opts: ExportOpts) -> Result<()> {
+    let (root_dir, key) = split_key_dir_path(&export_opts.dir)?;
+    let held = HeldKeys::lock(
+        &root_dir,
+        &[&key],
+        HoldOpts {
+            command: "export",
+            publish: true,
+            maintenance: true,
+            wait: true,
+        },
+    )?
+    .expect("waited for the lock");
+
+    let res = (|| -> Result<()> {
+        let dir = &held.dirs[0];
+        if !dir.is_dir() {
+            bail!("Key dir {} does not exist", dir.display());
+        }
+        let header = archive::Header::new(&key);
+        match export_opts.output.as_ref() {
+            Some(output) => util::store_to_file_with(output, |file| {
+                archive::export(dir, &header, file).map(|_manifest| ())
+            })??,
+            None => {
+                archive::export(dir, &header, io::stdout().lock())?;
+            }
+        }
+        Ok(())
+    })();
+    held.release(|_| ManifestUpdate::Keep)?;
+    res
+}
+
+fn import(import_opts: ImportOpts) -> Result<()> {
+    let input: Box<dyn io::Read> = match import_opts.input.as_ref() {
+        Some(input) => Box::new(
+            fs::File::open(input).with_context(|| format!("Failed to open {}", input.display()))?,
+        ),
+        None => Box::new(io::stdin().lock()),
+    };
+    let root_dir = fs::canonicalize(&import_opts.root)?;
+
+    let mut held: Option<(HeldKeys, PathBuf)> = None;
+    let res = archive::import(io::BufReader::new(input), |header| {
+        if !is_key(&header.key) {
+            bail!("Invalid key in the archive: {}", header.key);
+        }
+        let keys = HeldKeys::lock(
+            &root_dir,
+            &[&header.key],
+            HoldOpts {
+                command: "import",
+                publish: false,
+                maintenance: false,
+                wait: false,
+            },
+        )?
+        .ok_or_else(|| format_err!("Key {} is locked", header.key))?;
+        let staging_dir = keys.root.create_staging_dir(&header.key)?;
+        let (_, staging_dir) = held.insert((keys, staging_dir));
+        Ok(staging_dir.clone())
+    });
+    let Some((held, staging_dir)) = held else {
+        return res.map(|_| ());
+    };
+    let res = res.and_then(|header| {
+        held.root.install_staging_dir(&header.key, &staging_dir)?;
+        info!(
+            target: LOG_TARGET,
+            key = header.key,
+            exported_at = %header.exported_at,
+            "Imported"
+        );
+        println!("{}", held.dirs[0].display());
+        Ok(())
+    });
+    let installed = res.is_ok();
+    if !installed {
+        if let Err(err) = fs::remove_dir_all(&staging_dir) {
+            warn!(%err, staging_dir = %staging_dir.display(), "Error removing staging dir");
+        }
+    }
+    held.release(|_| {
+        if installed {
+            ManifestUpdate::Clear
+        } else {
+            ManifestUpdate::Keep
+        }
+    })?;
+    res
+}
+
+fn config(config_opts: ConfigOpts) -> Result<()> {
+    let mut root = Root::new(&config_opts.root)?;
+    let mut config = root::load_config(&config_opts.root)?;
+
+    if config_opts.metadata_store.is_some()
+        || config_opts.locking.is_some()
+        || config_opts.max_key_bytes.is_some()
+        || config_opts.over_quota.is_some()
+    {
+        config.metadata_store = config_opts.metadata_store.unwrap_or(config.metadata_store);
+        config.locking = config_opts.locking.unwrap_or(config.locking);
+        if let Some(max_key_bytes) = config_opts.max_key_bytes {
+            config.max_key_bytes = (max_key_bytes != 0).then_some(max_key_bytes);
+        }
+        config.over_quota = config_opts.over_quota.unwrap_or(config.over_quota);
+        root.set_config(&config)?;
+    }
+
+    println!("{}", serde_json::to_string_pretty(&config)?);
+    Ok(())
+}
+
+fn stats(stats_opts: StatsOpts) -> Result<()> {
+    let mut root = Root::new(&stats_opts.root)?;
+
+    let (key_name_stats, key_stats) = root.with_lock(|root| {
+        let mut data = root.load_data()?;
+        let keys = root.load_keys()?;
+        let key_name_stats = data.stats.key_name_totals(&keys);
+        let key_stats = keys
+            .into_iter()
+            .map(|(key, key_data)| (key, key_data.stats))
+            .collect::<BTreeMap<_, _>>();
+
+        if stats_opts.reset {
+            data.stats = Default::default();
+            root.store_data(&data)?;
+            for key in key_stats.keys() {
+                root.with_key_lock(key, |locked_key| {
+                    if let Some(mut key_data) = locked_key.load()? {
+                        key_data.stats = Default::default();
+                        locked_key.store(&key_data)?;
+                    }
+                    Ok(())
+                })?;
+            }
+        }
+        Ok((key_name_stats, key_stats))
+    })?;
+
+    print_usage_stats("KEY_NAME", &key_name_stats);
+    if stats_opts.keys {
+        println!();
+        print_usage_stats("KEY", &key_stats);
+    }
+
+    Ok(())
+}
+
+fn du(du_opts: DuOpts) -> Result<()> {
+    let (root_dir, only_key) = match (du_opts.dir, du_opts.root) {
+        (Some(dir), _) => {
+            let (root_dir, key) = split_key_dir_path(&dir)?;
+            (root_dir, Some(key))
+        }
+        (None, Some(root_dir)) => (root_dir, None),
+        (None, None) => bail!("Either `--dir` or `--root` must be given"),
+    };
+    let mut root = Root::new(&root_dir)?;
+    let mut load_keys = || -> Result<BTreeMap<String, root::dto::KeyData>> {
+        let Some(only_key) = only_key.as_ref() else {
+            return root.with_lock(|root| root.load_keys());
+        };
+        // just the one key, not to contend with everything else on the root
+        Ok(root
+            .with_key_lock(only_key, |locked_key| locked_key.load())?
+            .map(|key_data| (only_key.clone(), key_data))
+            .into_iter()
+            .collect())
+    };
+
+    let mut keys = load_keys()?;
+    if du_opts.refresh {
+        let stale: Vec<_> = keys
+            .iter()
+            .filter(|(_, key_data)| key_data.is_size_stale())
+            .map(|(key, _)| key.clone())
+            .collect();
+        if !stale.is_empty() {
+            let root = Root::new(&root_dir)?;
+            for key in &stale {
+                if !root.measure_dir(key)? {
+                    debug!(target: LOG_TARGET, key, "Locked, not measured");
+                }
+            }
+            keys = load_keys()?;
+        }
+    }
+
+    let mut by_key_name: BTreeMap<&str, BTreeMap<&String, &root::dto::KeyData>> = BTreeMap::new();
+    for (key, key_data) in &keys {
+        by_key_name
+            .entry(root::dto::key_name_of(key))
+            .or_default()
+            .insert(key, key_data);
+    }
+    // files shared by keys of the same name (see `dedup`) counted once
+    let key_name_usage: BTreeMap<_, _> = by_key_name
+        .into_iter()
+        .map(|(key_name, keys)| (key_name, (keys.len(), root.total_usage(keys))))
+        .collect();
+
+    let width = key_name_usage
+        .keys()
+        .map(|key_name| key_name.len())
+        .chain(["KEY_NAME".len()])
+        .max()
+        .unwrap_or_default();
+    println!(
+        "{:width$}  {:>6}  {:>15}  {:>15}",
+        "KEY_NAME", "KEYS", "APPARENT_BYTES", "ALLOCATED_BYTES"
+    );
+    for (key_name, (count, usage)) in &key_name_usage {
+        println!(
+            "{key_name:width$}  {count:>6}  {:>15}  {:>15}",
+            usage.apparent_bytes, usage.allocated_bytes
+        );
+    }
+
+    println!();
+    let width = keys
+        .keys()
+        .map(String::len)
+        .chain(["KEY".len()])
+        .max()
+        .unwrap_or_default();
+    println!(
+        "{:width$}  {:>15}  {:>15}  MEASURED_AT",
+        "KEY", "APPARENT_BYTES", "ALLOCATED_BYTES"
+    );
+    for (key, key_data) in &keys {
+        let Some(size) = key_data.size else {
+            println!("{key:width$}  {:>15}  {:>15}  -", "-", "-");
+            continue;
+        };
+        println!(
+            "{key:width$}  {:>15}  {:>15}  {}{}",
+            size.usage.apparent_bytes,
+            size.usage.allocated_bytes,
+            size.measured_at
+                .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            if key_data.is_size_stale() {
+                " (stale)"
+            } else {
+                ""
+            }
+        );
+    }
+
+    Ok(())
+}
+
+fn print_usage_stats(header: &str, stats: &BTreeMap<String, UsageStats>) {
+    let width = stats
+        .keys()
+        .map(String::len)
+        .chain([header.len()])
+        .max()
+        .unwrap_or_default();
+    println!(
+        "{header:width$}  {:>12}  {:>8}  {:>8}  {:>8}  {:>15}  {:>13}  {:>15}",
+        "ACQUISITIONS",
+        "HITS",
+        "MISSES",
+        "HIT_RATE",
+        "WAIT_TOTAL_SECS",
+        "WAIT_MAX_SECS",
+        "HELD_TOTAL_SECS"
+    );
+    for (name, s) in stats {
+        println!(
+            "{name:width$}  {:>12}  {:>8}  {:>8}  {:>7.1}%  {:>15.1}  {:>13.1}  {:>15.1}",
+            s.acquisitions,
+            s.hits,
+            s.misses,
+            if s.acquisitions == 0 {
+                0.0
+            } else {
+                s.hits as f64 * 100.0 / s.acquisitions as f64
+            },
+            s.wait_total_ms as f64 / 1000.0,
+            s.wait_max_ms as f64 / 1000.0,
+            s.held_total_ms as f64 / 1000.0,
+        );
+    }
+}
+
+fn log(log_opts: LogOpts) -> Result<()> {
+    let path = root::journal_file_path(&log_opts.root);
+
+    let entries = journal::read_all(&path)?;
+    let matching: Vec<_> = entries.iter().filter(|e| log_opts.matches(e)).collect();
+    for entry in &matching[matching.len().saturating_sub(log_opts.lines)..] {
+        println!("{}", serde_json::to_string(entry)?);
+    }
+
+    if log_opts.follow {
+        journal::follow(&path, |entry| {
+            if log_opts.matches(&entry) {
+                if let Ok(line) = serde_json::to_string(&entry) {
+                    println!("{line}");
+                }
+            }
+        })?;
+    }
+
+    Ok(())
 }
 
 fn split_key_dir_path(dir: &Path) -> Result<(PathBuf, String)> {
@@ -291,13 +2184,28 @@ fn split_key_dir_path(dir: &Path) -> Result<(PathBuf, String)> {
     Ok((parent, key))
 }
 
-fn get_cache_key(common_lock_opts: &CommonLockOpts) -> Result<String, anyhow::Error> {
+fn get_key(key_name: &str, key_str: &[String], key_file: &[PathBuf]) -> Result<String> {
+    if !is_key_name(key_name) {
+        bail!("Invalid key name: {key_name}");
+    }
+    Ok(format!(
+        "{}-{}",
+        key_name,
+        get_cache_key(key_name, key_str, key_file)?
+    ))
+}
+
+fn get_cache_key(
+    key_name: &str,
+    key_strs: &[String],
+    key_files: &[PathBuf],
+) -> Result<String, anyhow::Error> {
     let mut hasher = blake3::Hasher::new();
-    hasher.update(common_lock_opts.key_name.as_bytes());
-    for key_str in &common_lock_opts.key_str {
+    hasher.update(key_name.as_bytes());
+    for key_str in key_strs {
         hasher.update(key_str.as_bytes());
     }
-    for key_file in &common_lock_opts.key_file {
+    for key_file in key_files {
         let mut reader = fs::File::open(key_file)
             .with_context(|| format!("Failed to open {}", key_file.display()))?;
         io::copy(&mut reader, &mut hasher)
@@ -0,0 +1,121 @@
+//! Content manifests of key dirs, for detecting corruption
+//!
+//! Every file is hashed with blake3, and every dir hashes the names, types
+//! and hashes of its entries, so the whole dir has a single Merkle root hash.
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use chrono::Utc;
+
+use crate::root::dto::{DirManifest, ManifestEntry};
+
+/// Hash everything in `dir`
+pub fn build(dir: &Path) -> io::Result<DirManifest> {
+    let mut entries = BTreeMap::new();
+    let root_hash = hash_dir(dir, "", &mut entries)?;
+    Ok(DirManifest {
+        recorded_at: Utc::now(),
+        root_hash: root_hash.to_hex().to_string(),
+        entries,
+    })
+}
+
+fn hash_dir(
+    dir: &Path,
+    rel_path: &str,
+    entries: &mut BTreeMap<String, ManifestEntry>,
+) -> io::Result<blake3::Hash> {
+    let mut dir_entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    dir_entries.sort_by_key(|entry| entry.file_name());
+    let mut hasher = blake3::Hasher::new();
+    for entry in dir_entries {
+        let file_name = entry.file_name();
+        let Some(name) = file_name.to_str() else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Path is not valid UTF-8: {}", entry.path().display()),
+            ));
+        };
+        let entry_path = if rel_path.is_empty() {
+            name.to_owned()
+        } else {
+            format!("{rel_path}/{name}")
+        };
+        let file_type = entry.file_type()?;
+        let (kind, hash, manifest_entry) = if file_type.is_dir() {
+            let hash = hash_dir(&entry.path(), &entry_path, entries)?;
+            (b'd', hash, ManifestEntry::Dir)
+        } else if file_type.is_symlink() {
+            let target = fs::read_link(entry.path())?;
+            let target = target.to_string_lossy().into_owned();
+            let hash = blake3::hash(target.as_bytes());
+            (b'l', hash, ManifestEntry::Symlink { target })
+        } else {
+            let mut hasher = blake3::Hasher::new();
+            let size = io::copy(&mut fs::File::open(entry.path())?, &mut hasher)?;
+            let hash = hasher.finalize();
+            (
+                b'f',
+                hash,
+                ManifestEntry::File {
+                    size,
+                    blake3: hash.to_hex().to_string(),
+                },
+            )
+        };
+        hasher.update(name.as_bytes());
+        hasher.update(&[0, kind]);
+        hasher.update(hash.as_bytes());
+        entries.insert(entry_path, manifest_entry);
+    }
+    Ok(hasher.finalize())
+}
+
+/// Paths in a dir that don't match its manifest
+#[derive(Debug, Default)]
+pub struct Diff {
+    pub modified: Vec<String>,
+    pub missing: Vec<String>,
+    pub extra: Vec<String>,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.modified.is_empty() && self.missing.is_empty() && self.extra.is_empty()
+    }
+}
+
+/// Rehash `dir` and compare it with `manifest`
+///
+/// A missing `dir` is reported as all of its entries missing.
+pub fn diff(dir: &Path, manifest: &DirManifest) -> io::Result<Diff> {
+    let current = match build(dir) {
+        Ok(current) => current,
+        Err(err) if err.kind() == io::ErrorKind::NotFound && !dir.exists() => DirManifest {
+            recorded_at: Utc::now(),
+            root_hash: String::new(),
+            entries: BTreeMap::new(),
+        },
+        Err(err) => return Err(err),
+    };
+    let mut diff = Diff::default();
+    if current.root_hash == manifest.root_hash {
+        return Ok(diff);
+    }
+    for (path, entry) in &manifest.entries {
+        match current.entries.get(path) {
+            None => diff.missing.push(path.clone()),
+            Some(current_entry) if current_entry != entry => diff.modified.push(path.clone()),
+            Some(_) => {}
+        }
+    }
+    diff.extra = current
+        .entries
+        .keys()
+        .filter(|path| !manifest.entries.contains_key(*path))
+        .cloned()
+        .collect();
+    Ok(diff)
+}
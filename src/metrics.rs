@@ -0,0 +1,161 @@
+//! Prometheus metrics in the [text exposition format], suitable for the
+//! node_exporter textfile collector
+//!
+//! [text exposition format]: https://prometheus.io/docs/instrumenting/exposition_formats/
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::path::Path;
+
+use anyhow::Result;
+use chrono::Utc;
+use tracing::{debug, warn};
+
+use crate::root::dto::{Histogram, KeyData, RootData, LOCK_WAIT_BUCKETS_SECS};
+use crate::root::Root;
+use crate::{util, LOG_TARGET};
+
+/// Render metrics of the cache at `root_path`
+///
+/// Sizes are the ones last measured after releases (see `du`), as walking
+/// all the dirs on every update takes too long. Files shared between keys
+/// (see `dedup`) are counted once.
+pub fn render(root_path: &Path) -> Result<String> {
+    let mut root = Root::new(root_path)?;
+    let (data, keys) = root.with_lock(|root| Ok((root.load_data()?, root.load_keys()?)))?;
+    let total_bytes = root.total_usage(&keys).apparent_bytes;
+
+    Ok(render_data(&data, &keys, total_bytes))
+}
+
+fn render_data(data: &RootData, keys: &BTreeMap<String, KeyData>, total_bytes: u64) -> String {
+    let now = Utc::now();
+    let locked_keys = keys.values().filter(|k| k.is_locked(now)).count();
+    let stats = &data.stats;
+    let key_name_totals = stats.key_name_totals(keys);
+
+    let mut out = String::new();
+    let mut gauge = |name: &str, help: &str, value: f64| {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} gauge");
+        let _ = writeln!(out, "{name} {value}");
+    };
+    gauge(
+        "fs_dir_cache_keys",
+        "Number of cache keys in the root",
+        keys.len() as f64,
+    );
+    gauge(
+        "fs_dir_cache_bytes",
+        "Total size of all cache key dirs, as last measured",
+        total_bytes as f64,
+    );
+    gauge(
+        "fs_dir_cache_locked_keys",
+        "Number of currently locked cache keys",
+        locked_keys as f64,
+    );
+    if let Some(last_gc) = stats.last_gc.as_ref() {
+        gauge(
+            "fs_dir_cache_gc_duration_seconds",
+            "Duration of the last GC run",
+            last_gc.duration_ms as f64 / 1000.0,
+        );
+        gauge(
+            "fs_dir_cache_gc_last_run_timestamp_seconds",
+            "Time the last GC run finished",
+            last_gc.finished.timestamp() as f64,
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP fs_dir_cache_evictions_total Number of cache keys deleted by GC"
+    );
+    let _ = writeln!(out, "# TYPE fs_dir_cache_evictions_total counter");
+    let _ = writeln!(out, "fs_dir_cache_evictions_total {}", stats.evictions);
+
+    for (name, help) in [
+        ("acquisitions", "Number of lock acquisitions"),
+        ("hits", "Number of acquisitions of an existing key"),
+        ("misses", "Number of acquisitions creating a fresh key"),
+    ] {
+        let _ = writeln!(out, "# HELP fs_dir_cache_{name}_total {help}");
+        let _ = writeln!(out, "# TYPE fs_dir_cache_{name}_total counter");
+        for (key_name, s) in &key_name_totals {
+            let value = match name {
+                "acquisitions" => s.acquisitions,
+                "hits" => s.hits,
+                _ => s.misses,
+            };
+            let _ = writeln!(
+                out,
+                "fs_dir_cache_{name}_total{{key_name=\"{}\"}} {value}",
+                escape_label(key_name)
+            );
+        }
+    }
+
+    let mut hist = Histogram::default();
+    for s in key_name_totals.values() {
+        hist.merge(&s.lock_wait);
+    }
+    let _ = writeln!(
+        out,
+        "# HELP fs_dir_cache_lock_wait_seconds Time spent waiting to acquire a key lock"
+    );
+    let _ = writeln!(out, "# TYPE fs_dir_cache_lock_wait_seconds histogram");
+    for (i, le) in LOCK_WAIT_BUCKETS_SECS.iter().enumerate() {
+        let _ = writeln!(
+            out,
+            "fs_dir_cache_lock_wait_seconds_bucket{{le=\"{le}\"}} {}",
+            hist.buckets.get(i).copied().unwrap_or_default()
+        );
+    }
+    let _ = writeln!(
+        out,
+        "fs_dir_cache_lock_wait_seconds_bucket{{le=\"+Inf\"}} {}",
+        hist.count
+    );
+    let _ = writeln!(
+        out,
+        "fs_dir_cache_lock_wait_seconds_sum {}",
+        hist.sum_ms as f64 / 1000.0
+    );
+    let _ = writeln!(out, "fs_dir_cache_lock_wait_seconds_count {}", hist.count);
+
+    out
+}
+
+fn escape_label(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Atomically (re)write the metrics of `root_path` into `textfile`
+pub fn write_textfile(root_path: &Path, textfile: &Path) -> Result<()> {
+    let metrics = render(root_path)?;
+    debug!(
+        target: LOG_TARGET,
+        textfile = %textfile.display(), "Writing metrics"
+    );
+    util::store_to_file_with(textfile, |f| f.write_all(metrics.as_bytes()))??;
+    Ok(())
+}
+
+/// Like [`write_textfile`], but only logs a warning on failure
+///
+/// Used for automatic updates, which should never fail the actual command.
+pub fn update_textfile(root_path: &Path, textfile: Option<&Path>) {
+    let Some(textfile) = textfile else {
+        return;
+    };
+    if let Err(err) = write_textfile(root_path, textfile) {
+        warn!(
+            target: LOG_TARGET,
+            %err,
+            textfile = %textfile.display(),
+            "Failed to update metrics textfile"
+        );
+    }
+}
@@ -0,0 +1,266 @@
+//! Remote second-level caches
+//!
+//! A key dir missing from the local root is downloaded from the remote (as an
+//! [`archive`](crate::archive)) before it's handed out, and uploaded back after
+//! `exec` succeeds in it, if it changed. Each key is stored as two objects:
+//! `<key>.tar.zst` and `<key>.manifest.json`, describing it. Other uses are
+//! recorded with a third one, `<key>.used`, so keys that are only downloaded
+//! aren't evicted while in use.
+//!
+//! Backend is selected by the scheme of the remote url.
+mod http;
+#[cfg(feature = "s3")]
+mod s3;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+use anyhow::{bail, Context as _, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tracing::{debug, info, warn};
+
+use crate::root::{journal, Root};
+use crate::{archive, LOG_TARGET};
+
+const ARCHIVE_SUFFIX: &str = ".tar.zst";
+const MANIFEST_SUFFIX: &str = ".manifest.json";
+const USED_SUFFIX: &str = ".used";
+
+pub trait RemoteBackend {
+    /// Object `name`, or `None` if the remote doesn't have it
+    fn get(&self, name: &str) -> Result<Option<Box<dyn Read + Send>>>;
+
+    /// Store the file at `path` as the object `name`
+    fn put(&self, name: &str, path: &Path) -> Result<()>;
+
+    /// Store `data` as the object `name`
+    fn put_bytes(&self, name: &str, data: &[u8]) -> Result<()>;
+
+    /// Delete the object `name`, if it exists
+    fn delete(&self, name: &str) -> Result<()>;
+
+    /// All the objects
+    fn list(&self) -> Result<Vec<RemoteObject>>;
+}
+
+#[derive(Debug, Clone)]
+pub struct RemoteObject {
+    pub name: String,
+    pub last_modified: DateTime<Utc>,
+}
+
+/// Open the remote at `url`, picking the backend by its scheme
+pub fn open(url: &str) -> Result<Box<dyn RemoteBackend>> {
+    Ok(match url.split_once("://") {
+        Some(("http" | "https", _)) => Box::new(http::HttpBackend::new(url)),
+        #[cfg(feature = "s3")]
+        Some(("s3", _)) => Box::new(s3::S3Backend::from_url(url)?),
+        #[cfg(not(feature = "s3"))]
+        Some(("s3", _)) => {
+            bail!("S3 remote cache is not supported by this build (`s3` feature disabled)")
+        }
+        _ => bail!("Unsupported remote cache url: {url}"),
+    })
+}
+
+/// Stored next to the archive, to tell what's in it without downloading it
+#[derive(Serialize)]
+struct RemoteManifest<'a> {
+    header: &'a archive::Header,
+    archive_size: u64,
+    #[serde(flatten)]
+    manifest: &'a archive::Manifest,
+}
+
+/// Stored as `<key>.used`, only its modification time matters
+#[derive(Serialize)]
+struct UsedMarker {
+    used_at: DateTime<Utc>,
+}
+
+/// Populate the (missing) dir of the locked `key` from the archive of
+/// `remote_key` in `remote`
+///
+/// Returns `false` if the remote doesn't have it.
+pub fn download(
+    remote: &dyn RemoteBackend,
+    root: &Root,
+    key: &str,
+    remote_key: &str,
+    lock_id: &str,
+) -> Result<bool> {
+    let staging_dir = root.create_staging_dir(key)?;
+    let res = fetch(remote, root, key, remote_key, lock_id, &staging_dir).and_then(|found| {
+        if found {
+            root.install_staging_dir(key, &staging_dir)?;
+        }
+        Ok(found)
+    });
+    if !matches!(res, Ok(true)) {
+        fs::remove_dir_all(&staging_dir).context("Failed to remove staging dir")?;
+    }
+    res
+}
+
+/// Unpack the archive of `remote_key` in `remote` into the empty `dir`, for
+/// the locked `key`
+///
+/// Returns `false` if the remote doesn't have it. On errors `dir` can be left
+/// partially populated.
+pub fn fetch(
+    remote: &dyn RemoteBackend,
+    root: &Root,
+    key: &str,
+    remote_key: &str,
+    lock_id: &str,
+    dir: &Path,
+) -> Result<bool> {
+    let Some(reader) = remote.get(&format!("{remote_key}{ARCHIVE_SUFFIX}"))? else {
+        debug!(target: LOG_TARGET, key, remote_key, "Not in remote cache");
+        return Ok(false);
+    };
+    archive::import(io::BufReader::new(reader), |header| {
+        if header.key != remote_key {
+            bail!(
+                "Remote archive is of a different key: {}, expected {}",
+                header.key,
+                remote_key
+            );
+        }
+        Ok(dir.to_owned())
+    })?;
+    info!(target: LOG_TARGET, key, remote_key, "Downloaded from remote cache");
+    // `upload` skips it unless modified, see `gc_unused`
+    record_use(remote, key, remote_key);
+    root.record(journal::Entry::new(
+        key,
+        Some(lock_id),
+        journal::Event::RemoteDownloaded,
+    ));
+    Ok(true)
+}
+
+/// Mark `remote_key` as used, without uploading it again
+fn record_use(remote: &dyn RemoteBackend, key: &str, remote_key: &str) {
+    let res = serde_json::to_vec(&UsedMarker {
+        used_at: Utc::now(),
+    })
+    .map_err(anyhow::Error::from)
+    .and_then(|used| remote.put_bytes(&format!("{remote_key}{USED_SUFFIX}"), &used));
+    if let Err(err) = res {
+        warn!(target: LOG_TARGET, %err, key, remote_key, "Failed to record the use in remote cache");
+    }
+}
+
+/// Content of `remote_key` in `remote`, as described by its manifest object
+fn remote_manifest(
+    remote: &dyn RemoteBackend,
+    remote_key: &str,
+) -> Result<Option<archive::Manifest>> {
+    let Some(reader) = remote.get(&format!("{remote_key}{MANIFEST_SUFFIX}"))? else {
+        return Ok(None);
+    };
+    Ok(Some(serde_json::from_reader(io::BufReader::new(reader))?))
+}
+
+/// Upload the dir of the locked `key` to `remote` as the archive of
+/// `remote_key`
+///
+/// If the remote already has the same content (e.g. it was downloaded from
+/// there, and not modified since), only its use is recorded.
+pub fn upload(
+    remote: &dyn RemoteBackend,
+    root: &Root,
+    key: &str,
+    remote_key: &str,
+    lock_id: &str,
+) -> Result<()> {
+    let dir = root.key_dir_path(key);
+    // the archive must have a known size before it's uploaded
+    let staging_dir = root.create_staging_dir(key)?;
+    let res = (|| -> Result<bool> {
+        let archive_path = staging_dir.join("archive.tar.zst");
+        let header = archive::Header::new(remote_key);
+        let manifest = archive::export(
+            &dir,
+            &header,
+            io::BufWriter::new(fs::File::create(&archive_path)?),
+        )?;
+        match remote_manifest(remote, remote_key) {
+            Ok(Some(remote_manifest)) if remote_manifest == manifest => return Ok(false),
+            Ok(_) => {}
+            Err(err) => {
+                debug!(target: LOG_TARGET, %err, key, remote_key, "Failed to read remote manifest");
+            }
+        }
+        let manifest_path = staging_dir.join("manifest.json");
+        fs::write(
+            &manifest_path,
+            serde_json::to_vec_pretty(&RemoteManifest {
+                header: &header,
+                archive_size: fs::metadata(&archive_path)?.len(),
+                manifest: &manifest,
+            })?,
+        )?;
+        remote.put(&format!("{remote_key}{ARCHIVE_SUFFIX}"), &archive_path)?;
+        remote.put(&format!("{remote_key}{MANIFEST_SUFFIX}"), &manifest_path)?;
+        Ok(true)
+    })();
+    fs::remove_dir_all(&staging_dir).context("Failed to remove staging dir")?;
+    if !res? {
+        debug!(target: LOG_TARGET, key, remote_key, "Unchanged in remote cache");
+        record_use(remote, key, remote_key);
+        return Ok(());
+    }
+    info!(target: LOG_TARGET, key, remote_key, "Uploaded to remote cache");
+    root.record(journal::Entry::new(
+        key,
+        Some(lock_id),
+        journal::Event::RemoteUploaded,
+    ));
+    Ok(())
+}
+
+/// Delete keys neither uploaded nor downloaded since `deadline`, returning
+/// them
+///
+/// `exec` uploads the dirs after every successful use (or records it in
+/// `<key>.used` if unchanged), and so do downloads, so this matches the local
+/// `gc unused`.
+pub fn gc_unused(remote: &dyn RemoteBackend, deadline: DateTime<Utc>) -> Result<Vec<String>> {
+    let objects = remote.list()?;
+    let mut last_used: BTreeMap<&str, DateTime<Utc>> = BTreeMap::new();
+    for object in &objects {
+        if let Some(key) = object.name.strip_suffix(USED_SUFFIX) {
+            last_used.insert(key, object.last_modified);
+        }
+    }
+
+    let mut evicted = vec![];
+    for object in &objects {
+        let Some(key) = object.name.strip_suffix(ARCHIVE_SUFFIX) else {
+            continue;
+        };
+        let last_used = last_used
+            .remove(key)
+            .map_or(object.last_modified, |used| used.max(object.last_modified));
+        if deadline <= last_used {
+            continue;
+        }
+        debug!(target: LOG_TARGET, key, %last_used, "Deleting from remote cache");
+        remote.delete(&object.name)?;
+        remote.delete(&format!("{key}{MANIFEST_SUFFIX}"))?;
+        remote.delete(&format!("{key}{USED_SUFFIX}"))?;
+        evicted.push(key.to_owned());
+    }
+    // of archives deleted meanwhile
+    for (key, used) in last_used {
+        if used < deadline {
+            remote.delete(&format!("{key}{USED_SUFFIX}"))?;
+        }
+    }
+    Ok(evicted)
+}
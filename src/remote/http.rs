@@ -0,0 +1,78 @@
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{bail, Context as _, Result};
+
+use super::{RemoteBackend, RemoteObject};
+
+/// Objects stored as `<url>/<name>`
+///
+/// Works with anything accepting `PUT`s of files to be `GET` later: simple
+/// static file servers (e.g. nginx with WebDAV), bazel-remote, etc. Objects
+/// can't be listed, so there's no GC.
+pub struct HttpBackend {
+    base_url: String,
+    agent: ureq::Agent,
+}
+
+impl HttpBackend {
+    pub fn new(url: &str) -> Self {
+        Self {
+            base_url: url.trim_end_matches('/').to_owned(),
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(Duration::from_secs(10))
+                .timeout_read(Duration::from_secs(60))
+                .build(),
+        }
+    }
+
+    fn object_url(&self, name: &str) -> String {
+        format!("{}/{name}", self.base_url)
+    }
+}
+
+impl RemoteBackend for HttpBackend {
+    fn get(&self, name: &str) -> Result<Option<Box<dyn Read + Send>>> {
+        let url = self.object_url(name);
+        match self.agent.get(&url).call() {
+            Ok(response) => Ok(Some(response.into_reader())),
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("Failed to GET {url}")),
+        }
+    }
+
+    fn put(&self, name: &str, path: &Path) -> Result<()> {
+        let url = self.object_url(name);
+        let file = fs::File::open(path)?;
+        let len = file.metadata()?.len();
+        self.agent
+            .put(&url)
+            .set("Content-Length", &len.to_string())
+            .send(file)
+            .with_context(|| format!("Failed to PUT {url}"))?;
+        Ok(())
+    }
+
+    fn put_bytes(&self, name: &str, data: &[u8]) -> Result<()> {
+        let url = self.object_url(name);
+        self.agent
+            .put(&url)
+            .send_bytes(data)
+            .with_context(|| format!("Failed to PUT {url}"))?;
+        Ok(())
+    }
+
+    fn delete(&self, name: &str) -> Result<()> {
+        let url = self.object_url(name);
+        match self.agent.delete(&url).call() {
+            Ok(_) | Err(ureq::Error::Status(404, _)) => Ok(()),
+            Err(err) => Err(err).with_context(|| format!("Failed to DELETE {url}")),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<RemoteObject>> {
+        bail!("HTTP remote cache can't be listed")
+    }
+}
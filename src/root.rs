@@ -1,25 +1,36 @@
-mod dto;
+pub mod dto;
+pub mod journal;
+mod locking;
+mod migrate;
+mod store;
+mod waiter;
 
-use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
 use std::io::{self};
+use std::os::unix::fs::MetadataExt as _;
 #[cfg(not(target_os = "macos"))]
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::{Path, PathBuf};
 use std::time::Duration;
-use std::{fs, thread};
 
-use anyhow::{bail, Result};
-use chrono::Utc;
+use anyhow::{bail, Context as _, Result};
+use chrono::{DateTime, Utc};
 use convi::ExpectFrom;
+#[cfg(target_os = "macos")]
 use fs2::FileExt;
-use tracing::{debug, info, warn};
+use rand::distributions::{Alphanumeric, DistString};
+pub use store::LockedKey;
+use store::MetadataStore;
+use tracing::{debug, error, info, warn};
 
 use crate::{util, LOG_TARGET};
 
 /// Root directory of a cache
 pub struct Root {
     path: PathBuf,
-    lock_file: fs::File,
+    locking: dto::LockingMode,
+    store: Box<dyn MetadataStore>,
 }
 
 impl Root {
@@ -27,16 +38,1119 @@ impl Root {
         let path = path.into();
         ensure_root_exists(&path)?;
 
-        let lock = util::open_lock_file(&path)?;
+        let config = load_config(&path)?;
+        let store = store::open(&path, &config)?;
 
-        Ok(Self {
+        let mut root = Self {
             path,
-            lock_file: lock,
-        })
+            locking: config.locking,
+            store,
+        };
+        root.ensure_data_current()?;
+        Ok(root)
     }
 
+    /// Make sure root data is in the current format, so operations that don't
+    /// lock the whole root can proceed
+    fn ensure_data_current(&mut self) -> Result<()> {
+        let path = data_file_path(&self.path);
+        let needs_load = match fs::File::open(&path) {
+            Ok(file) => match serde_json::from_reader(io::BufReader::new(file)) {
+                Ok(data) => migrate::check(&data)?,
+                // possibly corrupted, loading under the lock will take care of it
+                Err(_) => true,
+            },
+            // a new root, to be created
+            Err(err) if err.kind() == io::ErrorKind::NotFound => true,
+            Err(err) => return Err(err.into()),
+        };
+        if needs_load {
+            self.with_lock(|root| root.load_data().map(|_| ()))?;
+        }
+        Ok(())
+    }
+
+    /// Run `f` with the whole root locked
+    ///
+    /// Only needed for operations that require a consistent view of all the
+    /// keys.
     pub fn with_lock<T>(&mut self, f: impl FnOnce(&mut LockedRoot) -> Result<T>) -> Result<T> {
-        f(&mut LockedRoot::new(&self.path, &mut self.lock_file)?)
+        f(&mut LockedRoot::new(
+            &self.path,
+            self.locking,
+            &*self.store,
+        )?)
+    }
+
+    /// Change the configuration of the root, moving all the key metadata
+    /// if the metadata store changed
+    ///
+    /// Processes that already opened the root keep using the previous
+    /// store, so this should only be done while the root is not in use.
+    pub fn set_config(&mut self, config: &dto::RootConfig) -> Result<()> {
+        let new_store = self.with_lock(|root| {
+            let prev = root.load_config()?;
+            let new_store = store::open(root.path, config)?;
+            if prev.metadata_store != config.metadata_store {
+                let moved = store::move_keys(root.store, &*new_store)?;
+                info!(
+                    target: LOG_TARGET,
+                    moved,
+                    from = %prev.metadata_store,
+                    to = %config.metadata_store,
+                    "Moved key metadata to a new store"
+                );
+            }
+            util::store_json_pretty_to_file(&config_file_path(root.path), config)?;
+            Ok(new_store)
+        })?;
+        self.store = new_store;
+        self.locking = config.locking;
+        Ok(())
+    }
+
+    /// Run `f` with data of a single key locked
+    pub fn with_key_lock<T>(
+        &self,
+        key: &str,
+        f: impl FnOnce(&mut LockedKey) -> Result<T>,
+    ) -> Result<T> {
+        store::with_key_lock(&*self.store, key, f)
+    }
+
+    /// Was `key` ever locked (and not evicted since)
+    pub fn has_key(&self, key: &str) -> Result<bool> {
+        self.store.contains_key(key)
+    }
+
+    pub fn record(&self, entry: journal::Entry) {
+        record(&self.path, self.locking, entry);
+    }
+
+    /// Lock all the `keys` at once, returning their dirs
+    ///
+    /// Keys are only taken when all of them are available, so processes
+    /// locking overlapping sets of keys in different order can't deadlock.
+    pub fn lock_keys(&self, keys: &[&str], req: LockRequest) -> Result<Vec<PathBuf>> {
+        Ok(self
+            .lock_keys_inner(keys, req, true)?
+            .expect("waited for the lock"))
+    }
+
+    /// Like [`Self::lock_keys`], but returns `None` instead of waiting if any
+    /// of the keys is busy
+    pub fn try_lock_keys(&self, keys: &[&str], req: LockRequest) -> Result<Option<Vec<PathBuf>>> {
+        self.lock_keys_inner(keys, req, false)
+    }
+
+    fn lock_keys_inner(
+        &self,
+        keys: &[&str],
+        req: LockRequest,
+        wait: bool,
+    ) -> Result<Option<Vec<PathBuf>>> {
+        for (i, key) in keys.iter().enumerate() {
+            if keys[..i].contains(key) {
+                bail!("Key {key} given more than once");
+            }
+        }
+        let lock_id = req.lock_id.as_str();
+        for key in keys {
+            self.record(journal::Entry::new(
+                key,
+                Some(lock_id),
+                journal::Event::LockRequested,
+            ));
+        }
+        let locking_start = Utc::now();
+        let mut had_to_wait = false;
+        // only bound once we actually have to wait for an unlock
+        let mut waiter: Option<waiter::Waiter> = None;
+        let hits = loop {
+            let waiter_path = waiter.as_ref().map(|w| w.path());
+            let attempt = store::with_keys_lock(&*self.store, keys, |locked_keys| {
+                let now = Utc::now();
+                let waited_ms = duration_to_ms(now.signed_duration_since(locking_start));
+
+                let mut all_key_data = Vec::with_capacity(keys.len());
+                let mut wait = None;
+                for (key, locked_key) in keys.iter().zip(locked_keys.iter_mut()) {
+                    let (key_data, existed) = match locked_key.load()? {
+                        Some(key_data) => (key_data, true),
+                        None => (dto::KeyData::new(now), false),
+                    };
+                    if key_data.published.is_some() && !req.publish {
+                        bail!("Key {key} was published and is immutable, use `exec --publish`");
+                    }
+                    let mut key_data = KeyState {
+                        data: key_data,
+                        existed,
+                        changed: false,
+                    };
+                    if wait.is_none() {
+                        wait = self
+                            .check_key(key, &mut key_data, now, lock_id, waiter_path)
+                            .map(|wait| (all_key_data.len(), wait));
+                    }
+                    all_key_data.push(key_data);
+                }
+
+                if let Some((busy_i, wait)) = wait {
+                    let Some(waiter_path) = waiter_path else {
+                        return Ok(LockAttempt::NeedWaiter);
+                    };
+                    // only queue up for the key being waited for, so waiting
+                    // doesn't hold up other keys
+                    for (i, (key_data, locked_key)) in all_key_data
+                        .iter_mut()
+                        .zip(locked_keys.iter_mut())
+                        .enumerate()
+                    {
+                        key_data.changed |= if i == busy_i {
+                            key_data
+                                .data
+                                .enqueue(now, waiter_path, lock_id, req.priority)
+                        } else {
+                            key_data.data.dequeue(waiter_path)
+                        };
+                        if key_data.changed {
+                            locked_key.store(&key_data.data)?;
+                        }
+                    }
+                    return Ok(wait);
+                }
+
+                let mut hits = Vec::with_capacity(keys.len());
+                for ((key, key_data), locked_key) in keys
+                    .iter()
+                    .zip(all_key_data.iter_mut())
+                    .zip(locked_keys.iter_mut())
+                {
+                    let hit = key_data.existed && !key_data.data.is_dir_dropped();
+                    if hit {
+                        self.record_takeover(key, &key_data.data, now, lock_id);
+                    }
+                    let key_data = &mut key_data.data;
+                    key_data.lock(now, &req)?;
+                    if !req.maintenance {
+                        key_data.stats.record_acquisition(hit, waited_ms);
+                    }
+                    hits.push(hit);
+                    if let Some(waiter_path) = waiter_path {
+                        key_data.dequeue(waiter_path);
+                    }
+                    locked_key.store(key_data)?;
+                }
+                Ok(LockAttempt::Acquired { hits })
+            })?;
+
+            match attempt {
+                LockAttempt::Acquired { hits } => break hits,
+                LockAttempt::NeedWaiter if !wait => {
+                    debug!(
+                        target: LOG_TARGET,
+                        ?keys,
+                        lock_id,
+                        "Keys busy, not waiting"
+                    );
+                    return Ok(None);
+                }
+                LockAttempt::NeedWaiter => {
+                    waiter = Some(waiter::Waiter::bind(&self.path)?);
+                }
+                LockAttempt::WaitForUnlock(timeout) => {
+                    had_to_wait |= true;
+                    waiter
+                        .as_ref()
+                        .expect("waiter bound before waiting")
+                        .wait(timeout)?;
+                }
+                LockAttempt::WaitForHolder(wait) => {
+                    had_to_wait |= true;
+                    wait();
+                }
+            }
+        };
+
+        let waited = Utc::now().signed_duration_since(locking_start);
+        for (key, hit) in keys.iter().zip(hits) {
+            self.record(journal::Entry::new(
+                key,
+                Some(lock_id),
+                journal::Event::LockAcquired {
+                    hit,
+                    waited_ms: duration_to_ms(waited),
+                },
+            ));
+            if had_to_wait {
+                info!(
+                    target: LOG_TARGET,
+                    key,
+                    lock_id,
+                    wait_secs=%waited.num_seconds(),
+                    "Acquired lock"
+                );
+            }
+        }
+        Ok(Some(
+            keys.iter().map(|key| self.key_dir_path(key)).collect(),
+        ))
+    }
+
+    /// Check if `key` can be taken right away, or what to wait for
+    ///
+    /// Also drops waiters that went away from the queue of the key.
+    fn check_key(
+        &self,
+        key: &str,
+        key_data: &mut KeyState,
+        now: DateTime<Utc>,
+        lock_id: &str,
+        waiter_path: Option<&Path>,
+    ) -> Option<LockAttempt> {
+        // drop waiters that went away without leaving the queue
+        let queue_len = key_data.data.queue.len();
+        key_data
+            .data
+            .queue
+            .retain(|w| Some(w.socket_path.as_path()) == waiter_path || waiter::is_alive(w, now));
+        key_data.changed |= key_data.data.queue.len() != queue_len;
+        let key_data = &key_data.data;
+
+        // sockets on other hosts can't be connected to, so such locks
+        // rely on their lease (timeout) instead
+        let local_sock_path = key_data
+            .socket_path
+            .clone()
+            .filter(|_| !key_data.is_held_remotely());
+        if let Some(prev_sock_path) = local_sock_path {
+            try_lock(&prev_sock_path).ok().map(|s| {
+                info!(
+                    target: LOG_TARGET,
+                    key,
+                    lock_id,
+                    sock_path = %prev_sock_path.display(),
+                    "Previous lock holder still alive (potentially)"
+                );
+                LockAttempt::WaitForHolder(Box::new(move || {
+                    let _ = clear_lock(s, &prev_sock_path).inspect_err(|err| {
+                        info!(
+                            %err,
+                            "Error during waiting for / clearing the old lock"
+                        )
+                    });
+                }))
+            })
+        } else if key_data.is_timelocked(now) && !key_data.is_owner_gone() {
+            let expires_in_msecs = key_data.expires_in(now).num_milliseconds();
+            // owner's exit, and unlocks on other hosts don't notify
+            let max_wait_ms = if key_data.owner.is_some() {
+                OWNER_RECHECK_MS
+            } else if self.locking == dto::LockingMode::Lockfile {
+                REMOTE_RECHECK_MS
+            } else {
+                MAX_WAIT_MS
+            };
+            info!(
+                target: LOG_TARGET,
+                key,
+                lock_id,
+                holder_lock_id = %key_data.lock_id,
+                expires_in_msecs,
+                "Waiting for the key lock to be released..."
+            );
+            // wake up on expiry, or eventually in case the
+            // notification got lost
+            Some(LockAttempt::WaitForUnlock(Duration::from_millis(
+                u64::expect_from(expires_in_msecs.clamp(10, max_wait_ms)),
+            )))
+        } else if let Some(head) = key_data
+            .queue_head()
+            .filter(|head| Some(head.socket_path.as_path()) != waiter_path)
+        {
+            debug!(
+                target: LOG_TARGET,
+                key,
+                lock_id,
+                head_lock_id = %head.lock_id,
+                "Waiting for earlier waiters to take their turn"
+            );
+            Some(LockAttempt::WaitForUnlock(QUEUE_RECHECK))
+        } else {
+            None
+        }
+    }
+
+    /// Clean up after, and journal how the previous lock of `key` ended,
+    /// before it gets taken over
+    fn record_takeover(
+        &self,
+        key: &str,
+        key_data: &dto::KeyData,
+        now: DateTime<Utc>,
+        lock_id: &str,
+    ) {
+        if let Some(prev_sock_path) = key_data.socket_path.as_ref() {
+            debug!(
+                target: LOG_TARGET,
+                key,
+                lock_id,
+                sock_path = %prev_sock_path.display(),
+                "Previous lock holder gone"
+            );
+            rm_prev_sock_path(prev_sock_path);
+            self.record(journal::Entry::new(
+                key,
+                Some(lock_id),
+                journal::Event::LockStolen {
+                    prev_lock_id: key_data.lock_id.clone(),
+                },
+            ));
+        } else if key_data.is_timelocked(now) {
+            debug!(
+                target: LOG_TARGET,
+                key,
+                lock_id,
+                owner = ?key_data.owner,
+                "Previous lock owner process gone"
+            );
+            self.record(journal::Entry::new(
+                key,
+                Some(lock_id),
+                journal::Event::LockStolen {
+                    prev_lock_id: key_data.lock_id.clone(),
+                },
+            ));
+        } else {
+            debug!(
+                target: LOG_TARGET,
+                key, lock_id, "Previous lock expired"
+            );
+            if !key_data.released {
+                self.record(journal::Entry::new(
+                    key,
+                    Some(lock_id),
+                    journal::Event::LockExpired {
+                        prev_lock_id: key_data.lock_id.clone(),
+                    },
+                ));
+            }
+        }
+    }
+
+    pub fn unlock_key(&self, key: &str, lock_id: String, manifest: ManifestUpdate) -> Result<()> {
+        self.with_key_lock(key, |locked_key| {
+            let mut key_data = match load_held(locked_key, key, &lock_id) {
+                Ok(key_data) => key_data,
+                Err(err) => {
+                    if let Some(key_data) = locked_key.load()? {
+                        self.record(journal::Entry::new(
+                            key,
+                            Some(&lock_id),
+                            journal::Event::UnlockMismatch {
+                                owner_lock_id: key_data.lock_id,
+                            },
+                        ));
+                    }
+                    return Err(err);
+                }
+            };
+            let now = Utc::now();
+            // locks held via a liveness socket don't rely on the timeout
+            if key_data.socket_path.is_none() && !key_data.is_timelocked(now) {
+                warn!(key, "Lock already expired");
+            }
+            let held = now.signed_duration_since(key_data.locked_at());
+            let maintenance = key_data.maintenance_lock.is_some();
+            key_data.unlock(now);
+            let manifest_path = manifest_file_path(&self.path, key);
+            match manifest {
+                ManifestUpdate::Keep => {}
+                ManifestUpdate::Clear => remove_file_if_exists(&manifest_path)?,
+                ManifestUpdate::Set(manifest) => {
+                    util::store_to_file_with(&manifest_path, |f| {
+                        serde_json::to_writer(f, &manifest)
+                    })?
+                    .context("Failed to store the manifest")?;
+                }
+            }
+            if !maintenance {
+                key_data.stats.record_release(duration_to_ms(held));
+            }
+            waiter::notify_all(&mut key_data.queue);
+            locked_key.store(&key_data)?;
+            self.record(journal::Entry::new(
+                key,
+                Some(&lock_id),
+                journal::Event::LockReleased {
+                    held_ms: duration_to_ms(held),
+                },
+            ));
+            Ok(())
+        })
+    }
+
+    /// Deal with the dir of `key` held by `lock_id` after a failed `exec`,
+    /// before it gets unlocked
+    pub fn handle_failure(&self, key: &str, lock_id: &str, action: dto::OnFailure) -> Result<()> {
+        let trashed = self.with_key_lock(key, |locked_key| {
+            let mut key_data = load_held(locked_key, key, lock_id)?;
+            let now = Utc::now();
+            let key_dir = self.key_dir_path(key);
+            let mut quarantine_path = None;
+            let mut trashed = None;
+            match action {
+                dto::OnFailure::Keep => {}
+                dto::OnFailure::Discard => {
+                    info!(target: LOG_TARGET, key, "Discarding the dir of a failed command");
+                    trashed = trash_dir(&self.path, &key_dir)?;
+                }
+                dto::OnFailure::Quarantine => {
+                    let path = quarantine_dir_path(&self.path)
+                        .join(format!("{key}-{}", now.format("%Y%m%dT%H%M%S%.3fZ")));
+                    info!(
+                        target: LOG_TARGET,
+                        key,
+                        path = %path.display(),
+                        "Quarantining the dir of a failed command"
+                    );
+                    fs::create_dir_all(quarantine_dir_path(&self.path))?;
+                    match fs::rename(&key_dir, &path) {
+                        Ok(()) => quarantine_path = Some(path),
+                        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                        Err(err) => return Err(err).context("Failed to quarantine the dir"),
+                    }
+                }
+            }
+            key_data.last_failure = Some(dto::FailureRecord {
+                time: now,
+                lock_id: lock_id.to_owned(),
+                action,
+                quarantine_path,
+            });
+            locked_key.store(&key_data)?;
+            self.record(journal::Entry::new(
+                key,
+                Some(lock_id),
+                journal::Event::ExecFailed { action },
+            ));
+            Ok(trashed)
+        })?;
+        if let Some(path) = trashed {
+            remove_trashed_dir(&path)?;
+        }
+        Ok(())
+    }
+
+    /// Delete the dir of `key` held by `lock_id`, e.g. when it's corrupted
+    ///
+    /// A published dir is unpublished, unless it's in use, in which case
+    /// nothing is done and `false` is returned.
+    pub fn discard_dir(&self, key: &str, lock_id: &str) -> Result<bool> {
+        let trashed = self.with_key_lock(key, |locked_key| {
+            let mut key_data = load_held(locked_key, key, lock_id)?;
+            if key_data.has_live_readers(Utc::now()) {
+                return Ok(None);
+            }
+            let trashed = trash_dir(&self.path, &self.key_dir_path(key))?;
+            remove_file_if_exists(&manifest_file_path(&self.path, key))?;
+            key_data.published = None;
+            key_data.size = None;
+            locked_key.store(&key_data)?;
+            Ok(Some(trashed))
+        })?;
+        let Some(trashed) = trashed else {
+            return Ok(false);
+        };
+        if let Some(path) = trashed {
+            remove_trashed_dir(&path)?;
+        }
+        Ok(true)
+    }
+
+    /// Discard or trim the dir of `key` held by `lock_id` if it's over
+    /// `max_bytes`, before it gets unlocked
+    ///
+    /// Files also linked from other dirs (see `dedup`) don't count, as they'd
+    /// take the space anyway. Published dirs are immutable, so they're always
+    /// discarded (unless in use). Returns `true` if the dir was over the quota.
+    pub fn enforce_quota(
+        &self,
+        key: &str,
+        lock_id: &str,
+        max_bytes: u64,
+        action: dto::OverQuota,
+    ) -> Result<bool> {
+        self.with_key_lock(key, |locked_key| load_held(locked_key, key, lock_id))?;
+        let dir = self.key_dir_path(key);
+        let bytes = match util::dir_usage_exclusive(&dir) {
+            Ok(usage) => usage.apparent_bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err).context("Failed to measure the dir"),
+        };
+        if bytes <= max_bytes {
+            return Ok(false);
+        }
+        let freed_bytes = match action {
+            dto::OverQuota::Trim if !self.is_published(key)? => {
+                util::trim_dir(&dir, max_bytes).context("Failed to trim the dir")?
+            }
+            _ if self.discard_dir(key, lock_id)? => bytes,
+            _ => 0,
+        };
+        warn!(
+            target: LOG_TARGET,
+            key, bytes, max_bytes, %action, freed_bytes, "Key dir over quota"
+        );
+        self.record(journal::Entry::new(
+            key,
+            Some(lock_id),
+            journal::Event::OverQuota {
+                bytes,
+                max_bytes,
+                action,
+                freed_bytes,
+            },
+        ));
+        Ok(true)
+    }
+
+    /// Content manifest recorded when `key` was last released, see
+    /// [`ManifestUpdate`]
+    ///
+    /// `key` must be locked, so it's not being replaced meanwhile.
+    pub fn load_manifest(&self, key: &str) -> Result<Option<dto::DirManifest>> {
+        let path = manifest_file_path(&self.path, key);
+        match fs::File::open(&path) {
+            Ok(file) => Ok(Some(
+                serde_json::from_reader(io::BufReader::new(file))
+                    .with_context(|| format!("Failed to load {}", path.display()))?,
+            )),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// When the content manifest of `key` was recorded, if it was
+    pub fn manifest_recorded_at(&self, key: &str) -> Result<Option<DateTime<Utc>>> {
+        match fs::metadata(manifest_file_path(&self.path, key)) {
+            Ok(metadata) => Ok(Some(metadata.modified()?.into())),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Measure the dir of `key` and record its size, unless it's locked
+    ///
+    /// Huge dirs take a while, so they are measured without holding any lock,
+    /// and the size is dropped if the key got locked meanwhile. Only the
+    /// subdirs changed since the last time are read again, see
+    /// [`util::dir_usage_incremental`]. Returns whether it was recorded.
+    pub fn measure_dir(&self, key: &str) -> Result<bool> {
+        let measured_at = Utc::now();
+        let unlocked = self.with_key_lock(key, |locked_key| {
+            Ok(locked_key
+                .load()?
+                .is_some_and(|key_data| !key_data.is_locked(measured_at)))
+        })?;
+        if !unlocked {
+            return Ok(false);
+        }
+        let cache_path = usage_cache_path(&self.path, key);
+        let mut cache = load_usage_cache(&cache_path);
+        let dir = self.key_dir_path(key);
+        let (usage, linked) = match util::dir_usage_incremental(&dir, &mut cache) {
+            Ok(res) => res,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Default::default(),
+            Err(err) => return Err(err).context("Failed to measure the dir"),
+        };
+        util::store_to_file_with(&cache_path, |f| serde_json::to_writer(f, &cache))?
+            .context("Failed to store the usage cache")?;
+        let shared_files: Vec<_> = linked
+            .into_values()
+            .filter(util::LinkedFile::is_shared)
+            .collect();
+        let mut shared = util::DiskUsage::default();
+        for file in &shared_files {
+            shared += file.usage;
+        }
+        self.with_key_lock(key, |locked_key| {
+            let Some(mut key_data) = locked_key.load()? else {
+                return Ok(false);
+            };
+            if key_data.is_locked(Utc::now()) || measured_at < key_data.modified_at() {
+                debug!(target: LOG_TARGET, key, "Locked while measuring, dropping the size");
+                return Ok(false);
+            }
+            // along with the size, so they always match
+            let shared_path = shared_files_path(&self.path, key);
+            if shared_files.is_empty() {
+                remove_file_if_exists(&shared_path)?;
+            } else {
+                util::store_to_file_with(&shared_path, |f| {
+                    serde_json::to_writer(f, &shared_files)
+                })?
+                .context("Failed to store the shared files")?;
+            }
+            key_data.size = Some(dto::DirSize {
+                measured_at,
+                usage,
+                shared,
+            });
+            locked_key.store(&key_data)?;
+            Ok(true)
+        })
+    }
+
+    /// Like [`Self::measure_dir`], but reading the whole dir, e.g. after its
+    /// files got linked elsewhere, which leaves the dirs unchanged
+    pub fn measure_dir_fully(&self, key: &str) -> Result<bool> {
+        remove_file_if_exists(&usage_cache_path(&self.path, key))?;
+        self.measure_dir(key)
+    }
+
+    /// Total size of the dirs of `keys`, as last measured, counting the files
+    /// shared between them (see `dedup`) only once
+    pub fn total_usage<'a>(
+        &self,
+        keys: impl IntoIterator<Item = (&'a String, &'a dto::KeyData)>,
+    ) -> util::DiskUsage {
+        let mut total = util::DiskUsage::default();
+        let mut seen = HashSet::new();
+        for (key, key_data) in keys {
+            let Some(size) = key_data.size else {
+                continue;
+            };
+            total += size.usage;
+            if size.shared == util::DiskUsage::default() {
+                continue;
+            }
+            let path = shared_files_path(&self.path, key);
+            let res = fs::File::open(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|file| {
+                    Ok(serde_json::from_reader::<_, Vec<util::LinkedFile>>(
+                        io::BufReader::new(file),
+                    )?)
+                });
+            match res {
+                Ok(files) => {
+                    total -= size.shared;
+                    for file in files {
+                        if seen.insert((file.dev, file.ino)) {
+                            total += file.usage;
+                        }
+                    }
+                }
+                Err(err) => {
+                    debug!(target: LOG_TARGET, %err, key, "No shared files, counting them all");
+                }
+            }
+        }
+        total
+    }
+
+    /// Start reading the published dir of `key`, if it's published
+    ///
+    /// Readers don't lock the key, they only keep it from getting evicted
+    /// while alive.
+    pub fn start_reading(
+        &self,
+        key: &str,
+        lock_id: &str,
+        socket_path: &Path,
+    ) -> Result<Option<PathBuf>> {
+        self.with_key_lock(key, |locked_key| {
+            let Some(mut key_data) = locked_key.load()? else {
+                return Ok(None);
+            };
+            if key_data.published.is_none() {
+                return Ok(None);
+            }
+            let now = Utc::now();
+            key_data.readers.retain(|reader| reader.is_alive(now));
+            key_data
+                .readers
+                .push(dto::Reader::new(now, lock_id, socket_path));
+            key_data.last_lock = now;
+            locked_key.store(&key_data)?;
+            Ok(Some(self.key_dir_path(key)))
+        })
+    }
+
+    pub fn stop_reading(&self, key: &str, lock_id: &str) -> Result<()> {
+        self.with_key_lock(key, |locked_key| {
+            let Some(mut key_data) = locked_key.load()? else {
+                bail!("Key {} does not exist", key);
+            };
+            key_data.readers.retain(|reader| reader.lock_id != lock_id);
+            locked_key.store(&key_data)
+        })
+    }
+
+    pub fn is_published(&self, key: &str) -> Result<bool> {
+        self.with_key_lock(key, |locked_key| {
+            Ok(locked_key
+                .load()?
+                .is_some_and(|key_data| key_data.published.is_some()))
+        })
+    }
+
+    /// Create an empty dir to populate `key` in, before [`Self::publish`]
+    ///
+    /// Leftovers of earlier failed populations are removed, so `key` must be
+    /// locked.
+    pub fn create_staging_dir(&self, key: &str) -> Result<PathBuf> {
+        let staging_root = staging_dir_path(&self.path);
+        fs::create_dir_all(&staging_root)?;
+        for entry in fs::read_dir(&staging_root)? {
+            let entry = entry?;
+            let is_of_key = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_prefix(key))
+                .is_some_and(|rest| rest.starts_with('.'));
+            if is_of_key {
+                debug!(target: LOG_TARGET, path = %entry.path().display(), "Removing leftover staging dir");
+                util::remove_dir_all(&entry.path())?;
+            }
+        }
+        let path = staging_root.join(format!(
+            "{key}.{}",
+            Alphanumeric.sample_string(&mut rand::thread_rng(), 8)
+        ));
+        fs::create_dir(&path)?;
+        Ok(path)
+    }
+
+    /// Move the `staging_dir` populated while holding the lock of `key` into
+    /// place, replacing the existing dir
+    pub fn install_staging_dir(&self, key: &str, staging_dir: &Path) -> Result<()> {
+        let key_dir = self.key_dir_path(key);
+        if let Err(err) = util::remove_dir_all(&key_dir) {
+            if err.kind() != io::ErrorKind::NotFound {
+                return Err(err).context("Failed to remove the previous dir");
+            }
+        }
+        fs::rename(staging_dir, &key_dir)?;
+        Ok(())
+    }
+
+    /// Move the `staging_dir` populated while holding the lock of `key` into
+    /// place, and mark it immutable
+    pub fn publish(&self, key: &str, lock_id: &str, staging_dir: &Path) -> Result<()> {
+        // all but the dir itself, which couldn't be moved then
+        util::make_contents_read_only(staging_dir)?;
+        let trashed = self.with_key_lock(key, |locked_key| {
+            let mut key_data = load_held(locked_key, key, lock_id)?;
+            let key_dir = self.key_dir_path(key);
+            // possibly populated before it was published
+            let trashed = trash_dir(&self.path, &key_dir)?;
+            fs::rename(staging_dir, &key_dir)?;
+            util::set_writable(&key_dir, false)?;
+            key_data.published = Some(Utc::now());
+            locked_key.store(&key_data)?;
+            self.record(journal::Entry::new(
+                key,
+                Some(lock_id),
+                journal::Event::Published,
+            ));
+            Ok(trashed)
+        })?;
+        if let Some(path) = trashed {
+            remove_trashed_dir(&path)?;
+        }
+        Ok(())
+    }
+
+    /// Extend a held lock to `timeout_secs` from now
+    ///
+    /// Fails if the lock is no longer held by `lock_id`, including when it
+    /// already expired.
+    pub fn renew_key(&self, key: &str, lock_id: &str, timeout_secs: f64) -> Result<()> {
+        self.with_key_lock(key, |locked_key| {
+            let mut key_data = load_held(locked_key, key, lock_id)?;
+            let now = Utc::now();
+            if key_data.released {
+                bail!("Key {} lock was already released", key);
+            }
+            if !key_data.is_timelocked(now) {
+                bail!("Key {} lock already expired", key);
+            }
+            key_data.renew(now, timeout_secs)?;
+            locked_key.store(&key_data)?;
+            debug!(
+                target: LOG_TARGET,
+                key,
+                lock_id,
+                locked_until = %key_data.locked_until,
+                "Renewed lock"
+            );
+            Ok(())
+        })
+    }
+
+    pub fn key_dir_path(&self, key: &str) -> PathBuf {
+        self.path.join(key)
+    }
+}
+
+/// Everything about a lock to acquire with [`Root::lock_keys`]
+#[derive(Clone)]
+pub struct LockRequest {
+    pub lock_id: String,
+    pub timeout_secs: f64,
+    /// Liveness socket of the holder, lock is released once it's gone
+    pub socket_path: Option<PathBuf>,
+    /// Lock is released once this process is gone
+    pub owner: Option<dto::OwnerProcess>,
+    pub holder: dto::HolderInfo,
+    /// Position in the queue of waiters, see [`dto::KeyData::queue_head`]
+    pub priority: i32,
+    /// Lock is taken to populate the dir with [`Root::publish`], so
+    /// published keys can be locked too
+    pub publish: bool,
+    /// Lock is taken by a maintenance command (e.g. `verify`), which doesn't
+    /// count as a use of the key: neither `last_lock` (what `gc unused` goes
+    /// by) nor the usage stats are updated
+    pub maintenance: bool,
+}
+
+/// What to do with the [`dto::DirManifest`] of a key on
+/// [`Root::unlock_key`], the only time it's written
+#[derive(Debug, Clone)]
+pub enum ManifestUpdate {
+    /// The dir was not modified
+    Keep,
+    /// The dir could have been modified
+    Clear,
+    Set(dto::DirManifest),
+}
+
+/// How often waiters check if the lock owner process is still alive
+const OWNER_RECHECK_MS: i64 = 1000;
+
+/// How often waiters check the key when the root is shared between hosts
+const REMOTE_RECHECK_MS: i64 = 2000;
+
+/// Longest a waiter sleeps without being notified before re-checking
+/// the key
+const MAX_WAIT_MS: i64 = 60_000;
+
+/// How often waiters further in the queue check if the ones ahead of them
+/// are still there, while the key is free
+const QUEUE_RECHECK: Duration = Duration::from_secs(1);
+
+/// Data of a key during an attempt of [`Root::lock_keys`]
+struct KeyState {
+    data: dto::KeyData,
+    /// Key had data before (so it's a cache hit)
+    existed: bool,
+    /// Data needs storing, even if the key doesn't get locked
+    changed: bool,
+}
+
+/// Outcome of a single attempt of [`Root::lock_keys`]
+enum LockAttempt {
+    Acquired {
+        /// For each of the keys
+        hits: Vec<bool>,
+    },
+    /// Key is locked until unlocked or expired, a waiter socket needs to be
+    /// registered before waiting
+    NeedWaiter,
+    /// Key is locked, wait for a notification from `unlock`, at most for
+    /// the given time
+    WaitForUnlock(Duration),
+    /// Holder has a liveness socket, call to wait until it's gone
+    WaitForHolder(Box<dyn FnOnce()>),
+}
+
+/// Where `exec --publish` populates dirs, see [`Root::create_staging_dir`]
+fn staging_dir_path(root_path: &Path) -> PathBuf {
+    root_path.join(".staging")
+}
+
+/// Where dirs of failed `exec` commands are moved, see
+/// [`dto::OnFailure::Quarantine`]
+fn quarantine_dir_path(root_path: &Path) -> PathBuf {
+    root_path.join(".quarantine")
+}
+
+/// Where dirs are moved to be deleted, see [`trash_dir`]
+fn trash_dir_path(root_path: &Path) -> PathBuf {
+    root_path.join(".trash")
+}
+
+/// Move `dir` out of the way, to delete it with [`remove_trashed_dir`] once
+/// its key is no longer locked in the metadata store
+///
+/// Huge dirs take a while to delete, and other processes can't use the
+/// store meanwhile (none of its keys, with SQLite). Dirs left behind by
+/// crashes are removed by gc. Returns `None` if there's no `dir`.
+pub fn trash_dir(root_path: &Path, dir: &Path) -> Result<Option<PathBuf>> {
+    let trash_root = trash_dir_path(root_path);
+    fs::create_dir_all(&trash_root)?;
+    let name = dir
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+    let path = trash_root.join(format!(
+        "{name}.{}",
+        Alphanumeric.sample_string(&mut rand::thread_rng(), 8)
+    ));
+    match util::rename_dir(dir, &path) {
+        Ok(()) => Ok(Some(path)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).context("Failed to move the dir out of the way"),
+    }
+}
+
+pub fn remove_trashed_dir(path: &Path) -> Result<()> {
+    debug!(target: LOG_TARGET, path = %path.display(), "Deleting dir");
+    match util::remove_dir_all(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            Err(err).with_context(|| format!("Failed to delete {}", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Where `exec` runs commands that couldn't lock their keys, see
+/// [`create_scratch_dir`]
+fn scratch_dir_path(root_path: &Path) -> PathBuf {
+    root_path.join(".scratch")
+}
+
+/// Process using the scratch dir at `path`, kept next to it
+fn scratch_owner_path(path: &Path) -> PathBuf {
+    path.with_extension("owner.json")
+}
+
+/// Create a temporary dir in the root, for this process
+///
+/// Removed with [`remove_scratch_dir`], or by gc once the process is gone.
+pub fn create_scratch_dir(root_path: &Path) -> Result<PathBuf> {
+    let path =
+        scratch_dir_path(root_path).join(Alphanumeric.sample_string(&mut rand::thread_rng(), 16));
+    // first, so the dir is never taken for abandoned
+    util::store_json_pretty_to_file(
+        &scratch_owner_path(&path),
+        &dto::OwnerProcess::new(std::process::id()),
+    )?;
+    fs::create_dir(&path)?;
+    Ok(path)
+}
+
+pub fn remove_scratch_dir(path: &Path) -> io::Result<()> {
+    match util::remove_dir_all(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    remove_file_if_exists(&scratch_owner_path(path))
+}
+
+/// Where the [`dto::DirManifest`] of `key` is kept, next to its metadata
+/// (if that's in files)
+fn manifest_file_path(root_path: &Path, key: &str) -> PathBuf {
+    root_path.join(".meta").join(format!("{key}.manifest.json"))
+}
+
+/// Data of `key`, which must be held by `lock_id`
+fn load_held(locked_key: &mut LockedKey, key: &str, lock_id: &str) -> Result<dto::KeyData> {
+    let Some(key_data) = locked_key.load()? else {
+        bail!("Key {} does not exist", key);
+    };
+    if key_data.lock_id != lock_id {
+        bail!(
+            "Key {} lock id does not match; used = {}, owner = {}",
+            key,
+            lock_id,
+            key_data.lock_id
+        );
+    }
+    Ok(key_data)
+}
+
+fn remove_file_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// Where [`Root::measure_dir`] keeps the usage of the subdirs of `key`
+fn usage_cache_path(root_path: &Path, key: &str) -> PathBuf {
+    root_path.join(".sizes").join(format!("{key}.json"))
+}
+
+/// Where [`Root::measure_dir`] lists the files of `key` shared with other
+/// dirs, see [`dto::DirSize::shared`]
+fn shared_files_path(root_path: &Path, key: &str) -> PathBuf {
+    root_path.join(".sizes").join(format!("{key}.linked.json"))
+}
+
+/// Missing or unreadable cache just means reading the whole dir
+fn load_usage_cache(path: &Path) -> util::UsageCache {
+    let res = fs::File::open(path)
+        .map_err(anyhow::Error::from)
+        .and_then(|file| Ok(serde_json::from_reader(io::BufReader::new(file))?));
+    match res {
+        Ok(cache) => cache,
+        Err(err) => {
+            debug!(target: LOG_TARGET, %err, path = %path.display(), "No usage cache");
+            Default::default()
+        }
+    }
+}
+
+fn data_file_path(root_path: &Path) -> PathBuf {
+    root_path.join("fs-dir-cache.json")
+}
+
+fn root_lock_path(root_path: &Path) -> PathBuf {
+    root_path.join("lock")
+}
+
+fn config_file_path(root_path: &Path) -> PathBuf {
+    root_path.join("fs-dir-cache.config.json")
+}
+
+pub fn load_config(root_path: &Path) -> Result<dto::RootConfig> {
+    let path = config_file_path(root_path);
+    match fs::File::open(&path) {
+        Ok(file) => serde_json::from_reader(io::BufReader::new(file))
+            .with_context(|| format!("Failed to load {}", path.display())),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Default::default()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn load_data_from(path: &Path) -> Result<(dto::RootData, bool)> {
+    migrate::parse(serde_json::from_reader(fs::File::open(path)?)?)
+        .with_context(|| format!("Failed to load {}", path.display()))
+}
+
+/// Did loading fail to read the file, rather than to parse it
+fn is_io_error(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause.is::<io::Error>()
+            || cause
+                .downcast_ref::<serde_json::Error>()
+                .is_some_and(serde_json::Error::is_io)
+    })
+}
+
+pub fn journal_file_path(root_path: &Path) -> PathBuf {
+    root_path.join("fs-dir-cache.journal.jsonl")
+}
+
+/// Append an event to the journal
+///
+/// Failing to do so is only logged, as the journal is a debugging aid.
+fn record(root_path: &Path, locking: dto::LockingMode, entry: journal::Entry) {
+    if let Err(err) = journal::append(&journal_file_path(root_path), locking, &entry) {
+        warn!(target: LOG_TARGET, %err, "Failed to write journal entry");
     }
 }
 
@@ -54,28 +1168,23 @@ fn ensure_root_exists(dir: &PathBuf) -> Result<()> {
 /// A handle passed to `with_lock` argument after root was acquired
 pub struct LockedRoot<'a> {
     path: &'a PathBuf,
-    lock_file: &'a mut fs::File,
-    locked: bool,
-}
-
-impl<'a> Drop for LockedRoot<'a> {
-    fn drop(&mut self) {
-        if self.locked {
-            let Ok(()) = self.lock_file.unlock() else {
-                warn!("Failed to release the cache lock file");
-                return;
-            };
-            self.locked = false;
-        }
-    }
+    locking: dto::LockingMode,
+    store: &'a dyn MetadataStore,
+    /// Released on drop
+    lock: Option<locking::HeldLock>,
 }
 
 impl<'a> LockedRoot<'a> {
-    fn new(path: &'a PathBuf, lock_file: &'a mut fs::File) -> Result<Self> {
+    fn new(
+        path: &'a PathBuf,
+        locking: dto::LockingMode,
+        store: &'a dyn MetadataStore,
+    ) -> Result<Self> {
         let mut locked_root = Self {
             path,
-            lock_file,
-            locked: false,
+            locking,
+            store,
+            lock: None,
         };
         locked_root.lock()?;
         Ok(locked_root)
@@ -86,202 +1195,316 @@ impl<'a> LockedRoot<'a> {
             target: LOG_TARGET,
             path = %self.path.display(), "Acquiring cache lock..."
         );
-        if self.lock_file.try_lock_exclusive().is_err() {
-            info!(
-                target: LOG_TARGET,
-                "Cache lock taken, waiting..."
-            );
-            self.lock_file.lock_exclusive()?;
+        let lock_path = root_lock_path(self.path);
+        let lock = match locking::try_lock_exclusive(self.locking, &lock_path)? {
+            Some(lock) => lock,
+            None => {
+                info!(
+                    target: LOG_TARGET,
+                    "Cache lock taken, waiting..."
+                );
+                locking::lock_exclusive(self.locking, &lock_path)?
+            }
         };
         debug!(
             target: LOG_TARGET,
             "Acquired cache lock"
         );
-        self.locked = true;
+        self.lock = Some(lock);
         Ok(())
     }
 
-    fn unlock(&mut self) -> Result<()> {
-        self.ensure_locked()?;
-        debug!(
-            target: LOG_TARGET,
-            path = %self.path.display(), "Releasing cache lock..."
-        );
-        self.lock_file.unlock()?;
-        self.locked = false;
-        Ok(())
+    fn data_file_path(&self) -> PathBuf {
+        data_file_path(self.path)
     }
 
-    fn data_file_path(&self) -> PathBuf {
-        self.path.join("fs-dir-cache.json")
+    pub fn record(&self, entry: journal::Entry) {
+        record(self.path, self.locking, entry);
     }
 
     fn ensure_locked(&self) -> anyhow::Result<()> {
-        if !self.locked {
+        if self.lock.is_none() {
             bail!("LockedRoot no longer valid");
         }
         Ok(())
     }
 
-    pub fn r#yield(&mut self, duration: Duration) -> Result<()> {
-        self.yield_with(|| {
-            thread::sleep(duration);
-        })
+    pub fn load_config(&self) -> Result<dto::RootConfig> {
+        load_config(self.path)
     }
 
-    pub fn r#yield_with<F, R>(&mut self, f: F) -> Result<R>
-    where
-        F: FnOnce() -> R,
-    {
-        self.unlock()?;
-        let r = f();
-        self.lock()?;
-        Ok(r)
+    fn backup_file_path(&self) -> PathBuf {
+        self.path.join("fs-dir-cache.json.bak")
     }
 
     pub fn load_data(&self) -> Result<dto::RootData> {
         self.ensure_locked()?;
         let path = self.data_file_path();
         if !path.try_exists()? {
-            return Ok(Default::default());
+            // right away, for older binaries to tell the root isn't for them
+            let data = dto::RootData::default();
+            self.write_data(&data)?;
+            return Ok(data);
+        }
+        match load_data_from(&path) {
+            Ok((mut data, migrated)) => {
+                if migrated {
+                    store::migrate_keys(self.store, &mut data)?;
+                    self.write_data(&data)?;
+                }
+                Ok(data)
+            }
+            // not corrupted, just too new for us
+            Err(err) if err.is::<migrate::IncompatibleVersionError>() => Err(err),
+            // not corrupted either, possibly a transient failure
+            Err(err) if is_io_error(&err) => Err(err),
+            Err(err) => self.recover_data(err),
         }
-        Ok(serde_json::from_reader::<_, _>(std::fs::File::open(path)?)?)
     }
 
-    pub fn store_data(&mut self, data: &dto::RootData) -> Result<()> {
-        util::store_json_pretty_to_file(&self.data_file_path(), data)
-    }
+    /// Recover from a corrupted data file, by using the backup of the
+    /// previous generation, or rebuilding from the key dirs if that fails too
+    fn recover_data(&self, err: anyhow::Error) -> Result<dto::RootData> {
+        let path = self.data_file_path();
+        error!(
+            target: LOG_TARGET,
+            err = %format!("{err:#}"),
+            path = %path.display(),
+            "Cache root data corrupted! Attempting recovery"
+        );
 
-    pub fn lock_key(
-        &mut self,
-        key: &str,
-        lock_id: &str,
-        timeout_secs: f64,
-        new_socket_path: Option<PathBuf>,
-    ) -> Result<PathBuf> {
-        let locking_start = Utc::now();
-        let mut had_to_wait = false;
-        let data = loop {
-            let mut data = self.load_data()?;
+        let backup = match load_data_from(&self.backup_file_path()) {
+            Err(backup_err)
+                if is_io_error(&backup_err)
+                    && backup_err.downcast_ref::<io::Error>().map(io::Error::kind)
+                        != Some(io::ErrorKind::NotFound) =>
+            {
+                return Err(backup_err.context("Failed to read the cache root data backup"));
+            }
+            backup => backup,
+        };
 
-            let now = Utc::now();
-            match data.keys.entry(key.to_owned()) {
-                Entry::Vacant(e) => {
-                    e.insert(
-                        dto::KeyData::new(now)
-                            .lock(now, lock_id, timeout_secs, new_socket_path.clone())?
-                            .to_owned(),
-                    );
-                    break data;
-                }
-                Entry::Occupied(mut e) => {
-                    if let Some(prev_sock_path) = e.get().socket_path.as_ref() {
-                        if let Ok(s) = try_lock(prev_sock_path) {
-                            info!(
-                                target: LOG_TARGET,
-                                key,
-                                lock_id,
-                                sock_path = %prev_sock_path.display(),
-                                "Previous lock holder still alive (potentially)"
-                            );
-                            had_to_wait |= true;
-                            self.r#yield_with(|| {
-                                let _ = clear_lock(s, prev_sock_path).inspect_err(|err| {
-                                    info!(
-                                        %err,
-                                        "Error during waiting for / clearing the old lock"
-                                    )
-                                });
-                            })?;
-                        } else {
-                            debug!(
-                                target: LOG_TARGET,
-                                key,
-                                lock_id,
-                                sock_path = %prev_sock_path.display(),
-                                "Previous lock holder gone"
-                            );
-                            rm_prev_sock_path(prev_sock_path);
-                            e.get_mut().lock(
-                                now,
-                                lock_id,
-                                timeout_secs,
-                                new_socket_path.clone(),
-                            )?;
-                            break data;
-                        }
-                    } else if !e.get().is_timelocked(now) {
-                        debug!(
-                            target: LOG_TARGET,
-                            key, lock_id, "Previous lock expired"
-                        );
-                        if let Some(prev_sock_path) = e.get().socket_path.as_ref() {
-                            rm_prev_sock_path(prev_sock_path);
-                        }
-                        e.get_mut()
-                            .lock(now, lock_id, timeout_secs, new_socket_path.clone())?;
-                        break data;
-                    } else {
-                        let expires_in_msecs = e.get().expires_in(now).num_milliseconds();
-                        let duration = Duration::from_millis(u64::expect_from(
-                            (expires_in_msecs / 100).clamp(10, 10000),
-                        ));
-                        info!(
-                            target: LOG_TARGET,
-                            key,
-                            lock_id,
-                            expires_in_msecs,
-                            "Waiting for the key lock to be released..."
-                        );
-                        had_to_wait |= true;
-                        self.r#yield(duration)?;
-                    }
-                }
+        // keep the corrupted file around for inspection
+        let corrupted_path =
+            path.with_extension(format!("json.corrupted-{}", Utc::now().timestamp()));
+        fs::rename(&path, &corrupted_path)?;
+        error!(
+            target: LOG_TARGET,
+            path = %corrupted_path.display(),
+            "Moved corrupted cache root data"
+        );
+
+        let (mut data, source) = match backup {
+            Ok((data, _migrated)) => {
+                error!(target: LOG_TARGET, "Recovered cache root data from the backup");
+                (data, dto::RecoverySource::Backup)
+            }
+            Err(backup_err) => {
+                error!(
+                    target: LOG_TARGET,
+                    err = %format!("{backup_err:#}"),
+                    "Cache root data backup unusable! Rebuilding from key dirs"
+                );
+                (self.rebuild_data()?, dto::RecoverySource::Rebuilt)
             }
         };
+        // backup could be from before keys were moved to their own files
+        store::migrate_keys(self.store, &mut data)?;
 
-        self.store_data(&data)?;
+        data.recoveries.push(dto::Recovery {
+            time: Utc::now(),
+            source,
+            error: format!("{err:#}"),
+        });
+        let excess = data.recoveries.len().saturating_sub(dto::MAX_RECOVERIES);
+        data.recoveries.drain(..excess);
 
-        if had_to_wait {
+        self.write_data(&data)?;
+        self.record(journal::Entry::root(journal::Event::DataRecovered {
+            source,
+        }));
+        Ok(data)
+    }
+
+    /// Reconstruct data from the key dirs present in the root, using their
+    /// mtime as the last lock time
+    ///
+    /// Only used for keys without their own metadata file.
+    fn rebuild_data(&self) -> Result<dto::RootData> {
+        let mut data = dto::RootData::default();
+        for entry in fs::read_dir(self.path)? {
+            let entry = entry?;
+            let Some(key) = entry.file_name().to_str().map(ToOwned::to_owned) else {
+                continue;
+            };
+            let metadata = entry.metadata()?;
+            if !metadata.is_dir() || !dto::is_key(&key) {
+                continue;
+            }
+            let mtime = metadata.modified()?.into();
             info!(
                 target: LOG_TARGET,
                 key,
-                lock_id,
-                wait_secs=%Utc::now().signed_duration_since(locking_start).num_seconds(),
-                "Acquired lock"
+                %mtime,
+                "Recovered key"
             );
+            data.keys.insert(key, dto::KeyData::new(mtime));
         }
-        Ok(self.path.join(key))
+        Ok(data)
     }
 
-    pub fn unlock_key(&mut self, key: &str, lock_id: String) -> Result<()> {
-        let mut data = self.load_data()?;
+    pub fn store_data(&mut self, data: &dto::RootData) -> Result<()> {
+        self.write_data(data)
+    }
 
-        if let Some(key_data) = data.keys.get_mut(key) {
-            if key_data.lock_id != lock_id {
-                bail!(
-                    "Key {} lock id does not match; used = {}, owner = {}",
-                    key,
-                    lock_id,
-                    key_data.lock_id
-                );
+    /// Store `data`, keeping the previous generation as a backup
+    fn write_data(&self, data: &dto::RootData) -> Result<()> {
+        let path = self.data_file_path();
+        let backup_path = self.backup_file_path();
+        if path.try_exists()? {
+            if let Err(err) = fs::remove_file(&backup_path) {
+                if err.kind() != io::ErrorKind::NotFound {
+                    return Err(err.into());
+                }
             }
-            let now = Utc::now();
-            if !key_data.is_timelocked(now) {
-                warn!(key, "Lock already expired");
+            if fs::hard_link(&path, &backup_path).is_err() {
+                fs::copy(&path, &backup_path)?;
             }
-            key_data.unlock(now);
-            self.store_data(&data)?;
-        } else {
-            bail!("Key {} does not exist", key);
         }
+        util::store_json_pretty_to_file(&path, data)
+    }
 
-        Ok(())
+    /// All the keys in the root
+    pub fn keys(&self) -> Result<Vec<String>> {
+        self.store.list_keys()
+    }
+
+    pub fn with_key_lock<T>(
+        &self,
+        key: &str,
+        f: impl FnOnce(&mut LockedKey) -> Result<T>,
+    ) -> Result<T> {
+        store::with_key_lock(self.store, key, f)
+    }
+
+    /// Data of all the keys in the root
+    pub fn load_keys(&self) -> Result<BTreeMap<String, dto::KeyData>> {
+        let mut keys = BTreeMap::new();
+        for key in self.keys()? {
+            if let Some(key_data) = self.with_key_lock(&key, |locked_key| locked_key.load())? {
+                keys.insert(key, key_data);
+            }
+        }
+        Ok(keys)
     }
 
     pub fn key_dir_path(&self, key: &str) -> PathBuf {
         self.path.join(key)
     }
+
+    /// Drop what's recorded about the dir of `key` besides its metadata (the
+    /// content manifest and sizes), once it's deleted
+    pub fn remove_key_files(&self, key: &str) -> Result<()> {
+        remove_file_if_exists(&manifest_file_path(self.path, key))?;
+        remove_file_if_exists(&usage_cache_path(self.path, key))?;
+        remove_file_if_exists(&shared_files_path(self.path, key))?;
+        Ok(())
+    }
+
+    /// Remove temporary files left behind by writers that crashed, returning
+    /// how many
+    pub fn remove_stale_tmp_files(&self) -> Result<u64> {
+        let mut removed = 0;
+        for dir in [
+            self.path.to_owned(),
+            self.path.join(".meta"),
+            self.path.join(".sizes"),
+            scratch_dir_path(self.path),
+        ] {
+            removed += util::remove_stale_tmp_files(&dir, STALE_TMP_FILE_AGE)
+                .with_context(|| format!("Failed to clean up {}", dir.display()))?;
+        }
+        Ok(removed)
+    }
+
+    /// Remove the scratch dirs of processes that are gone, returning how many
+    ///
+    /// Processes on other hosts can't be checked, so their dirs are kept.
+    pub fn remove_abandoned_scratch_dirs(&self) -> Result<u64> {
+        let scratch_root = scratch_dir_path(self.path);
+        let entries = match fs::read_dir(&scratch_root) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err.into()),
+        };
+        let mut names = HashSet::new();
+        for entry in entries {
+            let name = entry?.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            // see `remove_stale_tmp_files`
+            if let Some((name, rest)) = name.split_once('.') {
+                if rest.contains("tmp-") {
+                    continue;
+                }
+                names.insert(name.to_owned());
+            } else {
+                names.insert(name.to_owned());
+            }
+        }
+        let mut removed = 0;
+        for name in names {
+            let path = scratch_root.join(name);
+            let owner = fs::read(scratch_owner_path(&path))
+                .ok()
+                .and_then(|contents| serde_json::from_slice::<dto::OwnerProcess>(&contents).ok());
+            if owner.is_some_and(|owner| !owner.is_gone()) {
+                continue;
+            }
+            debug!(target: LOG_TARGET, path = %path.display(), "Removing abandoned scratch dir");
+            remove_scratch_dir(&path)?;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    /// Remove the notification sockets of killed waiters, returning how many
+    pub fn remove_stale_waiter_sockets(&self) -> Result<u64> {
+        let keys = self.load_keys()?;
+        let queued = keys
+            .values()
+            .flat_map(|key_data| &key_data.queue)
+            .map(|waiter| waiter.socket_path.as_path())
+            .collect();
+        Ok(waiter::remove_stale_sockets(self.path, &queued)?)
+    }
+
+    /// Dirs moved by [`trash_dir`] long ago, but never deleted
+    pub fn stale_trashed_dirs(&self) -> Result<Vec<PathBuf>> {
+        let entries = match fs::read_dir(trash_dir_path(self.path)) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err.into()),
+        };
+        let deadline = Utc::now() - STALE_TMP_FILE_AGE;
+        let mut stale = vec![];
+        for entry in entries {
+            let entry = entry?;
+            // moving sets the ctime
+            if entry.metadata()?.ctime() < deadline.timestamp() {
+                stale.push(entry.path());
+            }
+        }
+        Ok(stale)
+    }
+}
+
+/// Temporary files not written to for this long are considered abandoned
+const STALE_TMP_FILE_AGE: Duration = Duration::from_secs(3600);
+
+fn duration_to_ms(duration: chrono::Duration) -> u64 {
+    u64::try_from(duration.num_milliseconds()).unwrap_or_default()
 }
 
 fn rm_prev_sock_path(prev_sock_path: &Path) {
@@ -298,6 +1521,12 @@ fn rm_prev_sock_path(prev_sock_path: &Path) {
 // On Darwin Unix Sockets are not automatically removed, and linger,
 // with processes that try to connect to them just hanging. This makes them
 // unsuitable for our needs. Just use a file that we lock exclusively.
+/// Held for as long as the process is alive, see [`mk_lock`]
+#[cfg(target_os = "macos")]
+pub type LivenessLock = fs::File;
+#[cfg(not(target_os = "macos"))]
+pub type LivenessLock = UnixListener;
+
 #[cfg(target_os = "macos")]
 pub fn mk_lock(path: &Path) -> Result<fs::File> {
     let lock_file = fs::File::create(path)?;
@@ -1,26 +1,282 @@
 use std::collections::BTreeMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use chrono::{DateTime, Utc};
+use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
 
+use crate::util;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct KeyData {
     pub locked_until: chrono::DateTime<chrono::Utc>,
     pub lock_id: String,
+    /// Last time the key was used, i.e. locked by anything but maintenance
+    /// commands
     pub last_lock: chrono::DateTime<chrono::Utc>,
+    /// When the current (or last) lock was acquired, if it was by a
+    /// maintenance command; `last_lock` otherwise
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maintenance_lock: Option<DateTime<Utc>>,
     pub socket_path: Option<PathBuf>,
+    /// Process the lock is held for, released once it's gone
+    #[serde(default)]
+    pub owner: Option<OwnerProcess>,
+    /// Process that acquired the lock, for diagnostics only
+    #[serde(default)]
+    pub holder: Option<HolderInfo>,
+    /// Was the last lock released with `unlock` (as opposed to expiring)
+    #[serde(default)]
+    pub released: bool,
+    #[serde(default)]
+    pub stats: UsageStats,
+    /// Processes waiting for the lock, see [`KeyData::queue_head`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub queue: Vec<QueuedWaiter>,
+    #[serde(default)]
+    pub next_ticket: u64,
+    /// What was done with the dir after the last failed `exec`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_failure: Option<FailureRecord>,
+    /// When the dir was published with `exec --publish`; it's immutable
+    /// since then
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub published: Option<DateTime<Utc>>,
+    /// Processes using the published dir, which can't be evicted meanwhile
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub readers: Vec<Reader>,
+    /// Size of the dir, measured in the background after releases
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<DirSize>,
+    /// Fields unknown to this version, preserved when writing back
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QueuedWaiter {
+    /// Order of arrival
+    pub ticket: u64,
+    /// Waiters with higher priority get the lock first
+    pub priority: i32,
+    pub lock_id: String,
+    /// Notification socket of the waiting process, also telling if it's
+    /// still alive
+    pub socket_path: PathBuf,
+    /// Host of the waiting process, the socket is only usable there
+    #[serde(default)]
+    pub hostname: Option<String>,
+    /// Last time the waiter checked the key, telling if a waiter on another
+    /// host is still alive
+    #[serde(default)]
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+/// How often waiters refresh [`QueuedWaiter::last_seen`]
+const LAST_SEEN_REFRESH_SECS: i64 = 10;
+
+impl QueuedWaiter {
+    /// Is the waiter on this host (or from before hosts were recorded)
+    pub fn is_local(&self) -> bool {
+        self.hostname.is_none() || self.hostname == this_hostname()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct HolderInfo {
+    pub pid: u32,
+    pub hostname: Option<String>,
+    pub cmdline: Vec<String>,
+}
+
+impl HolderInfo {
+    pub fn new(pid: u32, cmdline: Vec<String>) -> Self {
+        Self {
+            pid,
+            hostname: this_hostname(),
+            cmdline,
+        }
+    }
+}
+
+fn this_hostname() -> Option<String> {
+    hostname::get()
+        .ok()
+        .map(|h| h.to_string_lossy().into_owned())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct OwnerProcess {
+    pub pid: u32,
+    /// Distinguishes the process from a later one that reused the pid,
+    /// where available
+    pub start_time: Option<u64>,
+    /// Liveness can only be checked on the same host
+    pub hostname: Option<String>,
+}
+
+impl OwnerProcess {
+    pub fn new(pid: u32) -> Self {
+        Self {
+            pid,
+            start_time: util::process_start_time(pid),
+            hostname: this_hostname(),
+        }
+    }
+
+    /// Is the process running on this host
+    pub fn is_local(&self) -> bool {
+        self.hostname.is_some() && self.hostname == this_hostname()
+    }
+
+    /// Is the process known to be gone
+    pub fn is_gone(&self) -> bool {
+        if !self.is_local() {
+            return false;
+        }
+        if !util::is_process_alive(self.pid) {
+            return true;
+        }
+        match (self.start_time, util::process_start_time(self.pid)) {
+            (Some(recorded), Some(current)) => recorded != current,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Reader {
+    pub lock_id: String,
+    /// Liveness socket of the reader
+    pub socket_path: PathBuf,
+    pub hostname: Option<String>,
+    pub since: DateTime<Utc>,
+}
+
+/// Readers on other hosts can't be checked, so are considered gone after
+/// this long
+const REMOTE_READER_LEASE_SECS: i64 = 24 * 60 * 60;
+
+impl Reader {
+    pub fn new(now: DateTime<Utc>, lock_id: &str, socket_path: &Path) -> Self {
+        Self {
+            lock_id: lock_id.to_owned(),
+            socket_path: socket_path.to_owned(),
+            hostname: this_hostname(),
+            since: now,
+        }
+    }
+
+    pub fn is_alive(&self, now: DateTime<Utc>) -> bool {
+        if self.hostname == this_hostname() {
+            super::try_lock(&self.socket_path).is_ok()
+        } else {
+            now.signed_duration_since(self.since)
+                < chrono::Duration::seconds(REMOTE_READER_LEASE_SECS)
+        }
+    }
+}
+
+/// Size of a key dir, as of `measured_at`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct DirSize {
+    pub measured_at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub usage: util::DiskUsage,
+    /// Part of `usage` in files also linked from outside the dir (see
+    /// `dedup`), listed in `<root>/.sizes/<key>.linked.json`
+    #[serde(default)]
+    pub shared: util::DiskUsage,
+}
+
+/// Hashes of everything in a key dir, checked by `verify`
+///
+/// Kept apart from [`KeyData`], in `<root>/.meta/<key>.manifest.json`, as
+/// it's as big as the dir is.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DirManifest {
+    pub recorded_at: DateTime<Utc>,
+    /// blake3 Merkle tree root of the whole dir
+    pub root_hash: String,
+    /// By path relative to the dir
+    pub entries: BTreeMap<String, ManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ManifestEntry {
+    Dir,
+    File { size: u64, blake3: String },
+    Symlink { target: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FailureRecord {
+    pub time: DateTime<Utc>,
+    pub lock_id: String,
+    pub action: OnFailure,
+    /// Where the dir was moved to, with [`OnFailure::Quarantine`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quarantine_path: Option<PathBuf>,
 }
 
 impl KeyData {
+    /// Was the dir deleted or moved away after a failed `exec`, since the
+    /// key was last locked
+    pub fn is_dir_dropped(&self) -> bool {
+        self.last_failure.as_ref().is_some_and(|failure| {
+            failure.action != OnFailure::Keep && self.last_lock <= failure.time
+        })
+    }
+
+    /// Is the published dir in use
+    pub fn has_live_readers(&self, now: DateTime<Utc>) -> bool {
+        self.readers.iter().any(|reader| reader.is_alive(now))
+    }
+
     pub fn is_timelocked(&self, now: DateTime<Utc>) -> bool {
         now < self.locked_until
     }
 
+    /// Is the lock held by anyone
+    pub fn is_locked(&self, now: DateTime<Utc>) -> bool {
+        (self.is_timelocked(now) && !self.is_owner_gone())
+            || self
+                .socket_path
+                .as_ref()
+                .is_some_and(|p| super::try_lock(p).is_ok())
+    }
+
+    /// When the dir could have last been modified
+    pub fn modified_at(&self) -> DateTime<Utc> {
+        self.published.unwrap_or(self.last_lock)
+    }
+
+    /// Was the dir possibly modified since its size was measured
+    pub fn is_size_stale(&self) -> bool {
+        match &self.size {
+            None => true,
+            Some(size) => size.measured_at < self.modified_at(),
+        }
+    }
+
     pub fn is_last_used_before(&self, deadline: DateTime<Utc>) -> bool {
         self.last_lock < deadline
     }
 
+    /// Was the lock acquired by a process on another host, so its liveness
+    /// socket can't be checked
+    pub fn is_held_remotely(&self) -> bool {
+        self.holder
+            .as_ref()
+            .is_some_and(|h| h.hostname.is_some() && h.hostname != this_hostname())
+    }
+
+    /// Was the lock held for an owner process that is now gone
+    pub fn is_owner_gone(&self) -> bool {
+        self.owner.as_ref().is_some_and(OwnerProcess::is_gone)
+    }
+
     pub fn expires_in(&self, now: DateTime<Utc>) -> chrono::Duration {
         self.locked_until.signed_duration_since(now)
     }
@@ -28,42 +284,486 @@ impl KeyData {
     pub fn lock(
         &mut self,
         now: DateTime<Utc>,
-        lock_id: &str,
-        timeout_secs: f64,
-        socket_path: Option<PathBuf>,
+        req: &super::LockRequest,
     ) -> anyhow::Result<&mut Self> {
-        self.locked_until = now
-            .checked_add_signed(chrono::Duration::milliseconds(
-                (timeout_secs * 1000.0).round() as i64,
-            ))
-            .ok_or_else(|| anyhow::format_err!("Timeout overflow"))?;
-        self.last_lock = now;
-        self.lock_id = lock_id.to_owned();
-        self.socket_path = socket_path;
+        self.locked_until = deadline(now, req.timeout_secs)?;
+        if req.maintenance {
+            self.maintenance_lock = Some(now);
+        } else {
+            self.last_lock = now;
+            self.maintenance_lock = None;
+        }
+        self.lock_id = req.lock_id.clone();
+        self.socket_path = req.socket_path.clone();
+        self.owner = req.owner.clone();
+        self.holder = Some(req.holder.clone());
+        self.released = false;
+
+        Ok(self)
+    }
+
+    /// When the current (or last) lock was acquired
+    pub fn locked_at(&self) -> DateTime<Utc> {
+        self.maintenance_lock.unwrap_or(self.last_lock)
+    }
 
+    /// Extend the lock to `timeout_secs` from `now`
+    pub fn renew(&mut self, now: DateTime<Utc>, timeout_secs: f64) -> anyhow::Result<&mut Self> {
+        self.locked_until = deadline(now, timeout_secs)?;
         Ok(self)
     }
 
     pub fn unlock(&mut self, now: DateTime<Utc>) -> &mut Self {
         self.locked_until = now;
+        self.socket_path = None;
+        self.owner = None;
+        self.released = true;
         debug_assert!(!self.is_timelocked(now));
         self
     }
 
+    /// Join the queue of waiters, or refresh the entry if already in it
+    ///
+    /// Returns `true` if the data changed.
+    pub fn enqueue(
+        &mut self,
+        now: DateTime<Utc>,
+        socket_path: &Path,
+        lock_id: &str,
+        priority: i32,
+    ) -> bool {
+        if let Some(waiter) = self.queue.iter_mut().find(|w| w.socket_path == socket_path) {
+            let refresh = match waiter.last_seen {
+                None => true,
+                Some(last_seen) => {
+                    chrono::Duration::seconds(LAST_SEEN_REFRESH_SECS)
+                        <= now.signed_duration_since(last_seen)
+                }
+            };
+            if refresh {
+                waiter.last_seen = Some(now);
+            }
+            return refresh;
+        }
+        self.queue.push(QueuedWaiter {
+            ticket: self.next_ticket,
+            priority,
+            lock_id: lock_id.to_owned(),
+            socket_path: socket_path.to_owned(),
+            hostname: this_hostname(),
+            last_seen: Some(now),
+        });
+        self.next_ticket += 1;
+        true
+    }
+
+    /// Leave the queue of waiters
+    ///
+    /// Returns `true` if the data changed.
+    pub fn dequeue(&mut self, socket_path: &Path) -> bool {
+        let len = self.queue.len();
+        self.queue.retain(|w| w.socket_path != socket_path);
+        self.queue.len() != len
+    }
+
+    /// The waiter that should get the lock next: highest priority first,
+    /// then in order of arrival
+    pub fn queue_head(&self) -> Option<&QueuedWaiter> {
+        self.queue
+            .iter()
+            .min_by_key(|w| (std::cmp::Reverse(w.priority), w.ticket))
+    }
+
     pub fn new(now: DateTime<Utc>) -> Self {
         let s = Self {
             locked_until: now,
             lock_id: "".to_owned(),
             last_lock: now,
+            maintenance_lock: None,
             socket_path: None,
+            owner: None,
+            holder: None,
+            released: true,
+            stats: UsageStats::default(),
+            queue: vec![],
+            next_ticket: 0,
+            last_failure: None,
+            published: None,
+            readers: vec![],
+            size: None,
+            extra: BTreeMap::new(),
         };
         debug_assert!(!s.is_timelocked(now));
         s
     }
 }
+
+fn deadline(now: DateTime<Utc>, timeout_secs: f64) -> anyhow::Result<DateTime<Utc>> {
+    now.checked_add_signed(chrono::Duration::milliseconds(
+        (timeout_secs * 1000.0).round() as i64,
+    ))
+    .ok_or_else(|| anyhow::format_err!("Timeout overflow"))
+}
+
+/// Base name of the key (`--key-name`), without the hash suffix
+pub fn key_name_of(key: &str) -> &str {
+    key.rsplit_once('-')
+        .map(|(name, _hash)| name)
+        .unwrap_or(key)
+}
+
+/// Key of an additional slot of `key`, used when `key` is busy
+///
+/// Slots are separate keys, with the same key name. Slot `0` is `key` itself.
+pub fn slot_key(key: &str, slot: u32) -> String {
+    if slot == 0 {
+        return key.to_owned();
+    }
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(key.as_bytes());
+    hasher.update(&slot.to_le_bytes());
+    format!("{}-{}", key_name_of(key), hasher.finalize().to_hex())
+}
+
+/// Can `name` be used as a key name: a single path component, that can't
+/// clash with the internal dirs of the root either
+pub fn is_key_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && !name.contains(['/', '\0'])
+        && !name.contains("..")
+}
+
+/// Does `name` look like a key: `<key-name>-<blake3 hex hash>`
+pub fn is_key(name: &str) -> bool {
+    name.rsplit_once('-').is_some_and(|(name, hash)| {
+        is_key_name(name)
+            && hash.len() == 64
+            && hash
+                .chars()
+                .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+    })
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct UsageStats {
+    /// Number of times the lock was acquired
+    pub acquisitions: u64,
+    /// Acquisitions of a key that already existed
+    pub hits: u64,
+    /// Acquisitions that created a fresh key
+    pub misses: u64,
+    pub wait_total_ms: u64,
+    pub wait_max_ms: u64,
+    /// Time between acquisition and `unlock`
+    pub held_total_ms: u64,
+    pub releases: u64,
+    pub lock_wait: Histogram,
+}
+
+impl UsageStats {
+    pub fn record_acquisition(&mut self, hit: bool, wait_ms: u64) {
+        self.acquisitions += 1;
+        if hit {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+        }
+        self.wait_total_ms += wait_ms;
+        self.wait_max_ms = self.wait_max_ms.max(wait_ms);
+        self.lock_wait.observe(wait_ms);
+    }
+
+    pub fn record_release(&mut self, held_ms: u64) {
+        self.releases += 1;
+        self.held_total_ms += held_ms;
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        self.acquisitions += other.acquisitions;
+        self.hits += other.hits;
+        self.misses += other.misses;
+        self.wait_total_ms += other.wait_total_ms;
+        self.wait_max_ms = self.wait_max_ms.max(other.wait_max_ms);
+        self.held_total_ms += other.held_total_ms;
+        self.releases += other.releases;
+        self.lock_wait.merge(&other.lock_wait);
+    }
+}
+
+/// Upper bounds (in seconds) of the lock wait time histogram buckets
+pub const LOCK_WAIT_BUCKETS_SECS: [f64; 9] = [0.01, 0.1, 0.5, 1.0, 5.0, 30.0, 60.0, 300.0, 1800.0];
+
+/// Cumulative histogram, with buckets matching [`LOCK_WAIT_BUCKETS_SECS`]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Histogram {
+    pub buckets: Vec<u64>,
+    pub count: u64,
+    pub sum_ms: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, ms: u64) {
+        self.buckets.resize(LOCK_WAIT_BUCKETS_SECS.len(), 0);
+        for (bucket, le) in self.buckets.iter_mut().zip(LOCK_WAIT_BUCKETS_SECS) {
+            if ms as f64 / 1000.0 <= le {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum_ms += ms;
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        self.buckets.resize(LOCK_WAIT_BUCKETS_SECS.len(), 0);
+        for (bucket, other) in self.buckets.iter_mut().zip(&other.buckets) {
+            *bucket += other;
+        }
+        self.count += other.count;
+        self.sum_ms += other.sum_ms;
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GcRun {
+    pub finished: DateTime<Utc>,
+    pub duration_ms: u64,
+}
+
+/// Root-wide statistics
+///
+/// Statistics of existing keys are kept in their [`KeyData`].
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Stats {
+    /// Accumulated statistics of keys that no longer exist, per key name
+    pub key_names: BTreeMap<String, UsageStats>,
+    /// Number of keys deleted by GC
+    pub evictions: u64,
+    pub last_gc: Option<GcRun>,
+}
+
+impl Stats {
+    pub fn record_eviction(&mut self, key: &str, key_stats: &UsageStats) {
+        self.key_names
+            .entry(key_name_of(key).to_owned())
+            .or_default()
+            .merge(key_stats);
+        self.evictions += 1;
+    }
+
+    /// Statistics per key name, including existing `keys`
+    pub fn key_name_totals(
+        &self,
+        keys: &BTreeMap<String, KeyData>,
+    ) -> BTreeMap<String, UsageStats> {
+        let mut totals = self.key_names.clone();
+        for (key, key_data) in keys {
+            totals
+                .entry(key_name_of(key).to_owned())
+                .or_default()
+                .merge(&key_data.stats);
+        }
+        totals
+    }
+}
+
+/// Schema version of [`RootData`] written by this binary
+pub const CURRENT_VERSION: u32 = 2;
+
+/// Oldest schema version a binary must support to be able to correctly
+/// read and update data written by this binary
+///
+/// Only needs bumping on incompatible changes, i.e. ones that older binaries
+/// can't handle by just ignoring and preserving unknown fields.
+pub const MIN_READER_VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "snake_case")]
 /// Persistent data file at `<root>/fs_dir_cache.json`
+///
+/// Data of individual keys is stored separately, in `<root>/.meta/<key>.json`.
 pub struct RootData {
+    /// Schema version, `0` for files written before versioning was introduced
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default)]
+    pub min_reader_version: u32,
+    /// Keys stored in the root data file before version 2, moved to their
+    /// own files on migration
+    ///
+    /// Written as [`KEYS_MOVED`] when empty, for binaries predating schema
+    /// versioning: they require the field, and fail on it saying why.
+    #[serde(default, with = "legacy_keys")]
     pub keys: BTreeMap<String, KeyData>,
+    #[serde(default)]
+    pub stats: Stats,
+    /// Past recoveries from a corrupted data file, most recent last
+    #[serde(default)]
+    pub recoveries: Vec<Recovery>,
+    /// Fields unknown to this version, preserved when writing back
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, serde_json::Value>,
 }
+
+/// Placeholder of [`RootData::keys`]
+pub const KEYS_MOVED: &str =
+    "keys are stored in .meta/ since schema version 2, please upgrade fs-dir-cache";
+
+mod legacy_keys {
+    use std::collections::BTreeMap;
+
+    use serde::{Deserialize as _, Deserializer, Serialize as _, Serializer};
+
+    use super::{KeyData, KEYS_MOVED};
+
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum Keys {
+        Keys(BTreeMap<String, KeyData>),
+        Moved(String),
+    }
+
+    pub fn serialize<S: Serializer>(
+        keys: &BTreeMap<String, KeyData>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if keys.is_empty() {
+            KEYS_MOVED.serialize(serializer)
+        } else {
+            keys.serialize(serializer)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<String, KeyData>, D::Error> {
+        match Keys::deserialize(deserializer)? {
+            Keys::Keys(keys) => Ok(keys),
+            Keys::Moved(text) if text == KEYS_MOVED => Ok(BTreeMap::new()),
+            Keys::Moved(text) => Err(serde::de::Error::custom(format!(
+                "unexpected `keys`: {text}"
+            ))),
+        }
+    }
+}
+
+/// Number of [`Recovery`] records to keep
+pub const MAX_RECOVERIES: usize = 10;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RecoverySource {
+    /// Loaded from the backup of the previous generation
+    Backup,
+    /// Rebuilt from the key dirs present in the root
+    Rebuilt,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Recovery {
+    pub time: DateTime<Utc>,
+    pub source: RecoverySource,
+    /// Why the data file could not be loaded
+    pub error: String,
+}
+
+impl Default for RootData {
+    fn default() -> Self {
+        Self {
+            version: CURRENT_VERSION,
+            min_reader_version: MIN_READER_VERSION,
+            keys: BTreeMap::new(),
+            stats: Stats::default(),
+            recoveries: vec![],
+            extra: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+/// Per-root settings at `<root>/fs-dir-cache.config.json`
+pub struct RootConfig {
+    #[serde(default)]
+    pub metadata_store: MetadataStoreKind,
+    #[serde(default)]
+    pub locking: LockingMode,
+    /// Size limit of each key dir, checked when it's released
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_key_bytes: Option<u64>,
+    #[serde(default)]
+    pub over_quota: OverQuota,
+    /// Fields unknown to this version, preserved when writing back
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, serde_json::Value>,
+}
+
+/// `Display` a [`ValueEnum`] as its value on the command line
+macro_rules! display_as_value_enum {
+    ($ty:ty) => {
+        impl std::fmt::Display for $ty {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str(
+                    self.to_possible_value()
+                        .expect("no skipped variants")
+                        .get_name(),
+                )
+            }
+        }
+    };
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum MetadataStoreKind {
+    /// A JSON file per key, in `<root>/.meta`
+    #[default]
+    Json,
+    /// A single SQLite database at `<root>/fs-dir-cache.sqlite`
+    Sqlite,
+}
+
+display_as_value_enum!(MetadataStoreKind);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum LockingMode {
+    /// `flock` and liveness sockets, for local file systems
+    #[default]
+    Flock,
+    /// `link`-based lockfiles with leases, for network file systems shared
+    /// between hosts
+    Lockfile,
+}
+
+display_as_value_enum!(LockingMode);
+
+/// What to do with a key dir over [`RootConfig::max_key_bytes`]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum OverQuota {
+    /// Delete it
+    #[default]
+    Discard,
+    /// Delete the least recently accessed files, until it fits
+    Trim,
+}
+
+display_as_value_enum!(OverQuota);
+
+/// What to do with the dir of a key after a failed `exec`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum OnFailure {
+    /// Keep it as it is
+    #[default]
+    Keep,
+    /// Delete it
+    Discard,
+    /// Move it to `<root>/.quarantine`, for inspection
+    Quarantine,
+}
+
+display_as_value_enum!(OnFailure);
@@ -0,0 +1,198 @@
+//! Append-only JSON-lines journal of events happening in the root, for
+//! post-mortem debugging
+//!
+//! Written to by any process using the root, mostly without holding the
+//! root lock, so appends and rotation take a lock of their own.
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::dto::{LockingMode, OnFailure, OverQuota, RecoverySource};
+use super::locking;
+
+/// Size after which the journal gets rotated into `<journal>.1`
+pub const MAX_BYTES: u64 = 8 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    LockRequested,
+    LockAcquired {
+        hit: bool,
+        waited_ms: u64,
+    },
+    LockReleased {
+        held_ms: u64,
+    },
+    /// Previous holder didn't unlock before its timeout
+    LockExpired {
+        prev_lock_id: String,
+    },
+    /// Previous holder's liveness socket or owner process is gone
+    LockStolen {
+        prev_lock_id: String,
+    },
+    UnlockMismatch {
+        owner_lock_id: String,
+    },
+    GcEvicted,
+    /// Dir populated by `exec --publish` was moved into place
+    Published,
+    /// `exec` command failed, and the dir was dealt with according to
+    /// `--on-failure`
+    ExecFailed {
+        action: OnFailure,
+    },
+    /// Dir was over `--max-key-bytes` when released
+    OverQuota {
+        bytes: u64,
+        max_bytes: u64,
+        action: OverQuota,
+        /// Less than `bytes - max_bytes` if the dir couldn't be discarded,
+        /// as it's in use
+        freed_bytes: u64,
+    },
+    /// `verify` found the dir not matching its manifest
+    Corrupted {
+        modified: usize,
+        missing: usize,
+        extra: usize,
+        discarded: bool,
+    },
+    /// Missing dir was populated from the remote cache
+    RemoteDownloaded,
+    /// Dir was uploaded to the remote cache after `exec` succeeded
+    RemoteUploaded,
+    /// Root data file was corrupted and had to be recovered
+    DataRecovered {
+        source: RecoverySource,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Entry {
+    pub time: DateTime<Utc>,
+    /// Process that recorded the event
+    pub pid: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lock_id: Option<String>,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+impl Entry {
+    pub fn new(key: &str, lock_id: Option<&str>, event: Event) -> Self {
+        Self {
+            time: Utc::now(),
+            pid: std::process::id(),
+            key: Some(key.to_owned()),
+            lock_id: lock_id.map(ToOwned::to_owned),
+            event,
+        }
+    }
+}
+
+impl Entry {
+    /// Entry not related to any particular key
+    pub fn root(event: Event) -> Self {
+        Self {
+            time: Utc::now(),
+            pid: std::process::id(),
+            key: None,
+            lock_id: None,
+            event,
+        }
+    }
+}
+
+pub fn rotated_path(path: &Path) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(".1");
+    rotated.into()
+}
+
+/// Append `entry`, rotating the journal first if it got too big
+///
+/// Holds `<journal>.lock` meanwhile, so no entry is appended to a journal
+/// that is being rotated away, and it's not rotated twice.
+pub fn append(path: &Path, locking: LockingMode, entry: &Entry) -> anyhow::Result<()> {
+    let _lock = locking::lock_exclusive(locking, &path.with_extension("lock"))?;
+    if path
+        .metadata()
+        .is_ok_and(|metadata| MAX_BYTES <= metadata.len())
+    {
+        fs::rename(path, rotated_path(path))?;
+    }
+
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(&line)?;
+    Ok(())
+}
+
+/// Read all the entries, starting from the rotated journal
+///
+/// Lines that fail to parse (e.g. truncated by a crash) are skipped.
+pub fn read_all(path: &Path) -> anyhow::Result<Vec<Entry>> {
+    let mut entries = vec![];
+    for path in [rotated_path(path), path.to_owned()] {
+        let file = match fs::File::open(&path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        };
+        for line in io::BufReader::new(file).lines() {
+            if let Ok(entry) = serde_json::from_str(&line?) {
+                entries.push(entry);
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Call `f` with every entry appended to the journal from now on
+///
+/// Never returns, unless an error occurs.
+pub fn follow(path: &Path, mut f: impl FnMut(Entry)) -> anyhow::Result<()> {
+    let mut offset = path.metadata().map(|m| m.len()).unwrap_or_default();
+    let mut partial = String::new();
+    loop {
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        let file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        };
+        let len = file.metadata()?.len();
+        if len < offset {
+            // journal got rotated
+            offset = 0;
+            partial.clear();
+        }
+        let mut reader = io::BufReader::new(file);
+        io::Seek::seek(&mut reader, io::SeekFrom::Start(offset))?;
+        loop {
+            let read = reader.read_line(&mut partial)?;
+            if read == 0 {
+                break;
+            }
+            offset += u64::try_from(read)?;
+            if !partial.ends_with('\n') {
+                break;
+            }
+            if let Ok(entry) = serde_json::from_str(&partial) {
+                f(entry);
+            }
+            partial.clear();
+        }
+    }
+}
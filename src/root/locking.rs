@@ -0,0 +1,300 @@
+//! Exclusive locks on paths, either with `flock` or, for network file
+//! systems where `flock` is unreliable, with `link`-based lockfiles
+//!
+//! A lockfile is created by hard-linking a uniquely named file with the
+//! owner info into place, which is atomic even on NFS. The owner keeps
+//! touching it while holding the lock, so a lock held by a crashed process
+//! on another host can be broken once its lease runs out.
+use std::io::{self, Write as _};
+use std::os::unix::fs::MetadataExt as _;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, SystemTime};
+use std::{fs, thread};
+
+use anyhow::Result;
+use fs2::FileExt as _;
+use rand::distributions::{Alphanumeric, DistString};
+use tracing::{debug, warn};
+
+use super::dto::{LockingMode, OwnerProcess};
+use crate::{util, LOG_TARGET};
+
+/// Lockfile not touched by its owner for this long is considered stale
+pub const LEASE: Duration = Duration::from_secs(60);
+
+/// How often the owner touches a held lockfile
+const HEARTBEAT: Duration = Duration::from_secs(10);
+
+/// Longest delay between attempts to acquire a taken lockfile
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+/// A held lock, released on drop
+pub enum HeldLock {
+    Flock {
+        /// Lock is released when the file gets closed
+        _file: fs::File,
+    },
+    Lockfile {
+        _lockfile: Lockfile,
+    },
+}
+
+/// Acquire the lock on `path`, waiting for it if needed
+pub fn lock_exclusive(mode: LockingMode, path: &Path) -> Result<HeldLock> {
+    Ok(match mode {
+        LockingMode::Flock => HeldLock::Flock {
+            _file: flock_exclusive(path, true)?.expect("blocking"),
+        },
+        LockingMode::Lockfile => {
+            let mut backoff = Duration::from_millis(10);
+            loop {
+                if let Some(lockfile) = Lockfile::try_acquire(path)? {
+                    break HeldLock::Lockfile {
+                        _lockfile: lockfile,
+                    };
+                }
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    })
+}
+
+/// Acquire the lock on `path` if it's free
+pub fn try_lock_exclusive(mode: LockingMode, path: &Path) -> Result<Option<HeldLock>> {
+    Ok(match mode {
+        LockingMode::Flock => {
+            flock_exclusive(path, false)?.map(|file| HeldLock::Flock { _file: file })
+        }
+        LockingMode::Lockfile => Lockfile::try_acquire(path)?.map(|lockfile| HeldLock::Lockfile {
+            _lockfile: lockfile,
+        }),
+    })
+}
+
+/// Open and exclusively lock the file at `path`
+///
+/// Lock files of deleted keys get removed, so after acquiring the lock
+/// this checks that the file wasn't removed (and possibly re-created) in the
+/// meantime, and retries if it was.
+fn flock_exclusive(path: &Path, block: bool) -> Result<Option<fs::File>> {
+    loop {
+        let file = util::open_lock_file_at(path)?;
+        if block {
+            file.lock_exclusive()?;
+        } else if file.try_lock_exclusive().is_err() {
+            return Ok(None);
+        }
+        let locked = file.metadata()?;
+        match fs::metadata(path) {
+            Ok(current) if current.dev() == locked.dev() && current.ino() == locked.ino() => {
+                return Ok(Some(file));
+            }
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        debug!(
+            target: LOG_TARGET,
+            path = %path.display(), "Lock file replaced while waiting, retrying"
+        );
+    }
+}
+
+/// Path of the lockfile used for a lock on `path`
+///
+/// Different from the `flock` one, so the two can't be confused.
+fn lockfile_path(path: &Path) -> PathBuf {
+    let mut lockfile_path = path.as_os_str().to_owned();
+    lockfile_path.push(".lockfile");
+    lockfile_path.into()
+}
+
+pub struct Lockfile {
+    path: PathBuf,
+    owner: OwnerProcess,
+    /// Dropping it stops the heartbeat thread
+    stop_heartbeat: Option<mpsc::Sender<()>>,
+    heartbeat: Option<thread::JoinHandle<()>>,
+}
+
+impl Lockfile {
+    fn try_acquire(path: &Path) -> Result<Option<Self>> {
+        let path = lockfile_path(path);
+        let owner = OwnerProcess::new(std::process::id());
+
+        let mut unique_name = path.as_os_str().to_owned();
+        unique_name.push(format!(
+            ".{}-{}",
+            owner.hostname.as_deref().unwrap_or("unknown"),
+            Alphanumeric.sample_string(&mut rand::thread_rng(), 8)
+        ));
+        let unique_path = PathBuf::from(unique_name);
+        let mut file = fs::File::create(&unique_path)?;
+        file.write_all(&serde_json::to_vec(&owner)?)?;
+        file.sync_all()?;
+        drop(file);
+
+        let res = link_into_place(&unique_path, &path);
+        if let Err(err) = fs::remove_file(&unique_path) {
+            warn!(target: LOG_TARGET, %err, path = %unique_path.display(), "Could not remove temporary lockfile");
+        }
+        if !res? {
+            if is_stale(&path)? {
+                break_stale(&path)?;
+            }
+            return Ok(None);
+        }
+
+        let (stop_tx, stop_rx) = mpsc::channel::<()>();
+        let heartbeat_path = path.clone();
+        let heartbeat = thread::spawn(move || {
+            while let Err(mpsc::RecvTimeoutError::Timeout) = stop_rx.recv_timeout(HEARTBEAT) {
+                if let Err(err) = touch(&heartbeat_path) {
+                    warn!(target: LOG_TARGET, %err, path = %heartbeat_path.display(), "Failed to renew lockfile lease");
+                }
+            }
+        });
+        Ok(Some(Self {
+            path,
+            owner,
+            stop_heartbeat: Some(stop_tx),
+            heartbeat: Some(heartbeat),
+        }))
+    }
+}
+
+impl Drop for Lockfile {
+    fn drop(&mut self) {
+        drop(self.stop_heartbeat.take());
+        if let Some(heartbeat) = self.heartbeat.take() {
+            let _ = heartbeat.join();
+        }
+        // moved aside first, as the lock could have been broken as stale and
+        // taken by someone else, even right after checking the owner in place
+        let aside = match move_aside(&self.path, "release") {
+            Ok(Some(aside)) => aside,
+            Ok(None) => {
+                warn!(target: LOG_TARGET, path = %self.path.display(), "Lockfile was broken while held");
+                return;
+            }
+            Err(err) => {
+                warn!(target: LOG_TARGET, %err, path = %self.path.display(), "Failed to remove lockfile");
+                return;
+            }
+        };
+        let still_ours = fs::read(&aside)
+            .ok()
+            .and_then(|contents| serde_json::from_slice::<OwnerProcess>(&contents).ok())
+            .is_some_and(|owner| owner == self.owner);
+        if !still_ours {
+            warn!(target: LOG_TARGET, path = %self.path.display(), "Lockfile was taken over while held");
+            put_back(&aside, &self.path);
+            return;
+        }
+        if let Err(err) = fs::remove_file(&aside) {
+            warn!(target: LOG_TARGET, %err, path = %aside.display(), "Failed to remove lockfile");
+        }
+    }
+}
+
+/// Rename the lockfile at `path` to a unique name, so only the caller can
+/// inspect and remove it, returning `None` if there's none
+fn move_aside(path: &Path, purpose: &str) -> io::Result<Option<PathBuf>> {
+    let mut aside = path.as_os_str().to_owned();
+    aside.push(format!(
+        ".{purpose}-{}",
+        Alphanumeric.sample_string(&mut rand::thread_rng(), 8)
+    ));
+    let aside = PathBuf::from(aside);
+    match fs::rename(path, &aside) {
+        Ok(()) => Ok(Some(aside)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Undo [`move_aside`] of a lockfile that turned out not to be ours to
+/// remove, unless the lock got taken again meanwhile
+fn put_back(aside: &Path, path: &Path) {
+    match fs::hard_link(aside, path) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(err) => {
+            warn!(target: LOG_TARGET, %err, path = %path.display(), "Failed to put back lockfile");
+        }
+    }
+    if let Err(err) = fs::remove_file(aside) {
+        warn!(target: LOG_TARGET, %err, path = %aside.display(), "Could not remove temporary lockfile");
+    }
+}
+
+/// Returns `false` if the lock is taken
+fn link_into_place(unique_path: &Path, path: &Path) -> Result<bool> {
+    match fs::hard_link(unique_path, path) {
+        Ok(()) => Ok(true),
+        Err(err) => {
+            // over NFS `link` can succeed, but report failure (e.g. when the
+            // reply got lost), so check the link count to be sure
+            if fs::metadata(unique_path)?.nlink() == 2 {
+                return Ok(true);
+            }
+            if err.kind() == io::ErrorKind::AlreadyExists {
+                Ok(false)
+            } else {
+                Err(err.into())
+            }
+        }
+    }
+}
+
+fn touch(path: &Path) -> io::Result<()> {
+    fs::File::options()
+        .write(true)
+        .open(path)?
+        .set_modified(SystemTime::now())
+}
+
+/// Is the lockfile owned by a process that's gone, or not renewed for
+/// longer than the lease
+fn is_stale(path: &Path) -> Result<bool> {
+    let (metadata, contents) = match fs::metadata(path).and_then(|m| Ok((m, fs::read(path)?))) {
+        Ok(res) => res,
+        // released in the meantime
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err.into()),
+    };
+    if let Ok(owner) = serde_json::from_slice::<OwnerProcess>(&contents) {
+        if owner.is_gone() {
+            debug!(target: LOG_TARGET, path = %path.display(), ?owner, "Lockfile owner gone");
+            return Ok(true);
+        }
+    }
+    let age = SystemTime::now()
+        .duration_since(metadata.modified()?)
+        .unwrap_or_default();
+    if LEASE < age {
+        debug!(target: LOG_TARGET, path = %path.display(), ?age, "Lockfile lease expired");
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+/// Remove a stale lockfile
+///
+/// Renaming it away first makes sure only one of the processes that
+/// noticed it's stale removes it, and that it's still the stale one.
+fn break_stale(path: &Path) -> Result<()> {
+    let Some(stale_path) = move_aside(path, "stale")? else {
+        return Ok(());
+    };
+    if !is_stale(&stale_path)? {
+        // released and taken again since it was checked
+        put_back(&stale_path, path);
+        return Ok(());
+    }
+    warn!(target: LOG_TARGET, path = %path.display(), "Broke stale lockfile");
+    fs::remove_file(&stale_path)?;
+    Ok(())
+}
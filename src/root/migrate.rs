@@ -0,0 +1,155 @@
+//! Migrations of the `fs-dir-cache.json` schema
+//!
+//! Operate on raw json, so they can deal with data that no longer matches
+//! [`RootData`].
+use std::fmt;
+
+use anyhow::{Context as _, Result};
+use serde_json::Value;
+use tracing::info;
+
+use super::dto::{key_name_of, RootData, CURRENT_VERSION, MIN_READER_VERSION};
+use crate::LOG_TARGET;
+
+/// Data was written by a newer, incompatible version of fs-dir-cache
+#[derive(Debug)]
+pub struct IncompatibleVersionError {
+    version: u32,
+    min_reader_version: u32,
+}
+
+impl fmt::Display for IncompatibleVersionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Cache root data uses schema version {}, which requires fs-dir-cache supporting at least schema version {}, but this binary only supports up to version {CURRENT_VERSION}. Please upgrade fs-dir-cache.",
+            self.version, self.min_reader_version
+        )
+    }
+}
+
+impl std::error::Error for IncompatibleVersionError {}
+
+/// Migration at index `i` upgrades data from version `i` to `i + 1`
+const MIGRATIONS: &[fn(&mut Value) -> Result<()>] = &[migrate_v0_to_v1, migrate_v1_to_v2];
+
+/// Versioning introduced, nothing else changed
+fn migrate_v0_to_v1(_data: &mut Value) -> Result<()> {
+    Ok(())
+}
+
+/// Keys moved to per-key files (done by the caller, as it requires file
+/// system access), and so did their statistics
+///
+/// `key_names` now only accumulate keys that no longer exist, so the
+/// statistics of existing keys are taken out of them. The root-wide lock wait
+/// histogram is dropped, as it can't be attributed to key names.
+fn migrate_v1_to_v2(data: &mut Value) -> Result<()> {
+    let Some(stats) = data.get_mut("stats").and_then(Value::as_object_mut) else {
+        return Ok(());
+    };
+    let (key_stats, lock_wait) = (stats.remove("keys"), stats.remove("lock_wait"));
+    if let Some(lock_wait) = lock_wait {
+        let count = lock_wait.get("count").and_then(Value::as_u64);
+        info!(
+            target: LOG_TARGET,
+            count, "Dropping the root-wide lock wait histogram"
+        );
+    }
+    let Some(Value::Object(key_stats)) = key_stats else {
+        return Ok(());
+    };
+    for (key, key_stats) in key_stats {
+        let Some(key_data) = data
+            .get_mut("keys")
+            .and_then(|keys| keys.get_mut(&key))
+            .and_then(Value::as_object_mut)
+        else {
+            // evicted already
+            continue;
+        };
+        key_data.insert("stats".into(), key_stats.clone());
+        let name_stats = data
+            .get_mut("stats")
+            .and_then(|stats| stats.get_mut("key_names"))
+            .and_then(|key_names| key_names.get_mut(key_name_of(&key)))
+            .and_then(Value::as_object_mut);
+        for (field, total) in name_stats.into_iter().flatten() {
+            // maxima can't be taken apart
+            if field == "wait_max_ms" {
+                continue;
+            }
+            if let (Some(total_value), Some(key_value)) =
+                (total.as_u64(), key_stats.get(field).and_then(Value::as_u64))
+            {
+                *total = total_value.saturating_sub(key_value).into();
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Peek at the data file, without locking and fully parsing it
+///
+/// Returns `true` if the data needs migrating. Fails if the data is from a
+/// newer incompatible version.
+pub fn check(data: &Value) -> Result<bool> {
+    let version = get_u32(data, "version")?;
+    check_min_reader_version(version, get_u32(data, "min_reader_version")?)?;
+    Ok(version < CURRENT_VERSION)
+}
+
+fn check_min_reader_version(version: u32, min_reader_version: u32) -> Result<()> {
+    if CURRENT_VERSION < min_reader_version {
+        return Err(IncompatibleVersionError {
+            version,
+            min_reader_version,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+fn get_u32(data: &Value, field: &str) -> Result<u32> {
+    Ok(match data.get(field) {
+        None => 0,
+        Some(v) => v
+            .as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .with_context(|| format!("Invalid `{field}` value: {v}"))?,
+    })
+}
+
+/// Parse root data, applying any necessary migrations
+///
+/// Returns the data, and whether it was migrated (and thus should be
+/// stored).
+pub fn parse(mut data: Value) -> Result<(RootData, bool)> {
+    let version = get_u32(&data, "version")?;
+    let min_reader_version = get_u32(&data, "min_reader_version")?;
+
+    check_min_reader_version(version, min_reader_version)?;
+
+    let migrated = version < CURRENT_VERSION;
+    for from_version in version..CURRENT_VERSION {
+        info!(
+            target: LOG_TARGET,
+            from_version,
+            to_version = from_version + 1,
+            "Migrating cache root data"
+        );
+        MIGRATIONS[usize::try_from(from_version)?](&mut data)
+            .with_context(|| format!("Migration from version {from_version} failed"))?;
+        if let Some(obj) = data.as_object_mut() {
+            obj.insert("version".into(), (from_version + 1).into());
+        }
+    }
+
+    let mut data: RootData = serde_json::from_value(data)?;
+    // never downgrade data written by a newer binary
+    data.version = data.version.max(CURRENT_VERSION);
+    let migrated = migrated || data.min_reader_version < MIN_READER_VERSION;
+    data.min_reader_version = data.min_reader_version.max(MIN_READER_VERSION);
+
+    Ok((data, migrated))
+}
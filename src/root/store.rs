@@ -0,0 +1,158 @@
+//! Storage of per-key metadata
+//!
+//! Backend is selected per root, in its [`RootConfig`].
+mod json;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+use std::path::Path;
+
+use anyhow::Result;
+
+use super::dto::{KeyData, MetadataStoreKind, RootConfig, RootData};
+
+pub trait MetadataStore {
+    /// All the keys that have metadata
+    fn list_keys(&self) -> Result<Vec<String>>;
+
+    /// Does `key` have metadata, checked without locking (or creating) anything
+    fn contains_key(&self, key: &str) -> Result<bool>;
+
+    /// Start a transaction with exclusive access to the metadata of `key`
+    ///
+    /// Dropping the transaction without calling
+    /// [`KeyTransaction::commit`] discards changes, where supported.
+    /// Transactions on different keys can be held at the same time, and
+    /// should then be committed in the reverse order.
+    fn begin(&self, key: &str) -> Result<Box<dyn KeyTransaction + '_>>;
+}
+
+pub trait KeyTransaction {
+    fn load(&mut self) -> Result<Option<KeyData>>;
+
+    fn upsert(&mut self, data: &KeyData) -> Result<()>;
+
+    fn delete(&mut self) -> Result<()>;
+
+    fn commit(self: Box<Self>) -> Result<()>;
+}
+
+pub fn open(root_path: &Path, config: &RootConfig) -> Result<Box<dyn MetadataStore>> {
+    Ok(match config.metadata_store {
+        MetadataStoreKind::Json => Box::new(json::JsonStore::new(root_path, config.locking)),
+        // SQLite's own locking is not reliable on network file systems
+        #[cfg(feature = "sqlite")]
+        MetadataStoreKind::Sqlite if config.locking == super::dto::LockingMode::Lockfile => {
+            anyhow::bail!("SQLite metadata store can't be used with `lockfile` locking")
+        }
+        #[cfg(feature = "sqlite")]
+        MetadataStoreKind::Sqlite => Box::new(sqlite::SqliteStore::open(root_path)?),
+        #[cfg(not(feature = "sqlite"))]
+        MetadataStoreKind::Sqlite => {
+            anyhow::bail!("Root uses SQLite metadata store, but this build does not support it (`sqlite` feature disabled)")
+        }
+    })
+}
+
+/// Run `f` within a transaction on `key`, committing if it succeeded
+pub fn with_key_lock<T>(
+    store: &dyn MetadataStore,
+    key: &str,
+    f: impl FnOnce(&mut LockedKey) -> Result<T>,
+) -> Result<T> {
+    let mut locked_key = LockedKey {
+        tx: store.begin(key)?,
+    };
+    let res = f(&mut locked_key)?;
+    locked_key.tx.commit()?;
+    Ok(res)
+}
+
+/// Run `f` within transactions on all the `keys` at once, committing if it
+/// succeeded
+///
+/// Transactions are started in a sorted order of keys, so concurrent calls
+/// can't deadlock, and passed to `f` in the order of `keys`.
+pub fn with_keys_lock<T>(
+    store: &dyn MetadataStore,
+    keys: &[&str],
+    f: impl FnOnce(&mut [LockedKey]) -> Result<T>,
+) -> Result<T> {
+    let mut order: Vec<usize> = (0..keys.len()).collect();
+    order.sort_by_key(|&i| keys[i]);
+    let mut txs: Vec<Option<Box<dyn KeyTransaction + '_>>> = keys.iter().map(|_| None).collect();
+    for &i in &order {
+        txs[i] = Some(store.begin(keys[i])?);
+    }
+    let mut locked_keys: Vec<LockedKey> = txs
+        .into_iter()
+        .map(|tx| LockedKey {
+            tx: tx.expect("all keys began"),
+        })
+        .collect();
+    let res = f(&mut locked_keys)?;
+    let mut locked_keys: Vec<Option<LockedKey>> = locked_keys.into_iter().map(Some).collect();
+    for &i in order.iter().rev() {
+        locked_keys[i].take().expect("committed once").tx.commit()?;
+    }
+    Ok(res)
+}
+
+/// A handle passed to `with_key_lock` argument after the key was locked
+pub struct LockedKey<'a> {
+    tx: Box<dyn KeyTransaction + 'a>,
+}
+
+impl<'a> LockedKey<'a> {
+    pub fn load(&mut self) -> Result<Option<KeyData>> {
+        self.tx.load()
+    }
+
+    pub fn store(&mut self, data: &KeyData) -> Result<()> {
+        self.tx.upsert(data)
+    }
+
+    /// Delete the metadata of the key
+    pub fn remove(&mut self) -> Result<()> {
+        self.tx.delete()
+    }
+}
+
+/// Move all the keys from one store to another
+///
+/// Existing keys in `to` take precedence.
+pub fn move_keys(from: &dyn MetadataStore, to: &dyn MetadataStore) -> Result<usize> {
+    let mut moved = 0;
+    for key in from.list_keys()? {
+        with_key_lock(from, &key, |from_key| {
+            let Some(data) = from_key.load()? else {
+                return Ok(());
+            };
+            with_key_lock(to, &key, |to_key| {
+                if to_key.load()?.is_none() {
+                    to_key.store(&data)?;
+                }
+                Ok(())
+            })?;
+            from_key.remove()?;
+            moved += 1;
+            Ok(())
+        })?;
+    }
+    Ok(moved)
+}
+
+/// Move keys from root data written before version 2 into the store
+///
+/// Keys already in the store take precedence, as they can only be newer.
+pub fn migrate_keys(store: &dyn MetadataStore, data: &mut RootData) -> Result<()> {
+    for (key, key_data) in std::mem::take(&mut data.keys) {
+        with_key_lock(store, &key, |locked_key| {
+            if locked_key.load()?.is_none() {
+                locked_key.store(&key_data)?;
+            }
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
@@ -0,0 +1,168 @@
+//! Per-key metadata files at `<root>/.meta/<key>.json`, each guarded by its
+//! own lock file, so operations on different keys don't contend
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context as _, Result};
+use chrono::Utc;
+use tracing::error;
+
+use super::super::dto::{self, LockingMode};
+use super::super::journal;
+use super::super::locking::{self, HeldLock};
+use super::{KeyTransaction, MetadataStore};
+use crate::{util, LOG_TARGET};
+
+fn meta_dir_path(root_path: &Path) -> PathBuf {
+    root_path.join(".meta")
+}
+
+fn key_data_path(root_path: &Path, key: &str) -> PathBuf {
+    meta_dir_path(root_path).join(format!("{key}.json"))
+}
+
+fn key_lock_path(root_path: &Path, key: &str) -> PathBuf {
+    meta_dir_path(root_path).join(format!("{key}.lock"))
+}
+
+fn list_keys(root_path: &Path) -> Result<Vec<String>> {
+    let mut keys = vec![];
+    let entries = match fs::read_dir(meta_dir_path(root_path)) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(keys),
+        Err(err) => return Err(err.into()),
+    };
+    for entry in entries {
+        if let Some(key) = entry?
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_suffix(".json"))
+            // kept next to the metadata, see `Root::load_manifest`
+            .filter(|name| !name.ends_with(".manifest"))
+        {
+            keys.push(key.to_owned());
+        }
+    }
+    keys.sort();
+    Ok(keys)
+}
+
+/// Default store, keeping the metadata in files
+pub struct JsonStore {
+    root_path: PathBuf,
+    locking: LockingMode,
+}
+
+impl JsonStore {
+    pub fn new(root_path: &Path, locking: LockingMode) -> Self {
+        Self {
+            root_path: root_path.to_owned(),
+            locking,
+        }
+    }
+}
+
+impl MetadataStore for JsonStore {
+    fn list_keys(&self) -> Result<Vec<String>> {
+        list_keys(&self.root_path)
+    }
+
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        Ok(key_data_path(&self.root_path, key).try_exists()?)
+    }
+
+    fn begin(&self, key: &str) -> Result<Box<dyn KeyTransaction + '_>> {
+        fs::create_dir_all(meta_dir_path(&self.root_path))?;
+        let lock = locking::lock_exclusive(self.locking, &key_lock_path(&self.root_path, key))?;
+        Ok(Box::new(JsonKeyTransaction {
+            root_path: &self.root_path,
+            locking: self.locking,
+            key: key.to_owned(),
+            _lock: lock,
+        }))
+    }
+}
+
+/// Changes are written right away, so there's nothing to roll back
+struct JsonKeyTransaction<'a> {
+    root_path: &'a Path,
+    locking: LockingMode,
+    key: String,
+    /// Released on drop
+    _lock: HeldLock,
+}
+
+impl<'a> JsonKeyTransaction<'a> {
+    /// Replace corrupted metadata with one rebuilt from the key dir
+    fn recover(&mut self, path: &Path, err: anyhow::Error) -> Result<dto::KeyData> {
+        error!(
+            target: LOG_TARGET,
+            err = %format!("{err:#}"),
+            path = %path.display(),
+            "Cache key data corrupted! Rebuilding from the key dir"
+        );
+        let corrupted_path =
+            path.with_extension(format!("json.corrupted-{}", Utc::now().timestamp()));
+        fs::rename(path, &corrupted_path)?;
+
+        let mtime = match fs::metadata(self.root_path.join(&self.key)) {
+            Ok(metadata) => metadata.modified()?.into(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Utc::now(),
+            Err(err) => return Err(err.into()),
+        };
+        let data = dto::KeyData::new(mtime);
+        self.upsert(&data)?;
+        super::super::record(
+            self.root_path,
+            self.locking,
+            journal::Entry::new(
+                &self.key,
+                None,
+                journal::Event::DataRecovered {
+                    source: dto::RecoverySource::Rebuilt,
+                },
+            ),
+        );
+        Ok(data)
+    }
+}
+
+impl<'a> KeyTransaction for JsonKeyTransaction<'a> {
+    fn load(&mut self) -> Result<Option<dto::KeyData>> {
+        let path = key_data_path(self.root_path, &self.key);
+        let file = match fs::File::open(&path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        match serde_json::from_reader(io::BufReader::new(file)) {
+            Ok(data) => Ok(Some(data)),
+            Err(err) => self.recover(&path, err.into()).map(Some),
+        }
+    }
+
+    fn upsert(&mut self, data: &dto::KeyData) -> Result<()> {
+        util::store_json_pretty_to_file(&key_data_path(self.root_path, &self.key), data)
+            .with_context(|| format!("Failed to store data of key {}", self.key))
+    }
+
+    /// Delete the metadata of the key (including its lock file)
+    fn delete(&mut self) -> Result<()> {
+        for path in [
+            key_data_path(self.root_path, &self.key),
+            key_lock_path(self.root_path, &self.key),
+        ] {
+            if let Err(err) = fs::remove_file(&path) {
+                if err.kind() != io::ErrorKind::NotFound {
+                    return Err(err.into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn commit(self: Box<Self>) -> Result<()> {
+        Ok(())
+    }
+}
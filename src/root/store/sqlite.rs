@@ -0,0 +1,165 @@
+//! Metadata of all keys in a single SQLite database at
+//! `<root>/fs-dir-cache.sqlite`
+//!
+//! Key data is stored as JSON, in the same format as in the JSON store, so
+//! unknown fields are preserved and can be queried with `json_extract`.
+use std::cell::Cell;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context as _, Result};
+use rusqlite::{Connection, OptionalExtension as _};
+use tracing::warn;
+
+use super::super::dto;
+use super::{KeyTransaction, MetadataStore};
+use crate::LOG_TARGET;
+
+/// Other processes only hold the database for the duration of short
+/// transactions, so waiting for them this long means something is wrong
+const BUSY_TIMEOUT: Duration = Duration::from_secs(600);
+
+pub fn db_file_path(root_path: &Path) -> PathBuf {
+    root_path.join("fs-dir-cache.sqlite")
+}
+
+pub struct SqliteStore {
+    conn: Connection,
+    /// Number of open key transactions, all sharing one database
+    /// transaction
+    open_txs: Cell<usize>,
+    /// One of the open key transactions was dropped without committing
+    rollback: Cell<bool>,
+}
+
+impl SqliteStore {
+    pub fn open(root_path: &Path) -> Result<Self> {
+        let path = db_file_path(root_path);
+        let conn = Connection::open(&path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS keys (
+                key TEXT PRIMARY KEY NOT NULL,
+                data TEXT NOT NULL
+            )",
+        )?;
+        Ok(Self {
+            conn,
+            open_txs: Cell::new(0),
+            rollback: Cell::new(false),
+        })
+    }
+}
+
+impl MetadataStore for SqliteStore {
+    fn list_keys(&self) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare("SELECT key FROM keys ORDER BY key")?;
+        let keys = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(keys)
+    }
+
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        Ok(self.conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM keys WHERE key = ?1)",
+            [key],
+            |row| row.get(0),
+        )?)
+    }
+
+    fn begin(&self, key: &str) -> Result<Box<dyn KeyTransaction + '_>> {
+        if self.open_txs.get() == 0 {
+            // take the write lock right away, so concurrent read-modify-write
+            // cycles can't interleave
+            self.conn.execute_batch("BEGIN IMMEDIATE")?;
+            self.rollback.set(false);
+        }
+        self.open_txs.set(self.open_txs.get() + 1);
+        Ok(Box::new(SqliteKeyTransaction {
+            store: self,
+            key: key.to_owned(),
+            finished: false,
+        }))
+    }
+}
+
+impl SqliteStore {
+    /// Finish one of the open key transactions; the database transaction is
+    /// finished with the last one
+    fn finish(&self, rollback: bool) -> Result<()> {
+        self.rollback.set(self.rollback.get() || rollback);
+        self.open_txs.set(self.open_txs.get() - 1);
+        if 0 < self.open_txs.get() {
+            return Ok(());
+        }
+        if self.rollback.get() {
+            self.conn.execute_batch("ROLLBACK")?;
+            if !rollback {
+                anyhow::bail!(
+                    "Transaction rolled back, as another one held with it was not committed"
+                );
+            }
+        } else {
+            self.conn.execute_batch("COMMIT")?;
+        }
+        Ok(())
+    }
+}
+
+struct SqliteKeyTransaction<'a> {
+    store: &'a SqliteStore,
+    key: String,
+    finished: bool,
+}
+
+impl<'a> Drop for SqliteKeyTransaction<'a> {
+    fn drop(&mut self) {
+        if !self.finished {
+            if let Err(err) = self.store.finish(true) {
+                warn!(target: LOG_TARGET, %err, key = %self.key, "Failed to roll back transaction");
+            }
+        }
+    }
+}
+
+impl<'a> KeyTransaction for SqliteKeyTransaction<'a> {
+    fn load(&mut self) -> Result<Option<dto::KeyData>> {
+        let Some(data) = self
+            .store
+            .conn
+            .query_row("SELECT data FROM keys WHERE key = ?1", [&self.key], |row| {
+                row.get::<_, String>(0)
+            })
+            .optional()?
+        else {
+            return Ok(None);
+        };
+        Ok(Some(serde_json::from_str(&data).with_context(|| {
+            format!("Failed to parse data of key {}", self.key)
+        })?))
+    }
+
+    fn upsert(&mut self, data: &dto::KeyData) -> Result<()> {
+        self.store.conn.execute(
+            "INSERT INTO keys (key, data) VALUES (?1, ?2)
+                ON CONFLICT (key) DO UPDATE SET data = excluded.data",
+            (&self.key, serde_json::to_string(data)?),
+        )?;
+        Ok(())
+    }
+
+    fn delete(&mut self) -> Result<()> {
+        self.store
+            .conn
+            .execute("DELETE FROM keys WHERE key = ?1", [&self.key])?;
+        Ok(())
+    }
+
+    fn commit(mut self: Box<Self>) -> Result<()> {
+        self.finished = true;
+        self.store.finish(false)
+    }
+}
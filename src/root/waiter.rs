@@ -0,0 +1,172 @@
+//! Wake-up notifications for processes waiting for a key lock
+//!
+//! A waiter binds a datagram socket and joins the queue of the key with its
+//! path (while holding the key lock, so no notification can be missed), then
+//! blocks on it. `unlock` sends a datagram to every queued waiter. The socket
+//! disappearing means the waiter is gone and can be dropped from the queue.
+//!
+//! The sockets are bound in `<root>/.waiters`, and ones left behind by killed
+//! waiters are removed by gc, see [`remove_stale_sockets`].
+use std::collections::HashSet;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use std::{fs, io};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use rand::distributions::{Alphanumeric, DistString};
+use tracing::{debug, warn};
+
+use super::dto::QueuedWaiter;
+use crate::LOG_TARGET;
+
+pub struct Waiter {
+    socket: UnixDatagram,
+    path: PathBuf,
+}
+
+impl Waiter {
+    pub fn bind(root_path: &Path) -> Result<Self> {
+        let dir = dir_path(root_path);
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(format!(
+            "wait-{}",
+            Alphanumeric.sample_string(&mut rand::thread_rng(), 16)
+        ));
+        let socket = UnixDatagram::bind(&path)?;
+        Ok(Self { socket, path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Block until notified, or `timeout` passes
+    pub fn wait(&self, timeout: Duration) -> Result<()> {
+        self.socket.set_read_timeout(Some(timeout))?;
+        match self.socket.recv(&mut [0; 16]) {
+            Ok(_) => {
+                debug!(target: LOG_TARGET, "Woken up by unlock");
+            }
+            Err(err)
+                if err.kind() == io::ErrorKind::WouldBlock
+                    || err.kind() == io::ErrorKind::TimedOut => {}
+            Err(err) => return Err(err.into()),
+        }
+        // coalesce notifications that arrived in the meantime
+        self.socket.set_nonblocking(true)?;
+        while self.socket.recv(&mut [0; 16]).is_ok() {}
+        self.socket.set_nonblocking(false)?;
+        Ok(())
+    }
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.path) {
+            warn!(target: LOG_TARGET, %err, path = %self.path.display(), "Could not remove waiter socket");
+        }
+    }
+}
+
+fn dir_path(root_path: &Path) -> PathBuf {
+    root_path.join(".waiters")
+}
+
+/// Sockets younger than this are kept, as their waiters may not have joined
+/// a queue yet
+const MIN_STALE_SOCKET_AGE: Duration = Duration::from_secs(60);
+
+/// Remove the sockets of waiters that are gone, except for the `queued` ones
+/// (which can be of waiters on other hosts), returning how many
+///
+/// Sockets of earlier versions, bound right in the root, are removed too.
+pub fn remove_stale_sockets(root_path: &Path, queued: &HashSet<&Path>) -> io::Result<u64> {
+    let mut removed = 0;
+    for dir in [root_path.to_owned(), dir_path(root_path)] {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            let is_socket = entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| name.starts_with("wait-"));
+            if !is_socket || queued.contains(path.as_path()) {
+                continue;
+            }
+            let age = SystemTime::now()
+                .duration_since(entry.metadata()?.modified()?)
+                .unwrap_or_default();
+            if age < MIN_STALE_SOCKET_AGE {
+                continue;
+            }
+            // nothing bound to it anymore
+            let gone = UnixDatagram::unbound()?
+                .connect(&path)
+                .is_err_and(|err| err.kind() == io::ErrorKind::ConnectionRefused);
+            if !gone {
+                continue;
+            }
+            debug!(target: LOG_TARGET, path = %path.display(), "Removing stale waiter socket");
+            match fs::remove_file(&path) {
+                Ok(()) => removed += 1,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+    Ok(removed)
+}
+
+/// Waiter on another host that didn't check the key for this long is
+/// considered gone
+const REMOTE_WAITER_LEASE_SECS: i64 = 60;
+
+/// Is the waiter still waiting
+pub fn is_alive(waiter: &QueuedWaiter, now: DateTime<Utc>) -> bool {
+    if waiter.is_local() {
+        UnixDatagram::unbound().is_ok_and(|socket| socket.connect(&waiter.socket_path).is_ok())
+    } else {
+        waiter.last_seen.is_some_and(|last_seen| {
+            now.signed_duration_since(last_seen)
+                < chrono::Duration::seconds(REMOTE_WAITER_LEASE_SECS)
+        })
+    }
+}
+
+/// Wake up all the queued waiters on this host, dropping ones that are gone
+///
+/// Waiters on other hosts can't be notified, and poll instead.
+pub fn notify_all(queue: &mut Vec<QueuedWaiter>) {
+    // never block the unlock on a waiter; a full buffer means it's
+    // already been notified
+    let socket = match UnixDatagram::unbound().and_then(|socket| {
+        socket.set_nonblocking(true)?;
+        Ok(socket)
+    }) {
+        Ok(socket) => socket,
+        Err(err) => {
+            warn!(target: LOG_TARGET, %err, "Could not create notification socket");
+            return;
+        }
+    };
+    queue.retain(|waiter| !waiter.is_local() || notify(&socket, &waiter.socket_path));
+}
+
+/// Returns `false` if the waiter is gone
+fn notify(socket: &UnixDatagram, path: &Path) -> bool {
+    match socket.send_to(&[0], path) {
+        Ok(_) => true,
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => true,
+        Err(err) => {
+            debug!(target: LOG_TARGET, %err, path = %path.display(), "Waiter gone");
+            false
+        }
+    }
+}
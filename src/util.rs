@@ -1,18 +1,23 @@
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::io::{self, Write};
-use std::path::Path;
+use std::os::unix::fs::{MetadataExt as _, PermissionsExt as _};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
-use serde::Serialize;
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
 use tracing::debug;
 
-pub fn open_lock_file(root_path: &Path) -> anyhow::Result<fs::File> {
-    let path = root_path.join("lock");
+use crate::LOG_TARGET;
+
+pub fn open_lock_file_at(path: &Path) -> anyhow::Result<fs::File> {
     debug!(path = %path.display(), "Opening lock file...");
     let file = fs::OpenOptions::new()
         .create(true)
         .append(true)
         .read(true)
-        .open(path.clone())?;
+        .open(path)?;
     debug!(path = %path.display(), "Opened lock file");
     Ok(file)
 }
@@ -31,15 +36,446 @@ pub fn store_to_file_with<E, F>(path: &Path, f: F) -> io::Result<Result<(), E>>
 where
     F: Fn(&mut dyn io::Write) -> Result<(), E>,
 {
-    std::fs::create_dir_all(path.parent().expect("Not a root path"))?;
-    let tmp_path = path.with_extension("tmp");
+    let parent = path.parent().expect("Not a root path");
+    std::fs::create_dir_all(parent)?;
+    // unique, so a leftover from a crashed writer (possibly on another host
+    // sharing the file system) can't get in the way
+    let tmp_path = path.with_extension(format!(
+        "tmp-{}",
+        Alphanumeric.sample_string(&mut rand::thread_rng(), 8)
+    ));
     let mut file = std::fs::File::create(&tmp_path)?;
     if let Err(e) = f(&mut file) {
+        drop(file);
+        let _ = std::fs::remove_file(&tmp_path);
         return Ok(Err(e));
     }
     file.flush()?;
     file.sync_data()?;
     drop(file);
     std::fs::rename(tmp_path, path)?;
+    // make the rename itself durable
+    fs::File::open(parent)?.sync_all()?;
     Ok(Ok(()))
 }
+
+/// Remove the temporary files of [`store_to_file_with`] in `dir` not
+/// modified for `max_age`, returning how many
+pub fn remove_stale_tmp_files(dir: &Path, max_age: Duration) -> io::Result<u64> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+    let mut removed = 0;
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path();
+        let is_tmp = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| ext.starts_with("tmp-"));
+        let metadata = entry.metadata()?;
+        if !is_tmp || !metadata.is_file() {
+            continue;
+        }
+        let age = SystemTime::now()
+            .duration_since(metadata.modified()?)
+            .unwrap_or_default();
+        if age < max_age {
+            continue;
+        }
+        debug!(target: LOG_TARGET, path = %path.display(), "Removing stale temporary file");
+        match fs::remove_file(&path) {
+            Ok(()) => removed += 1,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(removed)
+}
+
+/// Space taken by a dir
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiskUsage {
+    /// Total size of the files, as seen by readers
+    pub apparent_bytes: u64,
+    /// Blocks allocated on the file system, including the subdirs
+    pub allocated_bytes: u64,
+}
+
+impl std::ops::AddAssign for DiskUsage {
+    fn add_assign(&mut self, other: Self) {
+        self.apparent_bytes += other.apparent_bytes;
+        self.allocated_bytes += other.allocated_bytes;
+    }
+}
+
+impl std::ops::SubAssign for DiskUsage {
+    fn sub_assign(&mut self, other: Self) {
+        self.apparent_bytes -= other.apparent_bytes;
+        self.allocated_bytes -= other.allocated_bytes;
+    }
+}
+
+/// A file with more than one link, e.g. after `dedup`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinkedFile {
+    pub dev: u64,
+    pub ino: u64,
+    pub nlink: u64,
+    /// Of its links, how many were found under the dirs walked
+    pub links_found: u64,
+    #[serde(flatten)]
+    pub usage: DiskUsage,
+}
+
+impl LinkedFile {
+    /// Is it also linked from outside the dirs walked
+    pub fn is_shared(&self) -> bool {
+        self.links_found < self.nlink
+    }
+}
+
+/// [`LinkedFile`]s by `(dev, ino)`
+pub type LinkedFiles = HashMap<(u64, u64), LinkedFile>;
+
+/// Whether the file of `metadata` was already counted, recording it in
+/// `linked` if it's hardlinked
+fn count_link(metadata: &fs::Metadata, linked: &mut LinkedFiles) -> bool {
+    if metadata.nlink() < 2 {
+        return false;
+    }
+    let file = linked
+        .entry((metadata.dev(), metadata.ino()))
+        .or_insert_with(|| LinkedFile {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            nlink: metadata.nlink(),
+            links_found: 0,
+            usage: DiskUsage {
+                apparent_bytes: metadata.len(),
+                allocated_bytes: metadata.blocks() * 512,
+            },
+        });
+    file.links_found += 1;
+    1 < file.links_found
+}
+
+/// Space taken by everything under `path`
+///
+/// Symlinks are not followed. Hardlinked files are counted only once, across
+/// all the calls sharing `linked`, where they are recorded.
+pub fn dir_usage(path: &Path, linked: &mut LinkedFiles) -> io::Result<DiskUsage> {
+    let mut total = DiskUsage::default();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            total += dir_usage(&entry.path(), linked)?;
+        } else if count_link(&metadata, linked) {
+            continue;
+        } else {
+            total.apparent_bytes += metadata.len();
+        }
+        // `st_blocks` are always 512 bytes
+        total.allocated_bytes += metadata.blocks() * 512;
+    }
+    Ok(total)
+}
+
+/// Space that deleting everything under `path` would free
+///
+/// Like [`dir_usage`], but without the files also linked from elsewhere.
+pub fn dir_usage_exclusive(path: &Path) -> io::Result<DiskUsage> {
+    let mut linked = LinkedFiles::new();
+    let mut usage = dir_usage(path, &mut linked)?;
+    for file in linked.values().filter(|file| file.is_shared()) {
+        usage -= file.usage;
+    }
+    Ok(usage)
+}
+
+/// Usage of a dir tree recorded by [`dir_usage_incremental`], to skip the
+/// dirs that didn't change since
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct UsageCache {
+    ino: u64,
+    /// Seconds and nanoseconds
+    mtime: (i64, i64),
+    /// Of the entries right in the dir, except for the subdirs listed below;
+    /// `None` if it has to be read again anyway, as it has hardlinked files
+    /// (counted once per walk) or names that aren't UTF-8
+    files: Option<DiskUsage>,
+    subdirs: BTreeMap<String, UsageCache>,
+}
+
+/// Like [`dir_usage`] with fresh `linked`, but only reading the dirs
+/// modified since `cache` was recorded, and updating it
+///
+/// The entries of a dir are assumed unchanged as long as its mtime is, so
+/// files modified in place (rather than replaced) are missed until their dir
+/// changes.
+pub fn dir_usage_incremental(
+    path: &Path,
+    cache: &mut UsageCache,
+) -> io::Result<(DiskUsage, LinkedFiles)> {
+    let mut linked = LinkedFiles::new();
+    let (usage, _blocks) = dir_usage_cached(path, cache, &mut linked)?;
+    Ok((usage, linked))
+}
+
+/// Usage under `path`, and the blocks taken by the dir itself
+fn dir_usage_cached(
+    path: &Path,
+    cache: &mut UsageCache,
+    linked: &mut LinkedFiles,
+) -> io::Result<(DiskUsage, u64)> {
+    // before reading it, so changes made meanwhile are picked up next time
+    let metadata = fs::symlink_metadata(path)?;
+    let mtime = (metadata.mtime(), metadata.mtime_nsec());
+    let mut total = match cache.files {
+        Some(files) if cache.ino == metadata.ino() && cache.mtime == mtime => files,
+        _ => {
+            let mut files = DiskUsage::default();
+            let mut cacheable = true;
+            let mut subdirs = BTreeMap::new();
+            for entry in fs::read_dir(path)? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                if metadata.is_dir() {
+                    match entry.file_name().into_string() {
+                        Ok(name) => {
+                            let subdir = cache.subdirs.remove(&name).unwrap_or_default();
+                            subdirs.insert(name, subdir);
+                        }
+                        Err(_) => {
+                            cacheable = false;
+                            files += dir_usage(&entry.path(), linked)?;
+                            files.allocated_bytes += metadata.blocks() * 512;
+                        }
+                    }
+                    continue;
+                }
+                if 1 < metadata.nlink() {
+                    cacheable = false;
+                    if count_link(&metadata, linked) {
+                        continue;
+                    }
+                }
+                files.apparent_bytes += metadata.len();
+                files.allocated_bytes += metadata.blocks() * 512;
+            }
+            *cache = UsageCache {
+                ino: metadata.ino(),
+                mtime,
+                files: cacheable.then_some(files),
+                subdirs,
+            };
+            files
+        }
+    };
+    for (name, subdir) in &mut cache.subdirs {
+        let (usage, blocks) = dir_usage_cached(&path.join(name), subdir, linked)?;
+        total += usage;
+        total.allocated_bytes += blocks;
+    }
+    Ok((total, metadata.blocks() * 512))
+}
+
+/// Delete the least recently accessed files under `path`, until the rest
+/// take at most `max_bytes` (apparent size)
+///
+/// Files also linked from elsewhere (see `dedup`) are left alone, as deleting
+/// them frees nothing. Access times can be coarse, e.g. with `relatime`.
+/// Returns the number of bytes freed.
+pub fn trim_dir(path: &Path, max_bytes: u64) -> io::Result<u64> {
+    let mut inodes = HashMap::new();
+    collect_inodes(path, &mut inodes)?;
+    let mut inodes: Vec<_> = inodes
+        .into_values()
+        .filter(|inode| inode.nlink <= inode.paths.len() as u64)
+        .collect();
+    let mut total: u64 = inodes.iter().map(|inode| inode.len).sum();
+    inodes.sort_by_key(|inode| inode.atime);
+
+    let mut freed = 0;
+    for inode in inodes {
+        if total <= max_bytes {
+            break;
+        }
+        for path in inode.paths {
+            debug!(target: LOG_TARGET, path = %path.display(), "Trimming");
+            fs::remove_file(path)?;
+        }
+        total -= inode.len;
+        freed += inode.len;
+    }
+    Ok(freed)
+}
+
+/// A file (or symlink) found by [`collect_inodes`]
+struct Inode {
+    atime: i64,
+    len: u64,
+    nlink: u64,
+    paths: Vec<PathBuf>,
+}
+
+/// All the files (and symlinks) under `path`, by inode
+fn collect_inodes(path: &Path, inodes: &mut HashMap<(u64, u64), Inode>) -> io::Result<()> {
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            collect_inodes(&entry.path(), inodes)?;
+            continue;
+        }
+        inodes
+            .entry((metadata.dev(), metadata.ino()))
+            .or_insert_with(|| Inode {
+                atime: metadata.atime(),
+                len: metadata.len(),
+                nlink: metadata.nlink(),
+                paths: vec![],
+            })
+            .paths
+            .push(entry.path());
+    }
+    Ok(())
+}
+
+/// Remove write permissions of `path` and everything under it
+///
+/// Deleting the tree then requires [`remove_dir_all`], and moving `path`
+/// to another dir requires [`rename_dir`].
+pub fn make_read_only(path: &Path) -> io::Result<()> {
+    make_contents_read_only(path)?;
+    set_writable(path, false)
+}
+
+/// Like [`make_read_only`], but leaving `path` itself writable
+pub fn make_contents_read_only(path: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            make_read_only(&entry.path())?;
+        } else if file_type.is_file() {
+            set_writable(&entry.path(), false)?;
+        }
+    }
+    Ok(())
+}
+
+/// Like [`fs::rename`], but also for dirs made read-only with
+/// [`make_read_only`], which can't be moved to another dir as they are
+pub fn rename_dir(from: &Path, to: &Path) -> io::Result<()> {
+    let read_only = fs::symlink_metadata(from)?.permissions().mode() & 0o200 == 0;
+    if !read_only {
+        return fs::rename(from, to);
+    }
+    set_writable(from, true)?;
+    let res = fs::rename(from, to);
+    set_writable(if res.is_ok() { to } else { from }, false)?;
+    res
+}
+
+pub fn set_writable(path: &Path, writable: bool) -> io::Result<()> {
+    let mut permissions = fs::symlink_metadata(path)?.permissions();
+    let mode = if writable {
+        permissions.mode() | 0o200
+    } else {
+        permissions.mode() & !0o222
+    };
+    if mode != permissions.mode() {
+        permissions.set_mode(mode);
+        fs::set_permissions(path, permissions)?;
+    }
+    Ok(())
+}
+
+/// Like [`fs::remove_dir_all`], but also for trees made read-only with
+/// [`make_read_only`], restoring write permissions of the dirs as needed
+pub fn remove_dir_all(path: &Path) -> io::Result<()> {
+    match fs::remove_dir_all(path) {
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+            make_dirs_writable(path)?;
+            fs::remove_dir_all(path)
+        }
+        res => res,
+    }
+}
+
+fn make_dirs_writable(path: &Path) -> io::Result<()> {
+    set_writable(path, true)?;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            make_dirs_writable(&entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Run `f` modifying the entries of `dir`, letting the owner write to it
+/// meanwhile if it's read-only (see [`make_read_only`])
+pub fn with_writable_dir<T>(dir: &Path, f: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+    let read_only = fs::symlink_metadata(dir)?.permissions().mode() & 0o200 == 0;
+    if !read_only {
+        return f();
+    }
+    set_writable(dir, true)?;
+    let res = f();
+    set_writable(dir, false)?;
+    res
+}
+
+/// Does a process with a given pid (on this host) exist
+pub fn is_process_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // signal `0` only checks if the process could be signaled
+    // SAFETY: `kill` has no memory safety preconditions
+    let res = unsafe { libc::kill(pid, 0) };
+    res == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Start time of a process, in clock ticks since boot
+#[cfg(target_os = "linux")]
+pub fn process_start_time(pid: u32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // `comm` (2nd field) can contain spaces and parens, so skip past it
+    let (_, rest) = stat.rsplit_once(')')?;
+    // `starttime` is the 22nd field, and `rest` starts at the 3rd
+    rest.split_whitespace().nth(19)?.parse().ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn process_start_time(_pid: u32) -> Option<u64> {
+    None
+}
+
+/// Command line of a process (on this host)
+#[cfg(target_os = "linux")]
+pub fn process_cmdline(pid: u32) -> Option<Vec<String>> {
+    let cmdline = fs::read(format!("/proc/{pid}/cmdline")).ok()?;
+    // each argument is NUL-terminated; empty for kernel threads and zombies
+    if cmdline.is_empty() {
+        return None;
+    }
+    Some(
+        cmdline
+            .strip_suffix(b"\0")
+            .unwrap_or(&cmdline)
+            .split(|&b| b == 0)
+            .map(|arg| String::from_utf8_lossy(arg).into_owned())
+            .collect(),
+    )
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn process_cmdline(_pid: u32) -> Option<Vec<String>> {
+    None
+}
@@ -0,0 +1,1772 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+
+use assert_cmd::assert::OutputAssertExt as _;
+use assert_cmd::cargo;
+
+fn our_bin_cmd(root: &Path) -> std::process::Command {
+    let mut cmd = std::process::Command::new(cargo::cargo_bin(env!("CARGO_PKG_NAME")));
+    cmd.env("FS_DIR_CACHE_ROOT", root);
+    cmd.stderr(Stdio::inherit());
+    cmd
+}
+
+fn stdout_of(cmd: &mut std::process::Command) -> anyhow::Result<String> {
+    Ok(String::from_utf8(
+        cmd.output()?.assert().success().get_output().stdout.clone(),
+    )?)
+}
+
+fn lock_key(root: &Path, key_name: &str, lock_id: &str) -> anyhow::Result<PathBuf> {
+    Ok(PathBuf::from(
+        stdout_of(our_bin_cmd(root).args([
+            "lock",
+            "--key-name",
+            key_name,
+            "--lock-id",
+            lock_id,
+            "--timeout-secs",
+            "60",
+        ]))?
+        .trim(),
+    ))
+}
+
+#[test]
+fn status_reports_holder() -> anyhow::Result<()> {
+    let root_dir = tempfile::tempdir()?;
+
+    let out = stdout_of(our_bin_cmd(root_dir.path()).args(["status", "--key-name", "keyname"]))?;
+    assert!(out.contains("state: never locked"), "{out}");
+    // nothing left behind by looking
+    assert!(
+        !root_dir.path().join(".meta").try_exists()?
+            || std::fs::read_dir(root_dir.path().join(".meta"))?
+                .next()
+                .is_none()
+    );
+
+    let dir = lock_key(root_dir.path(), "keyname", "lockid")?;
+
+    let out = stdout_of(
+        our_bin_cmd(root_dir.path())
+            .args(["status", "--dir"])
+            .arg(&dir),
+    )?;
+    assert!(out.contains("state: locked"), "{out}");
+    assert!(out.contains("lock_id: lockid"), "{out}");
+    assert!(out.contains("timeout_secs: 60"), "{out}");
+    // the process that ran `lock`, not `lock` itself
+    assert!(
+        out.contains(&format!("holder_pid: {}\n", std::process::id())),
+        "{out}"
+    );
+    let args: Vec<String> = std::env::args().collect();
+    assert!(
+        out.contains(&format!("holder_cmdline: {}\n", args.join(" "))),
+        "{out}"
+    );
+
+    our_bin_cmd(root_dir.path())
+        .args(["unlock", "--lock-id", "lockid", "--dir"])
+        .arg(&dir)
+        .assert()
+        .success();
+
+    let out = stdout_of(our_bin_cmd(root_dir.path()).args(["status", "--key-name", "keyname"]))?;
+    assert!(out.contains("state: unlocked"), "{out}");
+
+    Ok(())
+}
+
+#[test]
+fn stats_count_hits_and_misses() -> anyhow::Result<()> {
+    let root_dir = tempfile::tempdir()?;
+
+    for _ in 0..3 {
+        our_bin_cmd(root_dir.path())
+            .args(["exec", "--key-name", "keyname", "--", "true"])
+            .assert()
+            .success();
+    }
+
+    let out = stdout_of(our_bin_cmd(root_dir.path()).args(["stats", "--keys", "--reset"]))?;
+    let line = out
+        .lines()
+        .find(|l| l.starts_with("keyname "))
+        .expect("keyname stats present");
+    let fields: Vec<_> = line.split_whitespace().collect();
+    assert_eq!(fields[1..4], ["3", "2", "1"], "{out}");
+
+    let out = stdout_of(our_bin_cmd(root_dir.path()).args(["stats"]))?;
+    let line = out
+        .lines()
+        .find(|l| l.starts_with("keyname "))
+        .expect("keyname stats present");
+    let fields: Vec<_> = line.split_whitespace().collect();
+    assert_eq!(fields[1..4], ["0", "0", "0"], "{out}");
+
+    Ok(())
+}
+
+#[test]
+fn metrics_textfile() -> anyhow::Result<()> {
+    let root_dir = tempfile::tempdir()?;
+    let textfile = root_dir.path().join("metrics.prom");
+
+    our_bin_cmd(root_dir.path())
+        .env("FS_DIR_CACHE_METRICS_TEXTFILE", &textfile)
+        .args(["exec", "--key-name", "keyname", "--", "true"])
+        .assert()
+        .success();
+
+    let metrics = std::fs::read_to_string(&textfile)?;
+    assert!(metrics.contains("\nfs_dir_cache_keys 1\n"), "{metrics}");
+    assert!(
+        metrics.contains("fs_dir_cache_misses_total{key_name=\"keyname\"} 1"),
+        "{metrics}"
+    );
+    assert!(
+        metrics.contains("fs_dir_cache_lock_wait_seconds_count 1"),
+        "{metrics}"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn log_filters_by_lock_id() -> anyhow::Result<()> {
+    let root_dir = tempfile::tempdir()?;
+
+    let dir = lock_key(root_dir.path(), "keyname", "lockid")?;
+    our_bin_cmd(root_dir.path())
+        .args(["unlock", "--lock-id", "lockid", "--dir"])
+        .arg(&dir)
+        .assert()
+        .success();
+    our_bin_cmd(root_dir.path())
+        .args(["exec", "--key-name", "keyname", "--", "true"])
+        .assert()
+        .success();
+
+    let out = stdout_of(our_bin_cmd(root_dir.path()).args(["log", "--lock-id", "lockid"]))?;
+    let events: Vec<_> = out
+        .lines()
+        .map(|l| serde_json::from_str::<serde_json::Value>(l).map(|v| v["event"].clone()))
+        .collect::<Result<_, _>>()?;
+    assert_eq!(
+        events,
+        ["lock_requested", "lock_acquired", "lock_released"],
+        "{out}"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn schema_versioning() -> anyhow::Result<()> {
+    let root_dir = tempfile::tempdir()?;
+    let data_path = root_dir.path().join("fs-dir-cache.json");
+
+    // pre-versioning file, with a field from some future version
+    std::fs::write(&data_path, r#"{"keys": {}, "future_field": [1, 2]}"#)?;
+    lock_key(root_dir.path(), "keyname", "lockid")?;
+
+    let data: serde_json::Value = serde_json::from_slice(&std::fs::read(&data_path)?)?;
+    assert_eq!(data["version"], 2);
+    assert_eq!(data["future_field"], serde_json::json!([1, 2]));
+
+    std::fs::write(
+        &data_path,
+        r#"{"version": 99, "min_reader_version": 99, "keys": {}}"#,
+    )?;
+    let out = our_bin_cmd(root_dir.path())
+        .args(["status", "--key-name", "keyname"])
+        .stderr(Stdio::piped())
+        .output()?;
+    assert!(!out.status.success());
+    assert!(String::from_utf8(out.stderr)?.contains("Please upgrade fs-dir-cache"));
+
+    Ok(())
+}
+
+#[test]
+fn corrupted_data_recovery() -> anyhow::Result<()> {
+    let root_dir = tempfile::tempdir()?;
+    let data_path = root_dir.path().join("fs-dir-cache.json");
+    let backup_path = root_dir.path().join("fs-dir-cache.json.bak");
+    let load_data = || -> anyhow::Result<serde_json::Value> {
+        Ok(serde_json::from_slice(&std::fs::read(&data_path)?)?)
+    };
+
+    for key_name in ["key1", "key2"] {
+        our_bin_cmd(root_dir.path())
+            .args(["exec", "--key-name", key_name, "--", "true"])
+            .assert()
+            .success();
+    }
+    // store root data twice, so there's a backup
+    for _ in 0..2 {
+        our_bin_cmd(root_dir.path())
+            .args(["gc", "unused", "--seconds", "3600"])
+            .assert()
+            .success();
+    }
+
+    std::fs::write(&data_path, r#"{"keys": {"#)?;
+    our_bin_cmd(root_dir.path())
+        .args(["stats"])
+        .assert()
+        .success();
+    let data = load_data()?;
+    assert_eq!(data["recoveries"][0]["source"], "backup");
+
+    std::fs::write(&data_path, "")?;
+    std::fs::write(&backup_path, "")?;
+    std::fs::remove_dir_all(root_dir.path().join(".meta"))?;
+    our_bin_cmd(root_dir.path())
+        .args(["stats"])
+        .assert()
+        .success();
+    let data = load_data()?;
+    assert_eq!(data["recoveries"][0]["source"], "rebuilt");
+    assert_eq!(std::fs::read_dir(root_dir.path().join(".meta"))?.count(), 4);
+
+    // failing to read it doesn't mean it's corrupted
+    std::fs::remove_file(&data_path)?;
+    std::fs::create_dir(&data_path)?;
+    for entry in std::fs::read_dir(root_dir.path())? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().contains(".corrupted-") {
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+    let root_entries = || std::fs::read_dir(root_dir.path()).map(Iterator::count);
+    let entries_before = root_entries()?;
+    our_bin_cmd(root_dir.path())
+        .args(["stats"])
+        .assert()
+        .failure();
+    assert!(data_path.is_dir());
+    assert_eq!(root_entries()?, entries_before);
+
+    Ok(())
+}
+
+#[test]
+fn gc_removes_stale_tmp_files() -> anyhow::Result<()> {
+    let root_dir = tempfile::tempdir()?;
+    lock_key(root_dir.path(), "keyname", "lockid")?;
+
+    // left behind by crashed writers, and one still being written
+    let stale = [
+        root_dir.path().join("fs-dir-cache.tmp-abcdefgh"),
+        root_dir.path().join(".meta/keyname.tmp-abcdefgh"),
+    ];
+    for path in &stale {
+        std::fs::write(path, "{")?;
+        std::fs::File::options()
+            .write(true)
+            .open(path)?
+            .set_modified(std::time::SystemTime::now() - std::time::Duration::from_secs(7200))?;
+    }
+    let fresh = root_dir.path().join(".meta/keyname.tmp-ijklmnop");
+    std::fs::write(&fresh, "{")?;
+
+    our_bin_cmd(root_dir.path())
+        .args(["gc", "unused", "--seconds", "3600"])
+        .assert()
+        .success();
+    for path in &stale {
+        assert!(!path.try_exists()?, "{}", path.display());
+    }
+    assert!(fresh.try_exists()?);
+
+    Ok(())
+}
+
+#[test]
+fn gc_removes_stale_waiter_sockets() -> anyhow::Result<()> {
+    use std::os::unix::net::UnixDatagram;
+
+    let root_dir = tempfile::tempdir()?;
+    let waiters_dir = root_dir.path().join(".waiters");
+    std::fs::create_dir(&waiters_dir)?;
+    // of a waiter killed long ago, and of one still waiting
+    let (dead, alive) = (
+        waiters_dir.join("wait-dead"),
+        waiters_dir.join("wait-alive"),
+    );
+    drop(UnixDatagram::bind(&dead)?);
+    let _alive_socket = UnixDatagram::bind(&alive)?;
+    for path in [&dead, &alive] {
+        std::process::Command::new("touch")
+            .args(["-d", "2 hours ago"])
+            .arg(path)
+            .assert()
+            .success();
+    }
+
+    our_bin_cmd(root_dir.path())
+        .args(["gc", "unused", "--seconds", "3600"])
+        .assert()
+        .success();
+    assert!(!dead.try_exists()?);
+    assert!(alive.try_exists()?);
+
+    Ok(())
+}
+
+#[test]
+fn migrates_keys_to_own_files() -> anyhow::Result<()> {
+    let root_dir = tempfile::tempdir()?;
+    let key = format!("old-{}", "0".repeat(64));
+
+    std::fs::write(
+        root_dir.path().join("fs-dir-cache.json"),
+        serde_json::json!({
+            "version": 1,
+            "keys": {
+                &key: {
+                    "locked_until": "2100-01-01T00:00:00Z",
+                    "lock_id": "oldlockid",
+                    "last_lock": "2000-01-01T00:00:00Z",
+                    "socket_path": null,
+                }
+            },
+            "stats": {
+                "keys": {
+                    &key: { "acquisitions": 3, "hits": 2, "misses": 1 },
+                },
+                // including the key above, and one evicted
+                "key_names": {
+                    "old": { "acquisitions": 5, "hits": 3, "misses": 2 },
+                },
+                "evictions": 1,
+                "lock_wait": { "buckets": [], "count": 5, "sum_ms": 0 },
+            }
+        })
+        .to_string(),
+    )?;
+
+    let out = stdout_of(
+        our_bin_cmd(root_dir.path())
+            .args(["status", "--dir"])
+            .arg(root_dir.path().join(&key)),
+    )?;
+    assert!(out.contains("state: locked"), "{out}");
+    assert!(out.contains("lock_id: oldlockid"), "{out}");
+
+    // counted once, in the key
+    let out = stdout_of(our_bin_cmd(root_dir.path()).args(["stats", "--keys"]))?;
+    let acquisitions = |name: &str| {
+        out.lines()
+            .find(|line| line.starts_with(name))
+            .and_then(|line| line.split_whitespace().nth(1))
+            .map(str::to_owned)
+    };
+    assert_eq!(acquisitions("old ").as_deref(), Some("5"), "{out}");
+    assert_eq!(acquisitions(&key).as_deref(), Some("3"), "{out}");
+
+    let data: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(root_dir.path().join("fs-dir-cache.json"))?)?;
+    assert_eq!(data["version"], 2);
+    // a hint for binaries predating schema versioning, which require `keys`
+    assert!(data["keys"]
+        .as_str()
+        .is_some_and(|keys| keys.contains("upgrade fs-dir-cache")));
+
+    Ok(())
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn switch_metadata_store() -> anyhow::Result<()> {
+    let root_dir = tempfile::tempdir()?;
+    let list_meta = || -> anyhow::Result<Vec<PathBuf>> {
+        Ok(std::fs::read_dir(root_dir.path().join(".meta"))?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .filter(|path| {
+                path.as_ref()
+                    .map_or(true, |path| path.extension() == Some("json".as_ref()))
+            })
+            .collect::<Result<_, _>>()?)
+    };
+
+    let dir = lock_key(root_dir.path(), "keyname", "lockid")?;
+
+    let out =
+        stdout_of(our_bin_cmd(root_dir.path()).args(["config", "--metadata-store", "sqlite"]))?;
+    assert!(out.contains(r#""metadata_store": "sqlite""#), "{out}");
+    assert!(list_meta()?.is_empty());
+
+    let out = stdout_of(our_bin_cmd(root_dir.path()).args(["status", "--key-name", "keyname"]))?;
+    assert!(out.contains("lock_id: lockid"), "{out}");
+    our_bin_cmd(root_dir.path())
+        .args(["unlock", "--lock-id", "lockid", "--dir"])
+        .arg(&dir)
+        .assert()
+        .success();
+
+    stdout_of(our_bin_cmd(root_dir.path()).args(["config", "--metadata-store", "json"]))?;
+    assert_eq!(list_meta()?.len(), 1);
+    let out = stdout_of(our_bin_cmd(root_dir.path()).args(["status", "--key-name", "keyname"]))?;
+    assert!(out.contains("state: unlocked"), "{out}");
+
+    Ok(())
+}
+
+#[test]
+fn unlock_wakes_up_waiters() -> anyhow::Result<()> {
+    let root_dir = tempfile::tempdir()?;
+    let lock_cmd = |lock_id: &str| {
+        let mut cmd = our_bin_cmd(root_dir.path());
+        cmd.args([
+            "lock",
+            "--key-name",
+            "keyname",
+            "--lock-id",
+            lock_id,
+            "--timeout-secs",
+            "3600",
+        ]);
+        cmd
+    };
+
+    let dir = PathBuf::from(stdout_of(&mut lock_cmd("first"))?.trim());
+    let mut waiting = lock_cmd("second").stdout(Stdio::null()).spawn()?;
+    std::thread::sleep(std::time::Duration::from_secs(1));
+    assert!(waiting.try_wait()?.is_none());
+
+    our_bin_cmd(root_dir.path())
+        .args(["unlock", "--lock-id", "first", "--dir"])
+        .arg(&dir)
+        .assert()
+        .success();
+    let unlocked = std::time::Instant::now();
+    assert!(waiting.wait()?.success());
+    assert!(unlocked.elapsed() < std::time::Duration::from_secs(5));
+
+    Ok(())
+}
+
+#[test]
+fn waiters_get_lock_in_order() -> anyhow::Result<()> {
+    let root_dir = tempfile::tempdir()?;
+    let lock_cmd = |lock_id: &str, priority: &str| {
+        let mut cmd = our_bin_cmd(root_dir.path());
+        cmd.args([
+            "lock",
+            "--key-name",
+            "keyname",
+            "--lock-id",
+            lock_id,
+            "--timeout-secs",
+            "3600",
+            "--priority",
+            priority,
+        ]);
+        cmd
+    };
+    let unlock = |dir: &Path, lock_id: &str| {
+        our_bin_cmd(root_dir.path())
+            .args(["unlock", "--lock-id", lock_id, "--dir"])
+            .arg(dir)
+            .assert()
+            .success();
+    };
+
+    let dir = PathBuf::from(stdout_of(&mut lock_cmd("holder", "0"))?.trim());
+    let mut waiting = vec![];
+    for (lock_id, priority) in [("first", "0"), ("second", "0"), ("urgent", "5")] {
+        let child = lock_cmd(lock_id, priority).stdout(Stdio::null()).spawn()?;
+        waiting.push((lock_id, child));
+        std::thread::sleep(std::time::Duration::from_millis(500));
+    }
+
+    let mut prev_lock_id = "holder";
+    for expected in ["urgent", "first", "second"] {
+        unlock(&dir, prev_lock_id);
+        let i = waiting
+            .iter()
+            .position(|(lock_id, _)| *lock_id == expected)
+            .expect("waiting");
+        let (_, mut child) = waiting.remove(i);
+        assert!(child.wait()?.success());
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        for (lock_id, child) in &mut waiting {
+            assert!(
+                child.try_wait()?.is_none(),
+                "{lock_id} got the lock before {expected}"
+            );
+        }
+        prev_lock_id = expected;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn heartbeat_keeps_lock_alive() -> anyhow::Result<()> {
+    let root_dir = tempfile::tempdir()?;
+
+    let dir = PathBuf::from(
+        stdout_of(our_bin_cmd(root_dir.path()).args([
+            "lock",
+            "--key-name",
+            "keyname",
+            "--lock-id",
+            "lockid",
+            "--timeout-secs",
+            "2",
+            "--heartbeat-secs",
+            "0.5",
+        ]))?
+        .trim(),
+    );
+    std::thread::sleep(std::time::Duration::from_secs(4));
+
+    let out = stdout_of(
+        our_bin_cmd(root_dir.path())
+            .args(["status", "--dir"])
+            .arg(&dir),
+    )?;
+    assert!(out.contains("state: locked"), "{out}");
+
+    our_bin_cmd(root_dir.path())
+        .args(["unlock", "--lock-id", "lockid", "--dir"])
+        .arg(&dir)
+        .assert()
+        .success();
+    our_bin_cmd(root_dir.path())
+        .args([
+            "renew",
+            "--lock-id",
+            "lockid",
+            "--timeout-secs",
+            "2",
+            "--dir",
+        ])
+        .arg(&dir)
+        .assert()
+        .failure();
+
+    Ok(())
+}
+
+#[test]
+fn lock_released_when_owner_exits() -> anyhow::Result<()> {
+    let root_dir = tempfile::tempdir()?;
+    let mut owner = std::process::Command::new("sleep").arg("600").spawn()?;
+
+    let dir = PathBuf::from(
+        stdout_of(our_bin_cmd(root_dir.path()).args([
+            "lock",
+            "--key-name",
+            "keyname",
+            "--lock-id",
+            "lockid",
+            "--timeout-secs",
+            "3600",
+            "--owner-pid",
+            &owner.id().to_string(),
+        ]))?
+        .trim(),
+    );
+    let status = || {
+        stdout_of(
+            our_bin_cmd(root_dir.path())
+                .args(["status", "--dir"])
+                .arg(&dir),
+        )
+    };
+    let out = status()?;
+    assert!(out.contains("state: locked"), "{out}");
+    assert!(out.contains("liveness: alive"), "{out}");
+
+    owner.kill()?;
+    owner.wait()?;
+    let out = status()?;
+    assert!(out.contains("state: unlocked"), "{out}");
+    assert!(out.contains("liveness: gone"), "{out}");
+
+    lock_key(root_dir.path(), "keyname", "otherlockid")?;
+
+    // by default owned by the caller, like the shell script here
+    let out = stdout_of(
+        std::process::Command::new("sh")
+            .env("FS_DIR_CACHE_ROOT", root_dir.path())
+            .args([
+                "-c",
+                r#""$0" lock --key-name other --lock-id lockid --timeout-secs 3600; true"#,
+            ])
+            .arg(cargo::cargo_bin(env!("CARGO_PKG_NAME"))),
+    )?;
+    let out = stdout_of(
+        our_bin_cmd(root_dir.path())
+            .args(["status", "--dir"])
+            .arg(out.trim()),
+    )?;
+    assert!(out.contains("state: unlocked"), "{out}");
+    assert!(out.contains("liveness: gone"), "{out}");
+
+    Ok(())
+}
+
+#[test]
+fn lockfile_locking() -> anyhow::Result<()> {
+    let root_dir = tempfile::tempdir()?;
+
+    let out = stdout_of(our_bin_cmd(root_dir.path()).args(["config", "--locking", "lockfile"]))?;
+    assert!(out.contains(r#""locking": "lockfile""#), "{out}");
+
+    // left behind by a crashed process on another host
+    let stale = root_dir.path().join("lock.lockfile");
+    std::fs::write(
+        &stale,
+        r#"{"pid":1,"start_time":null,"hostname":"some-other-host"}"#,
+    )?;
+    std::fs::File::options()
+        .write(true)
+        .open(&stale)?
+        .set_modified(std::time::SystemTime::now() - std::time::Duration::from_secs(3600))?;
+
+    let dir = lock_key(root_dir.path(), "keyname", "lockid")?;
+    let out = stdout_of(
+        our_bin_cmd(root_dir.path())
+            .args(["status", "--dir"])
+            .arg(&dir),
+    )?;
+    assert!(out.contains("state: locked"), "{out}");
+    our_bin_cmd(root_dir.path())
+        .args(["unlock", "--lock-id", "lockid", "--dir"])
+        .arg(&dir)
+        .assert()
+        .success();
+    // takes the root lock
+    stdout_of(our_bin_cmd(root_dir.path()).arg("stats"))?;
+
+    // the size of the dir is measured in the background, holding the key lock
+    // for a moment
+    let mut leftovers: Vec<PathBuf> = vec![];
+    for _ in 0..50 {
+        leftovers = std::fs::read_dir(root_dir.path())?
+            .chain(std::fs::read_dir(root_dir.path().join(".meta"))?)
+            .map(|entry| entry.map(|entry| entry.path()))
+            .filter(|path| {
+                path.as_ref()
+                    .map_or(true, |path| path.to_string_lossy().contains(".lockfile"))
+            })
+            .collect::<Result<_, _>>()?;
+        if leftovers.is_empty() {
+            return Ok(());
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    panic!("Lock files left behind: {leftovers:?}");
+}
+
+#[test]
+fn exec_with_multiple_caches() -> anyhow::Result<()> {
+    let root_dir = tempfile::tempdir()?;
+    let exec_cmd = |caches: [&str; 2]| {
+        let mut cmd = our_bin_cmd(root_dir.path());
+        cmd.arg("exec");
+        for cache in caches {
+            cmd.args(["--cache", cache]);
+        }
+        cmd.args([
+            "--",
+            "sh",
+            "-c",
+            r#"test -d "$TARGET" && test -d "$MODULES" && echo "$TARGET $MODULES" && sleep 1"#,
+        ]);
+        cmd
+    };
+
+    // taking the same keys in opposite order must not deadlock
+    let mut first = exec_cmd(["env=TARGET,key-name=target", "env=MODULES,key-name=modules"])
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let second = stdout_of(&mut exec_cmd([
+        "env=MODULES,key-name=modules",
+        "env=TARGET,key-name=target",
+    ]))?;
+    assert!(first.wait()?.success());
+
+    let (target, modules) = second.trim().split_once(' ').expect("two dirs");
+    assert!(Path::new(target)
+        .file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with("target-")));
+    assert!(Path::new(modules)
+        .file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with("modules-")));
+
+    Ok(())
+}
+
+#[test]
+fn multiple_caches_locked_all_or_nothing() -> anyhow::Result<()> {
+    let root_dir = tempfile::tempdir()?;
+    let status = |key_name: &str| {
+        stdout_of(our_bin_cmd(root_dir.path()).args(["status", "--key-name", key_name]))
+    };
+
+    let busy_dir = lock_key(root_dir.path(), "busy", "first")?;
+    let waiting = our_bin_cmd(root_dir.path())
+        .args([
+            "lock",
+            "--cache",
+            "env=FREE,key-name=free",
+            "--cache",
+            "env=BUSY,key-name=busy",
+            "--lock-id",
+            "second",
+            "--timeout-secs",
+            "3600",
+        ])
+        .stdout(Stdio::piped())
+        .spawn()?;
+    std::thread::sleep(std::time::Duration::from_secs(1));
+    let out = status("free")?;
+    assert!(out.contains("state: never locked"), "{out}");
+
+    our_bin_cmd(root_dir.path())
+        .args(["unlock", "--lock-id", "first", "--dir"])
+        .arg(&busy_dir)
+        .assert()
+        .success();
+    let out = String::from_utf8(
+        waiting
+            .wait_with_output()?
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone(),
+    )?;
+    let lines: Vec<_> = out.lines().collect();
+    assert_eq!(lines.len(), 2, "{out}");
+    assert!(lines[0].starts_with("FREE="), "{out}");
+    assert_eq!(lines[1], format!("BUSY={}", busy_dir.display()));
+
+    for key_name in ["free", "busy"] {
+        let out = status(key_name)?;
+        assert!(out.contains("lock_id: second"), "{out}");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn exec_on_busy() -> anyhow::Result<()> {
+    let root_dir = tempfile::tempdir()?;
+    let busy_dir = lock_key(root_dir.path(), "keyname", "lockid")?;
+    std::fs::create_dir_all(&busy_dir)?;
+    std::fs::write(busy_dir.join("cached"), "content")?;
+
+    let exec = |on_busy: &str, script: &str| {
+        stdout_of(our_bin_cmd(root_dir.path()).args([
+            "exec",
+            "--key-name",
+            "keyname",
+            "--on-busy",
+            on_busy,
+            "--",
+            "sh",
+            "-c",
+            script,
+        ]))
+    };
+
+    let scratch_root = root_dir.path().join(".scratch");
+    let out = exec("skip", "test ! -e cached && pwd")?;
+    let scratch_dir = PathBuf::from(out.trim());
+    assert_eq!(scratch_dir.parent(), Some(scratch_root.as_path()), "{out}");
+    assert_eq!(std::fs::read_dir(&scratch_root)?.count(), 0);
+
+    // left behind when killed, until gc
+    let out = our_bin_cmd(root_dir.path())
+        .args([
+            "exec",
+            "--key-name",
+            "keyname",
+            "--on-busy",
+            "clone",
+            "--",
+            "sh",
+            "-c",
+            "kill -9 $PPID",
+        ])
+        .output()?;
+    assert!(!out.status.success());
+    assert_ne!(std::fs::read_dir(&scratch_root)?.count(), 0);
+    our_bin_cmd(root_dir.path())
+        .args(["gc", "unused", "--seconds", "3600"])
+        .assert()
+        .success();
+    assert_eq!(std::fs::read_dir(&scratch_root)?.count(), 0);
+
+    let out = exec("clone", "cat cached")?;
+    assert_eq!(out, "content");
+
+    let out = exec("fresh", "test ! -e cached && pwd")?;
+    let slot_dir = PathBuf::from(out.trim());
+    assert_ne!(slot_dir.file_name(), busy_dir.file_name());
+    assert!(slot_dir
+        .file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with("keyname-")));
+    // the additional slot is reused once free
+    assert_eq!(exec("fresh", "pwd")?, out);
+
+    let out = stdout_of(
+        our_bin_cmd(root_dir.path())
+            .args(["status", "--dir"])
+            .arg(&busy_dir),
+    )?;
+    assert!(out.contains("lock_id: lockid"), "{out}");
+
+    Ok(())
+}
+
+#[test]
+fn exec_on_failure() -> anyhow::Result<()> {
+    let root_dir = tempfile::tempdir()?;
+    let exec = |on_failure: &str, script: &str| {
+        our_bin_cmd(root_dir.path())
+            .args([
+                "exec",
+                "--key-name",
+                "keyname",
+                "--on-failure",
+                on_failure,
+                "--",
+                "sh",
+                "-c",
+                script,
+            ])
+            .output()
+    };
+    let status =
+        || stdout_of(our_bin_cmd(root_dir.path()).args(["status", "--key-name", "keyname"]));
+
+    exec("keep", "touch partial && false")?.assert().failure();
+    exec("discard", "test -e partial && touch more && false")?
+        .assert()
+        .failure();
+    let out = status()?;
+    assert!(out.contains("state: unlocked"), "{out}");
+    assert!(out.contains("last_failure: discard"), "{out}");
+    // deleted after moving it out of the way
+    assert_eq!(
+        std::fs::read_dir(root_dir.path().join(".trash"))?.count(),
+        0
+    );
+
+    exec("quarantine", "test ! -e partial && touch broken && false")?
+        .assert()
+        .failure();
+    let out = status()?;
+    let quarantine_path = out
+        .lines()
+        .find_map(|line| line.strip_prefix("quarantined_to: "))
+        .expect("quarantined");
+    assert!(Path::new(quarantine_path).join("broken").exists(), "{out}");
+    assert!(Path::new(quarantine_path).starts_with(root_dir.path().join(".quarantine")));
+
+    exec("quarantine", "test ! -e broken")?.assert().success();
+
+    Ok(())
+}
+
+#[test]
+fn exec_publish() -> anyhow::Result<()> {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    let root_dir = tempfile::tempdir()?;
+    let exec = |args: &[&str], script: &str| {
+        our_bin_cmd(root_dir.path())
+            .args(["exec", "--key-name", "toolchain"])
+            .args(args)
+            .args(["--", "sh", "-c", script])
+            .output()
+    };
+    let key_dir = || -> anyhow::Result<Option<PathBuf>> {
+        Ok(std::fs::read_dir(root_dir.path())?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .find(|path| path.is_dir() && path.to_string_lossy().contains("toolchain-")))
+    };
+
+    exec(&["--publish"], "echo partial > tool && false")?
+        .assert()
+        .failure();
+    assert_eq!(key_dir()?, None);
+    assert_eq!(
+        std::fs::read_dir(root_dir.path().join(".staging"))?.count(),
+        0
+    );
+
+    exec(
+        &["--publish"],
+        "test ! -e tool && echo v1 > tool && mkdir lib && echo v1 > lib/dep",
+    )?
+    .assert()
+    .success();
+    let dir = key_dir()?.expect("published");
+    let tool = dir.join("tool");
+    assert_eq!(std::fs::read_to_string(&tool)?, "v1\n");
+    for path in [&dir, &tool, &dir.join("lib"), &dir.join("lib/dep")] {
+        assert_eq!(
+            std::fs::metadata(path)?.permissions().mode() & 0o222,
+            0,
+            "{}",
+            path.display()
+        );
+    }
+
+    let out = exec(&["--publish"], "cat tool")?;
+    assert_eq!(out.assert().success().get_output().stdout, b"v1\n");
+
+    exec(&[], "true")?.assert().failure();
+
+    let out = stdout_of(our_bin_cmd(root_dir.path()).args(["status", "--key-name", "toolchain"]))?;
+    assert!(out.contains("published: "), "{out}");
+    assert!(out.contains("readers: 0"), "{out}");
+
+    // read-only dirs can still be deleted
+    std::thread::sleep(std::time::Duration::from_secs(2));
+    let out = stdout_of(our_bin_cmd(root_dir.path()).args(["gc", "unused", "--seconds", "1"]))?;
+    assert_eq!(out.trim(), dir.to_string_lossy());
+    assert!(!dir.exists());
+    assert_eq!(
+        std::fs::read_dir(root_dir.path().join(".trash"))?.count(),
+        0
+    );
+
+    Ok(())
+}
+
+#[test]
+fn clone_key() -> anyhow::Result<()> {
+    use std::os::unix::fs::MetadataExt as _;
+
+    let root_dir = tempfile::tempdir()?;
+    let out = stdout_of(our_bin_cmd(root_dir.path()).args([
+        "exec",
+        "--key-name",
+        "source",
+        "--",
+        "sh",
+        "-c",
+        "mkdir sub && echo data > sub/file && ln -s sub/file link && pwd",
+    ]))?;
+    let from_dir = PathBuf::from(out.trim());
+    let modified = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+    std::fs::File::open(from_dir.join("sub/file"))?.set_modified(modified)?;
+
+    let clone = || {
+        our_bin_cmd(root_dir.path())
+            .args(["clone", "--key-name", "copy", "--from"])
+            .arg(&from_dir)
+            .output()
+    };
+    let out = clone()?;
+    let to_dir = PathBuf::from(
+        String::from_utf8(out.assert().success().get_output().stdout.clone())?.trim(),
+    );
+    assert_ne!(to_dir, from_dir);
+    assert_eq!(std::fs::read_to_string(to_dir.join("link"))?, "data\n");
+    assert!(std::fs::symlink_metadata(to_dir.join("link"))?.is_symlink());
+    assert_eq!(
+        std::fs::metadata(to_dir.join("sub/file"))?.modified()?,
+        modified
+    );
+
+    std::fs::write(to_dir.join("sub/file"), "changed")?;
+    assert_eq!(
+        std::fs::read_to_string(from_dir.join("sub/file"))?,
+        "data\n"
+    );
+
+    // never overwrites
+    clone()?.assert().failure();
+
+    // files of published dirs are only shared with dirs published too
+    let publish = |script: &str| {
+        stdout_of(our_bin_cmd(root_dir.path()).args([
+            "exec",
+            "--key-name",
+            "published",
+            "--publish",
+            "--",
+            "sh",
+            "-c",
+            script,
+        ]))
+    };
+    publish("echo data > file")?;
+    // run in the published dir, once there's one
+    let published_dir = PathBuf::from(publish("pwd")?.trim());
+    let clone_published = |key_name: &str, publish: bool| -> anyhow::Result<PathBuf> {
+        let mut cmd = our_bin_cmd(root_dir.path());
+        cmd.args(["clone", "--key-name", key_name, "--from"])
+            .arg(&published_dir);
+        if publish {
+            cmd.arg("--publish");
+        }
+        Ok(PathBuf::from(stdout_of(&mut cmd)?.trim()))
+    };
+    let (published, mutable, immutable) = (
+        std::fs::metadata(published_dir.join("file"))?,
+        std::fs::metadata(clone_published("mutable", false)?.join("file"))?,
+        std::fs::metadata(clone_published("immutable", true)?.join("file"))?,
+    );
+    // unless reflinked instead, on file systems that support it
+    assert_ne!(mutable.ino(), published.ino());
+    if 1 < published.nlink() {
+        assert_eq!(immutable.ino(), published.ino());
+    }
+
+    for key_name in ["source", "copy"] {
+        let out = stdout_of(our_bin_cmd(root_dir.path()).args(["status", "--key-name", key_name]))?;
+        assert!(out.contains("state: unlocked"), "{out}");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn export_import() -> anyhow::Result<()> {
+    let root_dir = tempfile::tempdir()?;
+    let dir = PathBuf::from(
+        stdout_of(our_bin_cmd(root_dir.path()).args([
+            "exec",
+            "--key-name",
+            "keyname",
+            "--",
+            "sh",
+            "-c",
+            "mkdir sub && echo data > sub/file && ln -s sub/file link && pwd",
+        ]))?
+        .trim(),
+    );
+    let archive = root_dir.path().join("keyname.tar.zst");
+    our_bin_cmd(root_dir.path())
+        .args(["export", "--dir"])
+        .arg(&dir)
+        .arg("--output")
+        .arg(&archive)
+        .assert()
+        .success();
+
+    let other_root_dir = tempfile::tempdir()?;
+    let import = || {
+        our_bin_cmd(other_root_dir.path())
+            .args(["import", "--input"])
+            .arg(&archive)
+            .output()
+    };
+    let out = import()?;
+    let imported_dir = PathBuf::from(
+        String::from_utf8(out.assert().success().get_output().stdout.clone())?.trim(),
+    );
+    assert_eq!(
+        std::fs::read_to_string(imported_dir.join("link"))?,
+        "data\n"
+    );
+    assert!(std::fs::symlink_metadata(imported_dir.join("link"))?.is_symlink());
+
+    // refuses to overwrite a locked key
+    let locked_dir = lock_key(other_root_dir.path(), "keyname", "lockid")?;
+    assert_eq!(locked_dir, imported_dir);
+    import()?.assert().failure();
+    our_bin_cmd(other_root_dir.path())
+        .args(["unlock", "--lock-id", "lockid", "--dir"])
+        .arg(&locked_dir)
+        .assert()
+        .success();
+    import()?.assert().success();
+
+    // corrupted archives are rejected
+    let mut data = std::fs::read(&archive)?;
+    data.truncate(data.len() / 2);
+    std::fs::write(&archive, data)?;
+    import()?.assert().failure();
+    assert_eq!(
+        std::fs::read_to_string(imported_dir.join("sub/file"))?,
+        "data\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn import_rejects_crafted_key() -> anyhow::Result<()> {
+    let parent_dir = tempfile::tempdir()?;
+    let root_dir = parent_dir.path().join("root");
+    std::fs::create_dir(&root_dir)?;
+    let victim = format!("victim-{}", "0".repeat(64));
+    std::fs::create_dir(parent_dir.path().join(&victim))?;
+    std::fs::write(parent_dir.path().join(&victim).join("file"), "victim")?;
+
+    let mut builder = tar::Builder::new(zstd::Encoder::new(vec![], 0)?);
+    let header = serde_json::to_vec(&serde_json::json!({
+        "format_version": 1,
+        "fs_dir_cache_version": "0.0.0",
+        "key": format!("../{victim}"),
+        "exported_at": "2024-01-01T00:00:00Z",
+    }))?;
+    let mut tar_header = tar::Header::new_gnu();
+    tar_header.set_size(u64::try_from(header.len())?);
+    tar_header.set_mode(0o644);
+    builder.append_data(
+        &mut tar_header,
+        "fs-dir-cache.header.json",
+        header.as_slice(),
+    )?;
+    let mut tar_header = tar::Header::new_gnu();
+    tar_header.set_size(4);
+    tar_header.set_mode(0o644);
+    builder.append_data(&mut tar_header, "data/file", "evil".as_bytes())?;
+    let manifest = serde_json::to_vec(&serde_json::json!({
+        "files": [{
+            "path": "file",
+            "size": 4,
+            "blake3": blake3::hash(b"evil").to_hex().to_string(),
+        }],
+    }))?;
+    let mut tar_header = tar::Header::new_gnu();
+    tar_header.set_size(u64::try_from(manifest.len())?);
+    tar_header.set_mode(0o644);
+    builder.append_data(
+        &mut tar_header,
+        "fs-dir-cache.manifest.json",
+        manifest.as_slice(),
+    )?;
+    let archive = builder.into_inner()?.finish()?;
+
+    let out = our_bin_cmd(&root_dir)
+        .arg("import")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .and_then(|mut child| {
+            use std::io::Write as _;
+            child.stdin.take().expect("piped").write_all(&archive)?;
+            child.wait_with_output()
+        })?;
+    out.assert().failure();
+    assert_eq!(
+        std::fs::read_to_string(parent_dir.path().join(&victim).join("file"))?,
+        "victim"
+    );
+
+    // nor key names that aren't a single path component
+    our_bin_cmd(&root_dir)
+        .args(["exec", "--key-name", "../escape", "--", "true"])
+        .assert()
+        .failure();
+
+    Ok(())
+}
+
+type RemoteFiles = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+/// A minimal remote cache, storing `PUT`s in memory
+fn spawn_remote_cache() -> anyhow::Result<(String, RemoteFiles)> {
+    let server = tiny_http::Server::http("127.0.0.1:0").map_err(|err| anyhow::format_err!(err))?;
+    let url = format!("http://{}/cache", server.server_addr());
+    let files = RemoteFiles::default();
+    let server_files = files.clone();
+    std::thread::spawn(move || {
+        for mut request in server.incoming_requests() {
+            let path = request.url().to_owned();
+            let response = match request.method() {
+                tiny_http::Method::Put => {
+                    let mut body = vec![];
+                    std::io::Read::read_to_end(request.as_reader(), &mut body).unwrap();
+                    server_files.lock().unwrap().insert(path, body);
+                    tiny_http::Response::from_data(vec![]).with_status_code(201)
+                }
+                _ => match server_files.lock().unwrap().get(&path) {
+                    Some(body) => tiny_http::Response::from_data(body.clone()),
+                    None => tiny_http::Response::from_data(vec![]).with_status_code(404),
+                },
+            };
+            let _ = request.respond(response);
+        }
+    });
+    Ok((url, files))
+}
+
+#[test]
+fn remote_cache() -> anyhow::Result<()> {
+    let (url, files) = spawn_remote_cache()?;
+    let exec = |root: &Path, script: &str| {
+        our_bin_cmd(root)
+            .env("FS_DIR_CACHE_REMOTE", &url)
+            .args(["exec", "--key-name", "keyname", "--", "sh", "-c", script])
+            .output()
+    };
+
+    let root_dir = tempfile::tempdir()?;
+    // failed commands are not uploaded
+    exec(root_dir.path(), "echo data > file && false")?
+        .assert()
+        .failure();
+    assert!(files.lock().unwrap().is_empty());
+    exec(root_dir.path(), "echo data > file")?
+        .assert()
+        .success();
+    // the archive and its manifest
+    assert_eq!(files.lock().unwrap().len(), 2);
+    let archive = || {
+        files
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(path, _)| path.ends_with(".tar.zst"))
+            .map(|(_, body)| body.clone())
+    };
+    let uploaded = archive();
+
+    // a fresh root (e.g. on another runner) gets it from the remote
+    let other_root_dir = tempfile::tempdir()?;
+    exec(other_root_dir.path(), "grep data file")?
+        .assert()
+        .success();
+
+    let out = stdout_of(our_bin_cmd(other_root_dir.path()).args(["log"]))?;
+    assert!(out.contains("remote_downloaded"), "{out}");
+    // not uploaded back, as it wasn't modified, only marked as used
+    assert_eq!(archive(), uploaded);
+    assert!(files
+        .lock()
+        .unwrap()
+        .keys()
+        .any(|path| path.ends_with(".used")));
+
+    exec(other_root_dir.path(), "echo more >> file")?
+        .assert()
+        .success();
+    assert_ne!(archive(), uploaded);
+
+    Ok(())
+}
+
+/// A minimal S3-compatible server, storing objects in memory
+///
+/// Counts the parts of multipart uploads in the `parts` of the returned
+/// `RemoteFiles`.
+#[cfg(feature = "s3")]
+fn spawn_s3() -> anyhow::Result<(String, RemoteFiles)> {
+    let now = || {
+        chrono::Utc::now()
+            .format("%Y-%m-%dT%H:%M:%S%.3fZ")
+            .to_string()
+    };
+    let server = tiny_http::Server::http("127.0.0.1:0").map_err(|err| anyhow::format_err!(err))?;
+    let endpoint = format!("http://{}", server.server_addr());
+    let files = RemoteFiles::default();
+    let server_files = files.clone();
+    std::thread::spawn(move || {
+        let mut parts: HashMap<String, Vec<u8>> = HashMap::new();
+        let mut modified: HashMap<String, String> = HashMap::new();
+        for mut request in server.incoming_requests() {
+            let (path, query) = request
+                .url()
+                .split_once('?')
+                .map(|(path, query)| (path.to_owned(), query.to_owned()))
+                .unwrap_or_else(|| (request.url().to_owned(), String::new()));
+            let signed = request.headers().iter().any(|header| {
+                header.field.equiv("Authorization")
+                    && header
+                        .value
+                        .as_str()
+                        .starts_with("AWS4-HMAC-SHA256 Credential=key/")
+            });
+            let mut body = vec![];
+            std::io::Read::read_to_end(request.as_reader(), &mut body).unwrap();
+            let mut files = server_files.lock().unwrap();
+            let response = match (request.method(), query.as_str()) {
+                _ if !signed => tiny_http::Response::from_data(vec![]).with_status_code(403),
+                (tiny_http::Method::Get, query) if query.starts_with("list-type=2") => {
+                    let contents: String = files
+                        .keys()
+                        .filter(|name| !name.contains("?part"))
+                        .map(|name| {
+                            format!(
+                                "<Contents><Key>{}</Key><LastModified>{}</LastModified></Contents>",
+                                name.trim_start_matches("/bucket/"),
+                                modified[name]
+                            )
+                        })
+                        .collect();
+                    tiny_http::Response::from_data(format!(
+                        "<ListBucketResult><IsTruncated>false</IsTruncated>{contents}</ListBucketResult>"
+                    ))
+                }
+                (tiny_http::Method::Get, _) => match files.get(&path) {
+                    Some(body) => tiny_http::Response::from_data(body.clone()),
+                    None => tiny_http::Response::from_data(vec![]).with_status_code(404),
+                },
+                (tiny_http::Method::Post, "uploads=") => tiny_http::Response::from_data(
+                    "<InitiateMultipartUploadResult><UploadId>upload</UploadId></InitiateMultipartUploadResult>",
+                ),
+                (tiny_http::Method::Put, query) if query.starts_with("partNumber=") => {
+                    parts.entry(path.clone()).or_default().extend(body);
+                    files.insert(format!("{path}?{query}"), vec![]);
+                    tiny_http::Response::from_data(vec![]).with_header(
+                        tiny_http::Header::from_bytes("ETag", format!("\"{query}\"")).unwrap(),
+                    )
+                }
+                (tiny_http::Method::Post, "uploadId=upload") => {
+                    modified.insert(path.clone(), now());
+                    files.insert(path.clone(), parts.remove(&path).unwrap_or_default());
+                    tiny_http::Response::from_data("<CompleteMultipartUploadResult/>")
+                }
+                (tiny_http::Method::Put, _) => {
+                    modified.insert(path.clone(), now());
+                    files.insert(path, body);
+                    tiny_http::Response::from_data(vec![])
+                }
+                (tiny_http::Method::Delete, _) => {
+                    files.remove(&path);
+                    tiny_http::Response::from_data(vec![]).with_status_code(204)
+                }
+                _ => tiny_http::Response::from_data(vec![]).with_status_code(400),
+            };
+            drop(files);
+            let _ = request.respond(response);
+        }
+    });
+    Ok((endpoint, files))
+}
+
+#[cfg(feature = "s3")]
+#[test]
+fn s3_remote_cache() -> anyhow::Result<()> {
+    let (endpoint, files) = spawn_s3()?;
+    let s3_cmd = |root: &Path| {
+        let mut cmd = our_bin_cmd(root);
+        cmd.env("FS_DIR_CACHE_REMOTE", "s3://bucket/prefix")
+            .env("AWS_ENDPOINT_URL", &endpoint)
+            .env("AWS_ACCESS_KEY_ID", "key")
+            .env("AWS_SECRET_ACCESS_KEY", "secret")
+            .env("FS_DIR_CACHE_S3_PART_SIZE_MIB", "5");
+        cmd
+    };
+
+    let root_dir = tempfile::tempdir()?;
+    // random, so it doesn't compress below the part size
+    s3_cmd(root_dir.path())
+        .args([
+            "exec",
+            "--key-name",
+            "keyname",
+            "--",
+            "sh",
+            "-c",
+            "head -c 12000000 /dev/urandom > big",
+        ])
+        .assert()
+        .success();
+    {
+        let files = files.lock().unwrap();
+        assert_eq!(
+            files
+                .keys()
+                .filter(|name| name.contains("?partNumber"))
+                .count(),
+            3,
+            "{:?}",
+            files.keys()
+        );
+        assert!(files
+            .keys()
+            .any(|name| name.starts_with("/bucket/prefix/keyname-")
+                && name.ends_with(".manifest.json")));
+    }
+
+    let other_root_dir = tempfile::tempdir()?;
+    s3_cmd(other_root_dir.path())
+        .args([
+            "exec",
+            "--key-name",
+            "keyname",
+            "--",
+            "sh",
+            "-c",
+            "test $(wc -c < big) -eq 12000000",
+        ])
+        .assert()
+        .success();
+
+    // downloaded (but not uploaded back) by yet another root since
+    std::thread::sleep(std::time::Duration::from_secs(2));
+    let third_root_dir = tempfile::tempdir()?;
+    let dir = PathBuf::from(
+        stdout_of(s3_cmd(third_root_dir.path()).args([
+            "lock",
+            "--key-name",
+            "keyname",
+            "--lock-id",
+            "lockid",
+            "--timeout-secs",
+            "60",
+        ]))?
+        .trim(),
+    );
+    assert_eq!(std::fs::metadata(dir.join("big"))?.len(), 12_000_000);
+    s3_cmd(third_root_dir.path())
+        .args(["unlock", "--lock-id", "lockid", "--dir"])
+        .arg(&dir)
+        .assert()
+        .success();
+    let gc = || stdout_of(s3_cmd(other_root_dir.path()).args(["gc", "unused", "--seconds", "1"]));
+    let out = gc()?;
+    assert!(!out.contains("s3://"), "{out}");
+
+    std::thread::sleep(std::time::Duration::from_secs(2));
+    let out = gc()?;
+    assert!(out.contains("s3://bucket/prefix/keyname-"), "{out}");
+    assert!(!files
+        .lock()
+        .unwrap()
+        .keys()
+        .any(|name| name.starts_with("/bucket/prefix/") && !name.contains("?part")));
+
+    Ok(())
+}
+
+#[test]
+fn verify_manifest() -> anyhow::Result<()> {
+    let root_dir = tempfile::tempdir()?;
+    let exec = |args: &[&str], script: &str| {
+        our_bin_cmd(root_dir.path())
+            .args(["exec", "--key-name", "keyname"])
+            .args(args)
+            .args(["--", "sh", "-c", script])
+            .output()
+    };
+    let verify = |args: &[&str]| {
+        our_bin_cmd(root_dir.path())
+            .args(["verify", "--all"])
+            .args(args)
+            .output()
+    };
+    let out = exec(
+        &["--manifest"],
+        "mkdir sub && echo a > sub/a && echo b > b && ln -s b link && pwd",
+    )?;
+    let dir = PathBuf::from(
+        String::from_utf8(out.assert().success().get_output().stdout.clone())?.trim(),
+    );
+
+    let out = verify(&[])?;
+    let out = String::from_utf8(out.assert().success().get_output().stdout.clone())?;
+    assert!(out.contains(": ok"), "{out}");
+
+    // kept out of the key metadata, loaded on every lock
+    let key = dir.file_name().expect("key dir").to_string_lossy();
+    let meta_dir = root_dir.path().join(".meta");
+    let manifest_path = meta_dir.join(format!("{key}.manifest.json"));
+    assert!(manifest_path.exists());
+    let key_data = std::fs::read_to_string(meta_dir.join(format!("{key}.json")))?;
+    assert!(!key_data.contains("root_hash"), "{key_data}");
+    let out = stdout_of(
+        our_bin_cmd(root_dir.path())
+            .args(["status", "--dir"])
+            .arg(&dir),
+    )?;
+    assert!(out.contains("manifest: "), "{out}");
+    let out = stdout_of(our_bin_cmd(root_dir.path()).args(["stats", "--keys"]))?;
+    assert!(!out.contains(".manifest"), "{out}");
+
+    std::fs::write(dir.join("sub/a"), "corrupted")?;
+    std::fs::remove_file(dir.join("b"))?;
+    std::fs::write(dir.join("c"), "c")?;
+    let out = verify(&[])?;
+    let out = String::from_utf8(out.assert().failure().get_output().stdout.clone())?;
+    assert!(
+        out.contains("corrupted (1 modified, 1 missing, 1 extra)"),
+        "{out}"
+    );
+    assert!(out.contains("modified: sub/a"), "{out}");
+    assert!(out.contains("missing: b"), "{out}");
+    assert!(out.contains("extra: c"), "{out}");
+
+    let out = verify(&["--repair", "discard"])?;
+    let out = String::from_utf8(out.assert().success().get_output().stdout.clone())?;
+    assert!(out.contains(": discarded"), "{out}");
+    assert!(!dir.exists());
+    assert!(!manifest_path.exists());
+
+    // released without a manifest, the dir could have been modified
+    exec(&["--manifest"], "echo a > a")?.assert().success();
+    exec(&[], "echo b > a")?.assert().success();
+    let out = verify(&[])?;
+    let out = String::from_utf8(out.assert().success().get_output().stdout.clone())?;
+    assert!(out.contains(": no manifest"), "{out}");
+
+    Ok(())
+}
+
+#[test]
+fn dedup() -> anyhow::Result<()> {
+    use std::os::unix::fs::MetadataExt as _;
+
+    let root_dir = tempfile::tempdir()?;
+    let exec = |key_name: &str, script: &str| -> anyhow::Result<PathBuf> {
+        Ok(PathBuf::from(
+            stdout_of(our_bin_cmd(root_dir.path()).args([
+                "exec",
+                "--key-name",
+                key_name,
+                "--",
+                "sh",
+                "-c",
+                script,
+            ]))?
+            .trim(),
+        ))
+    };
+    let dirs = [
+        exec("a", "echo shared > lib && chmod a-w lib && pwd")?,
+        exec(
+            "b",
+            "mkdir sub && echo shared > sub/lib && chmod a-w sub/lib && pwd",
+        )?,
+        exec("c", "echo other > lib && chmod a-w lib && pwd")?,
+    ];
+    // sizes recorded in the background after releases, or here
+    stdout_of(our_bin_cmd(root_dir.path()).args(["du", "--refresh"]))?;
+    let out = stdout_of(our_bin_cmd(root_dir.path()).arg("metrics"))?;
+    assert!(out.contains("fs_dir_cache_bytes 20\n"), "{out}");
+
+    let out = stdout_of(our_bin_cmd(root_dir.path()).arg("dedup"))?;
+    // without reflinks (e.g. ext4, tmpfs) only the read-only copies are shared
+    assert_eq!(out.trim(), "7");
+    let (a, b, c) = (
+        std::fs::metadata(dirs[0].join("lib"))?,
+        std::fs::metadata(dirs[1].join("sub/lib"))?,
+        std::fs::metadata(dirs[2].join("lib"))?,
+    );
+    if a.ino() != b.ino() {
+        // reflinked
+        assert_eq!(a.nlink(), 1);
+    } else {
+        assert_eq!(a.nlink(), 2);
+    }
+    assert_eq!(c.nlink(), 1);
+    assert_eq!(
+        std::fs::read_to_string(dirs[1].join("sub/lib"))?,
+        "shared\n"
+    );
+    if a.ino() == b.ino() {
+        let out = stdout_of(our_bin_cmd(root_dir.path()).arg("metrics"))?;
+        assert!(out.contains("fs_dir_cache_bytes 13\n"), "{out}");
+    }
+    // nothing left to share
+    let out = stdout_of(our_bin_cmd(root_dir.path()).arg("dedup"))?;
+    assert_eq!(out.trim(), "0");
+
+    for key_name in ["a", "b", "c"] {
+        let out = stdout_of(our_bin_cmd(root_dir.path()).args(["status", "--key-name", key_name]))?;
+        assert!(out.contains("state: unlocked"), "{out}");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn du() -> anyhow::Result<()> {
+    let root_dir = tempfile::tempdir()?;
+    let exec = |key_str: &str, script: &str| {
+        our_bin_cmd(root_dir.path())
+            .args(["exec", "--key-name", "keyname", "--key-str", key_str])
+            .args(["--", "sh", "-c", script])
+            .output()
+    };
+    exec("a", "head -c 1000 /dev/zero > file")?
+        .assert()
+        .success();
+    exec("b", "mkdir sub && head -c 500 /dev/zero > sub/file")?
+        .assert()
+        .success();
+
+    // measured in the background after the release, or here, whichever comes
+    // first
+    let out = stdout_of(our_bin_cmd(root_dir.path()).args(["du", "--refresh"]))?;
+    let key_name_line = out.lines().nth(1).unwrap_or_default();
+    assert!(key_name_line.starts_with("keyname "), "{out}");
+    assert_eq!(
+        key_name_line.split_whitespace().take(3).collect::<Vec<_>>(),
+        ["keyname", "2", "1500"],
+        "{out}"
+    );
+    assert!(!out.contains("stale"), "{out}");
+
+    // a lock holder can be modifying it
+    let dir = lock_key(root_dir.path(), "other", "lock-id")?;
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(dir.join("file"), "data")?;
+    let out = stdout_of(our_bin_cmd(root_dir.path()).args(["du", "--refresh"]))?;
+    let key_line = out
+        .lines()
+        .find(|line| line.starts_with("other-"))
+        .unwrap_or_default();
+    assert!(key_line.ends_with(" -"), "{out}");
+
+    our_bin_cmd(root_dir.path())
+        .args(["unlock", "--lock-id", "lock-id", "--dir"])
+        .arg(&dir)
+        .assert()
+        .success();
+    let out = stdout_of(
+        our_bin_cmd(root_dir.path())
+            .arg("du")
+            .arg("--refresh")
+            .arg("--dir")
+            .arg(&dir),
+    )?;
+    assert_eq!(out.lines().count(), 5, "{out}");
+    assert!(
+        out.lines().nth(1).unwrap_or_default().contains(" 4 "),
+        "{out}"
+    );
+
+    // only the changed subdirs are read again, but they are all picked up
+    for (path, len) in [("sub/file", 10), ("sub/deeper/file", 100)] {
+        let dir = lock_key(root_dir.path(), "other", "lock-id")?;
+        let path = dir.join(path);
+        std::fs::create_dir_all(path.parent().expect("has parent"))?;
+        std::fs::write(path, vec![0; len])?;
+        our_bin_cmd(root_dir.path())
+            .args(["unlock", "--lock-id", "lock-id", "--dir"])
+            .arg(&dir)
+            .assert()
+            .success();
+    }
+    let out = stdout_of(
+        our_bin_cmd(root_dir.path())
+            .args(["du", "--refresh", "--dir"])
+            .arg(&dir),
+    )?;
+    assert!(
+        out.lines().nth(1).unwrap_or_default().contains(" 114 "),
+        "{out}"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn max_key_bytes() -> anyhow::Result<()> {
+    let root_dir = tempfile::tempdir()?;
+    let exec = |args: &[&str], key_name: &str, script: &str| -> anyhow::Result<PathBuf> {
+        Ok(PathBuf::from(
+            stdout_of(
+                our_bin_cmd(root_dir.path())
+                    .args(["exec", "--key-name", key_name])
+                    .args(args)
+                    .args(["--", "sh", "-c", script]),
+            )?
+            .trim(),
+        ))
+    };
+
+    let dir = exec(
+        &["--max-key-bytes", "1000"],
+        "discarded",
+        "head -c 2000 /dev/zero > file && pwd",
+    )?;
+    assert!(!dir.exists());
+    let out = stdout_of(our_bin_cmd(root_dir.path()).arg("log"))?;
+    assert!(out.contains(r#""event":"over_quota""#), "{out}");
+
+    let dir = exec(
+        &["--max-key-bytes", "1000", "--over-quota", "trim"],
+        "trimmed",
+        "head -c 600 /dev/zero > old && head -c 600 /dev/zero > new \
+         && touch -a -d 2000-01-01 old && pwd",
+    )?;
+    assert!(!dir.join("old").exists());
+    assert!(dir.join("new").exists());
+
+    // from the root config, on `unlock`
+    stdout_of(our_bin_cmd(root_dir.path()).args(["config", "--max-key-bytes", "1000"]))?;
+    let dir = lock_key(root_dir.path(), "unlocked", "lockid")?;
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(dir.join("file"), vec![0; 2000])?;
+    // only by the lock holder
+    our_bin_cmd(root_dir.path())
+        .args([
+            "unlock",
+            "--lock-id",
+            "intruder",
+            "--over-quota",
+            "trim",
+            "--dir",
+        ])
+        .arg(&dir)
+        .assert()
+        .failure();
+    assert!(dir.join("file").exists());
+    our_bin_cmd(root_dir.path())
+        .args(["unlock", "--lock-id", "lockid", "--dir"])
+        .arg(&dir)
+        .assert()
+        .success();
+    assert!(!dir.exists());
+
+    // under the limit
+    let dir = exec(&[], "kept", "head -c 1000 /dev/zero > file && pwd")?;
+    assert!(dir.join("file").exists());
+
+    Ok(())
+}
+
+#[test]
+fn maintenance_commands_dont_count_as_use() -> anyhow::Result<()> {
+    let root_dir = tempfile::tempdir()?;
+    let dir = PathBuf::from(
+        stdout_of(our_bin_cmd(root_dir.path()).args([
+            "exec",
+            "--key-name",
+            "keyname",
+            "--manifest",
+            "--",
+            "pwd",
+        ]))?
+        .trim(),
+    );
+    std::thread::sleep(std::time::Duration::from_secs(2));
+
+    stdout_of(our_bin_cmd(root_dir.path()).args(["verify", "--all"]))?;
+    stdout_of(our_bin_cmd(root_dir.path()).arg("dedup"))?;
+    our_bin_cmd(root_dir.path())
+        .arg("export")
+        .arg("--dir")
+        .arg(&dir)
+        .arg("-o")
+        .arg(root_dir.path().join("archive.tar.zst"))
+        .assert()
+        .success();
+
+    let out = stdout_of(our_bin_cmd(root_dir.path()).args(["stats"]))?;
+    let line = out
+        .lines()
+        .find(|line| line.starts_with("keyname "))
+        .unwrap_or_default();
+    assert_eq!(line.split_whitespace().nth(1), Some("1"), "{out}");
+
+    let out = stdout_of(our_bin_cmd(root_dir.path()).args(["gc", "unused", "--seconds", "1"]))?;
+    assert_eq!(out.trim(), dir.display().to_string());
+
+    Ok(())
+}